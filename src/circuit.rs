@@ -1,12 +1,15 @@
 use openqasm as oq;
 use oq::{GenericError, ProgramVisitor};
 use quizx::gate::GType;
-use std::{fmt::Write, path::Path, collections::HashMap};
+use num_rational::Rational64;
+use serde::{Deserialize, Serialize};
+use crate::decomposition::{Decomposition, DecompositionTable, GateKind};
+use std::{fmt::Write, path::Path, collections::{HashMap, HashSet}};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Qubit(pub usize);
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Phase(pub usize);
 
 impl Phase {
@@ -17,6 +20,34 @@ impl Phase {
     pub fn is_clifford(self) -> bool {
         self.0 % 2 == 0
     }
+
+    /// Convert an angle into a phase, if it is a multiple of pi/4.
+    /// Exact rational multiples of pi are always recognized, anything else
+    /// (such as decimal angles) only if it is within `tolerance` of a multiple.
+    pub fn from_value(value: &oq::Value, tolerance: f64) -> Option<Phase> {
+        let (n, d) = (*value.b.numer(), *value.b.denom());
+        if *value.a.numer() == 0 && (4 * n) % d == 0 {
+            return Some(Phase((4 * n / d).rem_euclid(8) as usize))
+        }
+
+        let a = *value.a.numer() as f64 / *value.a.denom() as f64;
+        let b = n as f64 / d as f64;
+        angle_to_phase(a + b * std::f64::consts::PI, tolerance)
+    }
+}
+
+/// The default tolerance, in radians, for recognizing angles as multiples of pi/4.
+pub const DEFAULT_ANGLE_TOLERANCE: f64 = 1e-9;
+
+/// Convert an angle in radians into a phase, if it is within `tolerance` of a multiple of pi/4.
+pub fn angle_to_phase(theta: f64, tolerance: f64) -> Option<Phase> {
+    let quarter = std::f64::consts::FRAC_PI_4;
+    let k = (theta / quarter).round();
+    if k.is_finite() && (theta - k * quarter).abs() <= tolerance {
+        Some(Phase((k as i64).rem_euclid(8) as usize))
+    } else {
+        None
+    }
 }
 
 impl std::ops::Neg for Phase {
@@ -43,7 +74,7 @@ impl std::ops::Sub for Phase {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Gate {
     X(Qubit),
     CNOT(Qubit, Qubit),
@@ -52,7 +83,9 @@ pub enum Gate {
     CCZ(Qubit, Qubit, Qubit),
     CS(Qubit, Qubit),
     SWAP(Qubit, Qubit),
-    H(Qubit)
+    H(Qubit),
+    /// A placeholder that does nothing, but still occupies its qubit.
+    I(Qubit)
 }
 
 impl Gate {
@@ -65,7 +98,8 @@ impl Gate {
             Gate::CS(_, _) => false,
             Gate::CCZ(_, _, _) => false,
             Gate::SWAP(_, _) => true,
-            Gate::H(_) => true
+            Gate::H(_) => true,
+            Gate::I(_) => true
         }
     }
 
@@ -79,7 +113,8 @@ impl Gate {
             Gate::CS(q1, q2) => [q1, q2, q2],
             Gate::CCZ(q1, q2, q3) => [q1, q2, q3],
             Gate::SWAP(q1, q2) => [q1, q2, q2],
-            Gate::H(q) => [q, q, q]
+            Gate::H(q) => [q, q, q],
+            Gate::I(q) => [q, q, q]
         }
     }
     
@@ -105,58 +140,255 @@ impl Gate {
             Gate::CS(q1, q2) => { *q1 = f(*q1); *q2 = f(*q2); },
             Gate::CCZ(q1, q2, q3) => { *q1 = f(*q1); *q2 = f(*q2); *q3 = f(*q3); },
             Gate::SWAP(q1, q2) => { *q1 = f(*q1); *q2 = f(*q2); },
-            Gate::H(q) => *q = f(*q)
+            Gate::H(q) => *q = f(*q),
+            Gate::I(q) => *q = f(*q)
+        }
+    }
+}
+
+/// Which end of the register qubit 0 is at when reading and writing circuits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+pub enum Endian {
+    #[value(help = "Qubit i is q[i]")]
+    Little,
+    #[value(help = "Qubit i is q[n-1-i], for the n qubits of the input circuit")]
+    Big
+}
+
+impl Endian {
+    /// Convert a qubit between the internal little-endian order and this one. Only the first
+    /// `n` qubits are reversed, so ancillas keep their indices past them. Converting twice
+    /// is the identity.
+    pub fn convert(self, Qubit(q): Qubit, n: usize) -> Qubit {
+        match self {
+            Endian::Big if q < n => Qubit(n - 1 - q),
+            _ => Qubit(q)
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Circuit {
-    pub gates: Vec<Gate>
+    pub gates: Vec<Gate>,
+    /// Global phase of the circuit, as a multiple of pi/4
+    pub global_phase: Phase,
+    /// Comment lines carried through from the source, each with the index
+    /// of the gate it precedes. Comments past the last gate trail the circuit.
+    pub comments: Vec<(usize, String)>,
+    /// Measurements of a qubit into a classical bit, deferred to the end of the circuit
+    pub measurements: Vec<(Qubit, usize)>,
+    /// Clifford corrections conditioned on measurement results, deferred to the end of
+    /// the circuit after the measurements. Only the unitary `gates` are compiled.
+    pub corrections: Vec<(Condition, Gate)>
+}
+
+/// A classical condition `if (c == value)` on the register made up of the
+/// bits `start..start + count`, with bit `start` least significant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Condition {
+    pub start: usize,
+    pub count: usize,
+    pub value: u64
+}
+
+impl Condition {
+    /// The classical bits this condition reads.
+    pub fn bits(&self) -> std::ops::Range<usize> {
+        self.start..self.start + self.count
+    }
 }
 
 impl Circuit {
+    pub fn new(gates: Vec<Gate>) -> Circuit {
+        Circuit { gates, global_phase: Phase(0), comments: Vec::new(), measurements: Vec::new(), corrections: Vec::new() }
+    }
+
+    /// The comments that precede the gate at the given index.
+    fn comments_at(&self, i: usize) -> impl Iterator<Item = &str> {
+        self.comments.iter()
+            .filter(move |(j, _)| *j == i)
+            .map(|(_, c)| c.as_str())
+    }
+
+    /// The comments after the last gate.
+    fn trailing_comments(&self) -> impl Iterator<Item = &str> {
+        let n = self.gates.len();
+        self.comments.iter()
+            .filter(move |(j, _)| *j >= n)
+            .map(|(_, c)| c.as_str())
+    }
+
+    /// Move every comment to the start of the circuit, keeping their order. Rewrites that
+    /// reorder or rebuild the gates use this, since afterwards the index a comment was
+    /// attached to no longer refers to the same gate.
+    pub fn hoist_comments(&mut self) {
+        self.comments.iter_mut().for_each(|(i, _)| *i = 0);
+    }
+
     /// Translate the circuit to OPENQASM 2.0.
-    /// Specify opaque = true to generate an opaque gate definition for `ccz`.
+    /// Specify opaque = true to generate opaque gate definitions for `ccz` and `cs`,
+    /// otherwise `cs` is decomposed into CNOT and T gates.
+    /// The global phase is emitted as a `gphase` gate if opaque = true, and as a comment otherwise.
+    /// Deferred measurements and corrections are emitted at the end.
     pub fn to_openqasm(&self, opaque: bool) -> String {
+        self.to_openqasm_with(opaque, false)
+    }
+
+    /// As `to_openqasm`, optionally writing SWAPs as a single `swap` gate rather than three
+    /// CNOTs, which is declared as an opaque gate.
+    pub fn to_openqasm_with(&self, opaque: bool, native_swap: bool) -> String {
+        let mut table = DecompositionTable::default();
+        if native_swap {
+            table.set(GateKind::SWAP, Decomposition::Native)
+                .expect("A native SWAP can't make the table cyclic");
+        }
+        self.to_openqasm_with_table(opaque, &table)
+    }
+
+    /// As `to_openqasm`, lowering CZ, CS, CCZ and SWAP gates as given by the decomposition table.
+    /// Native gates missing from qelib1.inc are declared as opaque gates.
+    pub fn to_openqasm_with_table(&self, opaque: bool, table: &DecompositionTable) -> String {
+        let native_cs = opaque || table.is_native(GateKind::CS);
+        let write_gate = |out: &mut String, g: Gate| table.expand(g).into_iter().try_for_each(|g| match g {
+            Gate::SWAP(Qubit(a), Qubit(b)) if table.is_native(GateKind::SWAP) => writeln!(out, "swap q[{a}], q[{b}];"),
+            // qelib1.inc has no cs, so without the opaque definition use the same decomposition as to_zx
+            Gate::CS(Qubit(a), Qubit(b)) if !native_cs => writeln!(out, "cx q[{a}], q[{b}];\ntdg q[{b}];\ncx q[{a}], q[{b}];\nt q[{a}];\nt q[{b}];"),
+            g => write_qasm2_gate(out, g)
+        });
+
         let mut out = String::new();
-        let mut n = 0;
-        for &g in &self.gates {
+        if self.global_phase != Phase(0) {
+            let Phase(p) = self.global_phase;
+            if opaque {
+                writeln!(&mut out, "gphase({p}*pi/4) q[0];").unwrap();
+            } else {
+                writeln!(&mut out, "// gphase({p}*pi/4);").unwrap();
+            }
+        }
+        let mut n = self.write_gates(&mut out, write_gate);
+
+        // Deferred measurements and corrections, with one classical register per condition
+        let registers = self.classical_registers();
+        for &(Qubit(q), b) in &self.measurements {
+            let (start, _) = registers.iter().find(|(start, count)| (*start..start + count).contains(&b)).unwrap();
+            writeln!(&mut out, "measure q[{q}] -> c{start}[{}];", b - start).unwrap();
+            n = n.max(q);
+        }
+        for &(condition, g) in &self.corrections {
+            let mut gate = String::new();
+            write_gate(&mut gate, g).unwrap();
+            for line in gate.lines() {
+                writeln!(&mut out, "if (c{} == {}) {}", condition.start, condition.value, line).unwrap();
+            }
             let q = g.qubits();
             n = n.max(q[0].0).max(q[1].0).max(q[2].0);
-            match g {
-                Gate::X(Qubit(q)) => writeln!(&mut out, "x q[{q}];"),
-                Gate::CNOT(Qubit(c), Qubit(t)) => writeln!(&mut out, "cx q[{c}], q[{t}];"),
-                Gate::Phase(Phase(p), Qubit(q)) => match p {
-                    0 => Ok(()),
-                    1 => writeln!(&mut out, "t q[{q}];"),
-                    2 => writeln!(&mut out, "s q[{q}];"),
-                    3 => writeln!(&mut out, "s q[{q}];\nt q[{q}];"),
-                    4 => writeln!(&mut out, "z q[{q}];"),
-                    5 => writeln!(&mut out, "z q[{q}];\nt q[{q}];"),
-                    6 => writeln!(&mut out, "sdg q[{q}];"),
-                    7 => writeln!(&mut out, "tdg q[{q}];"),
-                    _ => panic!("unknown phase {p}")
-                },
-                Gate::CZ(Qubit(p), Qubit(q)) => writeln!(&mut out, "cz q[{p}], q[{q}];"),
-                Gate::CS(Qubit(p), Qubit(q)) => writeln!(&mut out, "cs q[{p}], q[{q}];"),
-                Gate::CCZ(Qubit(p), Qubit(q), Qubit(r)) => writeln!(&mut out, "ccz q[{p}], q[{q}], q[{r}];"),
-                Gate::SWAP(Qubit(a), Qubit(b)) => writeln!(&mut out, "cx q[{a}], q[{b}];\ncx q[{b}], q[{a}];\ncx q[{a}], q[{b}];"),
-                Gate::H(Qubit(q)) => writeln!(&mut out, "h q[{q}];")
-            }.unwrap()
         }
 
         let mut res = String::new();
         res.push_str(if opaque {
-            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nopaque ccz a, b, c;\nopaque cs a, b;\n"
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nopaque ccz a, b, c;\nopaque cs a, b;\nopaque gphase(g) a;\n"
         } else {
             "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n"
         });
+        if !opaque && table.is_native(GateKind::CCZ) {
+            res.push_str("opaque ccz a, b, c;\n");
+        }
+        if !opaque && table.is_native(GateKind::CS) {
+            res.push_str("opaque cs a, b;\n");
+        }
+        // qelib1.inc has no swap, so it always needs declaring
+        if table.is_native(GateKind::SWAP) {
+            res.push_str("opaque swap a, b;\n");
+        }
         writeln!(&mut res, "qreg q[{}];", n + 1).unwrap();
+        for (start, count) in registers {
+            writeln!(&mut res, "creg c{start}[{count}];").unwrap();
+        }
+        res += &out;
+        res
+    }
+
+    /// Translate the circuit to OPENQASM 3.0, using only gates from `stdgates.inc` and
+    /// the `ctrl @` modifier for `cs` and `ccz`. Phases are emitted as in `to_openqasm`,
+    /// and the global phase with the builtin `gphase`. This is a write-only format since
+    /// the parser only understands OPENQASM 2.0.
+    pub fn to_openqasm3(&self) -> String {
+        let mut out = String::new();
+        if self.global_phase != Phase(0) {
+            let Phase(p) = self.global_phase;
+            writeln!(&mut out, "gphase({p}*pi/4);").unwrap();
+        }
+        let mut n = self.write_gates(&mut out, write_qasm3_gate);
+
+        let registers = self.classical_registers();
+        for &(Qubit(q), b) in &self.measurements {
+            let (start, _) = registers.iter().find(|(start, count)| (*start..start + count).contains(&b)).unwrap();
+            writeln!(&mut out, "c{start}[{}] = measure q[{q}];", b - start).unwrap();
+            n = n.max(q);
+        }
+        for &(condition, g) in &self.corrections {
+            let mut gate = String::new();
+            write_qasm3_gate(&mut gate, g).unwrap();
+            writeln!(&mut out, "if (c{} == {}) {{ {} }}", condition.start, condition.value, gate.lines().collect::<Vec<_>>().join(" ")).unwrap();
+            let q = g.qubits();
+            n = n.max(q[0].0).max(q[1].0).max(q[2].0);
+        }
+
+        let mut res = String::from("OPENQASM 3.0;\ninclude \"stdgates.inc\";\n");
+        writeln!(&mut res, "qubit[{}] q;", n + 1).unwrap();
+        for (start, count) in registers {
+            writeln!(&mut res, "bit[{count}] c{start};").unwrap();
+        }
         res += &out;
         res
     }
 
+    /// Reconstruct the classical registers used by the deferred measurements and corrections,
+    /// as `(start, count)` ranges of bits. Conditions always span a whole register, and any
+    /// measured bit outside of them gets a register of its own. Gaps are filled with unused
+    /// registers so that declaring them in order keeps the same bit indices.
+    fn classical_registers(&self) -> Vec<(usize, usize)> {
+        let mut registers = self.corrections.iter()
+            .map(|(c, _)| (c.start, c.count))
+            .collect::<Vec<_>>();
+        for &(_, b) in &self.measurements {
+            if !registers.iter().any(|(start, count)| (*start..start + count).contains(&b)) {
+                registers.push((b, 1));
+            }
+        }
+        registers.sort();
+        registers.dedup();
+
+        let mut padded = Vec::new();
+        let mut next = 0;
+        for (start, count) in registers {
+            if start > next {
+                padded.push((next, start - next));
+            }
+            padded.push((start, count));
+            next = start + count;
+        }
+        padded
+    }
+
+    /// Write each gate with its comments using the given formatter, returning the
+    /// largest qubit index used.
+    fn write_gates(&self, out: &mut String, mut gate: impl FnMut(&mut String, Gate) -> std::fmt::Result) -> usize {
+        let mut n = 0;
+        for (i, &g) in self.gates.iter().enumerate() {
+            let q = g.qubits();
+            n = n.max(q[0].0).max(q[1].0).max(q[2].0);
+            for comment in self.comments_at(i) {
+                writeln!(out, "//{}", comment).unwrap();
+            }
+            gate(out, g).unwrap();
+        }
+        for comment in self.trailing_comments() {
+            writeln!(out, "//{}", comment).unwrap();
+        }
+        n
+    }
+
     pub fn qubits(&self) -> usize {
         if self.gates.is_empty() {
             return 0
@@ -170,45 +402,188 @@ impl Circuit {
         n + 1
     }
 
+    /// Count the gates satisfying the given predicate.
+    pub fn count_gates(&self, pred: impl Fn(Gate) -> bool) -> usize {
+        self.gates.iter().filter(|&&g| pred(g)).count()
+    }
+
+    /// The number of layers each qubit takes part in when every gate is scheduled as early as
+    /// possible, so that a gate starts one layer after the latest of its qubits is free.
+    /// Identity gates take no time.
+    pub fn depth_per_qubit(&self) -> Vec<usize> {
+        let mut layers = vec![0; self.qubits()];
+        for &g in &self.gates {
+            if matches!(g, Gate::I(_)) {
+                continue
+            }
+            let qubits = g.qubits();
+            let layer = 1 + qubits.iter().map(|q| layers[q.0]).max().unwrap();
+            for q in qubits {
+                layers[q.0] = layer;
+            }
+        }
+        layers
+    }
+
+    /// The length of the critical path through the circuit, as in `depth_per_qubit`.
+    pub fn depth(&self) -> usize {
+        self.depth_per_qubit().into_iter().max().unwrap_or(0)
+    }
+
+    pub fn num_cnots(&self) -> usize {
+        self.count_gates(|g| matches!(g, Gate::CNOT(_, _)))
+    }
+
+    /// Count the CNOT, CZ, CS, and SWAP gates.
+    pub fn num_two_qubit_gates(&self) -> usize {
+        self.count_gates(|g| matches!(g, Gate::CNOT(_, _) | Gate::CZ(_, _) | Gate::CS(_, _) | Gate::SWAP(_, _)))
+    }
+
+    pub fn num_ccz(&self) -> usize {
+        self.count_gates(|g| matches!(g, Gate::CCZ(_, _, _)))
+    }
+
+    pub fn num_cs(&self) -> usize {
+        self.count_gates(|g| matches!(g, Gate::CS(_, _)))
+    }
+
+    /// Remove all identity placeholder gates.
+    pub fn strip_identities(&mut self) {
+        let remove = self.gates.iter().map(|g| matches!(g, Gate::I(_))).collect::<Vec<_>>();
+        self.remove_gates(&remove);
+    }
+
+    /// Remove the gates at the indices marked in `remove`, which has one entry per gate.
+    pub fn remove_gates(&mut self, remove: &[bool]) {
+        // Count the removed gates before each gate, so that comments stay attached to the same gate
+        let mut removed = Vec::with_capacity(self.gates.len() + 1);
+        let mut count = 0;
+        for &r in remove {
+            removed.push(count);
+            count += r as usize;
+        }
+        removed.push(count);
+        for (i, _) in &mut self.comments {
+            *i -= removed[(*i).min(self.gates.len())];
+        }
+        let mut remove = remove.iter();
+        self.gates.retain(|_| !remove.next().unwrap());
+    }
+
+    /// Relabel the qubits of a circuit on qubits 0..n, so that qubit i becomes qubit map[i].
+    /// This is used to place a block synthesized on matrix rows back onto the original qubits.
+    pub fn rebase(&self, map: &[usize]) -> Circuit {
+        let mut circuit = self.clone();
+        for gate in &mut circuit.gates {
+            gate.map_qubits(|Qubit(q)| Qubit(map[q]));
+        }
+        circuit
+    }
+
+    /// The inverse of the unitary part of this circuit, with the gates reversed and each phase
+    /// negated. CS is written as CZ * CS, since CS^3 = CS^dagger. Measurements, corrections and
+    /// comments are not carried over.
+    pub fn inverse(&self) -> Circuit {
+        let mut gates = Vec::with_capacity(self.gates.len());
+        for &gate in self.gates.iter().rev() {
+            match gate {
+                Gate::Phase(p, q) => gates.push(Gate::Phase(-p, q)),
+                Gate::CS(a, b) => gates.extend([Gate::CZ(a, b), Gate::CS(a, b)]),
+                _ => gates.push(gate)
+            }
+        }
+        let mut circuit = Circuit::new(gates);
+        circuit.global_phase = -self.global_phase;
+        circuit
+    }
+
+    /// Relabel every qubit of this circuit, including those of the measurements and corrections.
+    pub fn map_qubits(&mut self, f: impl Fn(Qubit) -> Qubit + Copy) {
+        self.gates.iter_mut().for_each(|g| g.map_qubits(f));
+        self.measurements.iter_mut().for_each(|(q, _)| *q = f(*q));
+        self.corrections.iter_mut().for_each(|(_, g)| g.map_qubits(f));
+    }
+
+    /// Relabel the qubits of this circuit with `Endian::convert`.
+    pub fn convert_endian(&mut self, endian: Endian, n: usize) {
+        self.map_qubits(|q| endian.convert(q, n));
+    }
+
     /// Translate to a .qc file.
     /// Qubits is the number of qubits to treat as non-ancilla.
     pub fn to_qc(&self, qubits: usize) -> String {
+        self.to_qc_with(qubits, false)
+    }
+
+    /// As `to_qc`, optionally writing SWAPs as a single `swap` gate rather than three CNOTs.
+    pub fn to_qc_with(&self, qubits: usize, native_swap: bool) -> String {
+        let mut table = DecompositionTable::default();
+        if native_swap {
+            table.set(GateKind::SWAP, Decomposition::Native).unwrap();
+        }
+        self.to_qc_with_table(qubits, &table)
+    }
+
+    /// As `to_qc`, lowering CZ, CS, CCZ and SWAP gates as given by the decomposition table.
+    pub fn to_qc_with_table(&self, qubits: usize, table: &DecompositionTable) -> String {
+        self.write_qc(table, |i| i.to_string(), &(0..qubits).map(Qubit).collect::<Vec<_>>())
+    }
+
+    /// Write the circuit as .qc, naming `Qubit(i)` by `label(i)` and declaring `inputs` on the
+    /// `.i` line. Every qubit up to the largest one used or declared as an input is declared.
+    fn write_qc(&self, table: &DecompositionTable, label: impl Fn(usize) -> String, inputs: &[Qubit]) -> String {
         let mut out = String::new();
         let mut n = 0;
-        for &g in &self.gates {
-            let q = g.qubits();
-            n = n.max(q[0].0).max(q[1].0).max(q[2].0);
-            match g {
-                Gate::X(Qubit(q)) => writeln!(&mut out, "X {q}"),
-                Gate::CNOT(Qubit(c), Qubit(t)) => writeln!(&mut out, "cnot {c} {t}"),
-                Gate::Phase(Phase(p), Qubit(q)) => match p {
-                    0 => Ok(()),
-                    1 => writeln!(&mut out, "T {q}"),
-                    2 => writeln!(&mut out, "S {q}"),
-                    3 => writeln!(&mut out, "S {q}\nT {q}"),
-                    4 => writeln!(&mut out, "Z {q}"),
-                    5 => writeln!(&mut out, "Z {q}\nT {q}"),
-                    6 => writeln!(&mut out, "Z {q}\nS {q}"),
-                    7 => writeln!(&mut out, "Z {q}\nS {q}\nT {q}"),
-                    _ => panic!("unknown phase {p}")
-                },
-                Gate::CZ(Qubit(p), Qubit(q)) => writeln!(&mut out, "H {q}\ncnot {p} {q}\nH {q}"),
-                Gate::CS(Qubit(p), Qubit(q)) => writeln!(&mut out, "cnot {p} {q}\nZ {q}\nS {q}\nT {q}\ncnot {p} {q}\nT {p}\nT {q}"),
-                Gate::CCZ(Qubit(p), Qubit(q), Qubit(r)) => writeln!(&mut out, "H {r}\ntof {p} {q} {r}\nH {r}"),
-                Gate::SWAP(Qubit(a), Qubit(b)) => writeln!(&mut out, "cnot {a} {b}\ncnot {b} {a}\ncnot {a} {b}"),
-                Gate::H(Qubit(q)) => writeln!(&mut out, "H {q}")
-            }.unwrap()
+        for (i, &g) in self.gates.iter().enumerate() {
+            for comment in self.comments_at(i) {
+                writeln!(&mut out, "#{}", comment).unwrap();
+            }
+            for g in table.expand(g) {
+                let q = g.qubits();
+                n = n.max(q[0].0).max(q[1].0).max(q[2].0);
+                let [a, b, c] = q.map(|Qubit(i)| label(i));
+                match g {
+                    Gate::X(_) => writeln!(&mut out, "X {a}"),
+                    Gate::CNOT(_, _) => writeln!(&mut out, "cnot {a} {b}"),
+                    Gate::Phase(Phase(p), _) => match p {
+                        0 => Ok(()),
+                        1 => writeln!(&mut out, "T {a}"),
+                        2 => writeln!(&mut out, "S {a}"),
+                        3 => writeln!(&mut out, "S {a}\nT {a}"),
+                        4 => writeln!(&mut out, "Z {a}"),
+                        5 => writeln!(&mut out, "Z {a}\nT {a}"),
+                        6 => writeln!(&mut out, "Z {a}\nS {a}"),
+                        7 => writeln!(&mut out, "Z {a}\nS {a}\nT {a}"),
+                        _ => panic!("unknown phase {p}")
+                    },
+                    Gate::CZ(_, _) if table.is_native(GateKind::CZ) => writeln!(&mut out, "cz {a} {b}"),
+                    Gate::CZ(_, _) => writeln!(&mut out, "H {b}\ncnot {a} {b}\nH {b}"),
+                    Gate::CS(_, _) if table.is_native(GateKind::CS) => writeln!(&mut out, "cs {a} {b}"),
+                    Gate::CS(_, _) => writeln!(&mut out, "cnot {a} {b}\nZ {b}\nS {b}\nT {b}\ncnot {a} {b}\nT {a}\nT {b}"),
+                    Gate::CCZ(_, _, _) if table.is_native(GateKind::CCZ) => writeln!(&mut out, "ccz {a} {b} {c}"),
+                    Gate::CCZ(_, _, _) => writeln!(&mut out, "H {c}\ntof {a} {b} {c}\nH {c}"),
+                    Gate::SWAP(_, _) if table.is_native(GateKind::SWAP) => writeln!(&mut out, "swap {a} {b}"),
+                    Gate::SWAP(_, _) => writeln!(&mut out, "cnot {a} {b}\ncnot {b} {a}\ncnot {a} {b}"),
+                    Gate::H(_) => writeln!(&mut out, "H {a}"),
+                    Gate::I(_) => Ok(())
+                }.unwrap()
+            }
+        }
+        for comment in self.trailing_comments() {
+            writeln!(&mut out, "#{}", comment).unwrap();
         }
 
+        // Every input qubit must be declared, even if this circuit doesn't act on it
+        let n = inputs.iter().fold(n, |n, q| n.max(q.0));
         let mut res = String::new();
         writeln!(&mut res,
             ".v {}\n.i {}\nBEGIN\n{}\nEND", 
             (0..=n)
-                .map(|i| format!("{}", i))
+                .map(&label)
                 .collect::<Vec<_>>()
                 .join(" "),
-            (0..qubits)
-                .map(|i| format!("{}", i))
+            inputs.iter()
+                .map(|q| label(q.0))
                 .collect::<Vec<_>>()
                 .join(" "),
             out
@@ -216,25 +591,247 @@ impl Circuit {
         res
     }
 
+    /// Translate a Clifford circuit to stim's circuit format.
+    /// The global phase is dropped, since stim does not track it.
+    pub fn to_stim(&self) -> Result<String, UnexpectedGate> {
+        let mut out = String::new();
+        for (i, &g) in self.gates.iter().enumerate() {
+            for comment in self.comments_at(i) {
+                writeln!(&mut out, "#{}", comment).unwrap();
+            }
+            match g {
+                Gate::X(Qubit(q)) => writeln!(&mut out, "X {q}"),
+                Gate::CNOT(Qubit(c), Qubit(t)) => writeln!(&mut out, "CX {c} {t}"),
+                Gate::Phase(Phase(p), Qubit(q)) => match p {
+                    0 => Ok(()),
+                    2 => writeln!(&mut out, "S {q}"),
+                    4 => writeln!(&mut out, "Z {q}"),
+                    6 => writeln!(&mut out, "S_DAG {q}"),
+                    _ => return Err(UnexpectedGate(format!("non-Clifford phase {p}*pi/4 on qubit {q} in stim output")))
+                },
+                Gate::CZ(Qubit(p), Qubit(q)) => writeln!(&mut out, "CZ {p} {q}"),
+                Gate::SWAP(Qubit(a), Qubit(b)) => writeln!(&mut out, "SWAP {a} {b}"),
+                Gate::H(Qubit(q)) => writeln!(&mut out, "H {q}"),
+                Gate::I(Qubit(q)) => writeln!(&mut out, "I {q}"),
+                Gate::CS(_, _) | Gate::CCZ(_, _, _) => return Err(UnexpectedGate(format!("non-Clifford gate {:?} in stim output", g)))
+            }.unwrap()
+        }
+        for comment in self.trailing_comments() {
+            writeln!(&mut out, "#{}", comment).unwrap();
+        }
+        Ok(out)
+    }
+
+    /// Render the circuit as the body of a quantikz environment, with one wire per qubit.
+    /// Qubits is the number of qubits to treat as non-ancilla, the rest are labelled as |0>.
+    /// Gates are packed greedily into columns, and the global phase is dropped.
+    pub fn to_quantikz(&self, qubits: usize) -> String {
+        let n = qubits.max(self.qubits());
+        let mut columns: Vec<Vec<String>> = Vec::new();
+        // The span of wires each column uses, since a multi-qubit gate's vertical line would
+        // cross any gate on the wires between its qubits, even if they don't overlap
+        let mut spans: Vec<Vec<(usize, usize)>> = Vec::new();
+        for &g in &self.gates {
+            let q = g.qubits();
+            let span = (q.iter().map(|q| q.0).min().unwrap(), q.iter().map(|q| q.0).max().unwrap());
+            let fits = |column: &Vec<(usize, usize)>| column.iter().all(|&(lo, hi)| hi < span.0 || span.1 < lo);
+            if !spans.last().is_some_and(fits) {
+                columns.push(vec![String::from("\\qw"); n]);
+                spans.push(Vec::new());
+            }
+            let column = columns.last_mut().unwrap();
+            spans.last_mut().unwrap().push(span);
+
+            let ctrl = |from: usize, to: usize| format!("\\ctrl{{{}}}", to as isize - from as isize);
+            match g {
+                Gate::X(Qubit(q)) => column[q] = "\\gate{X}".into(),
+                Gate::CNOT(Qubit(c), Qubit(t)) => {
+                    column[c] = ctrl(c, t);
+                    column[t] = "\\targ{}".into();
+                },
+                Gate::Phase(Phase(p), Qubit(q)) => column[q] = match p {
+                    0 => "\\qw".into(),
+                    1 => "\\gate{T}".into(),
+                    2 => "\\gate{S}".into(),
+                    4 => "\\gate{Z}".into(),
+                    6 => "\\gate{S^\\dagger}".into(),
+                    7 => "\\gate{T^\\dagger}".into(),
+                    p => format!("\\gate{{P({p}\\pi/4)}}")
+                },
+                Gate::CZ(Qubit(a), Qubit(b)) => {
+                    column[a] = ctrl(a, b);
+                    column[b] = "\\control{}".into();
+                },
+                Gate::CS(Qubit(a), Qubit(b)) => {
+                    column[a] = ctrl(a, b);
+                    column[b] = "\\gate{S}".into();
+                },
+                Gate::CCZ(a, b, c) => {
+                    let mut q = [a.0, b.0, c.0];
+                    q.sort();
+                    column[q[0]] = ctrl(q[0], q[1]);
+                    column[q[1]] = ctrl(q[1], q[2]);
+                    column[q[2]] = "\\control{}".into();
+                },
+                Gate::SWAP(Qubit(a), Qubit(b)) => {
+                    column[a] = format!("\\swap{{{}}}", b as isize - a as isize);
+                    column[b] = "\\targX{}".into();
+                },
+                Gate::H(Qubit(q)) => column[q] = "\\gate{H}".into(),
+                Gate::I(_) => ()
+            }
+        }
+
+        let mut out = String::new();
+        writeln!(&mut out, "\\begin{{quantikz}}").unwrap();
+        for q in 0..n {
+            let label = if q < qubits { format!("\\lstick{{$q_{{{q}}}$}}") } else { String::from("\\lstick{$\\ket{0}$}") };
+            let row = std::iter::once(label)
+                .chain(columns.iter().map(|column| column[q].clone()))
+                .chain(std::iter::once(String::from("\\qw")))
+                .collect::<Vec<_>>();
+            let end = if q + 1 < n { " \\\\" } else { "" };
+            writeln!(&mut out, "{}{}", row.join(" & "), end).unwrap();
+        }
+        writeln!(&mut out, "\\end{{quantikz}}").unwrap();
+        out
+    }
+
+    /// Encode the circuit as a link to the Quirk simulator. Quirk can control any gate, so CZ,
+    /// CS and CCZ are written as Z and S gates with controls, each in a column of its own since
+    /// controls apply to the whole column. Uncontrolled single-qubit gates share columns.
+    pub fn to_quirk_url(&self) -> String {
+        let n = self.qubits();
+        let mut cols: Vec<Vec<serde_json::Value>> = Vec::new();
+        let mut shared = false;
+        for &g in &self.gates {
+            let mut col = vec![serde_json::Value::from(1); n];
+            let single: Option<(usize, &[&str])> = match g {
+                // Quirk only has phases by powers of two, so the odd multiples take two gates
+                Gate::Phase(Phase(p), Qubit(q)) => {
+                    let tokens: &[&str] = match p {
+                        0 => &[],
+                        1 => &["Z^¼"],
+                        2 => &["Z^½"],
+                        3 => &["Z^½", "Z^¼"],
+                        4 => &["Z"],
+                        5 => &["Z", "Z^¼"],
+                        6 => &["Z^-½"],
+                        7 => &["Z^-¼"],
+                        _ => panic!("unknown phase {p}")
+                    };
+                    Some((q, tokens))
+                },
+                Gate::X(Qubit(q)) => Some((q, &["X"][..])),
+                Gate::H(Qubit(q)) => Some((q, &["H"][..])),
+                Gate::I(_) => continue,
+                Gate::CNOT(Qubit(c), Qubit(t)) => { col[c] = "•".into(); col[t] = "X".into(); None },
+                Gate::CZ(Qubit(a), Qubit(b)) => { col[a] = "•".into(); col[b] = "Z".into(); None },
+                Gate::CS(Qubit(a), Qubit(b)) => { col[a] = "•".into(); col[b] = "Z^½".into(); None },
+                Gate::CCZ(Qubit(a), Qubit(b), Qubit(c)) => { col[a] = "•".into(); col[b] = "•".into(); col[c] = "Z".into(); None },
+                Gate::SWAP(Qubit(a), Qubit(b)) => { col[a] = "Swap".into(); col[b] = "Swap".into(); None }
+            };
+
+            if let Some((q, tokens)) = single {
+                for &token in tokens {
+                    if !shared || cols.last().unwrap()[q] != 1 {
+                        cols.push(vec![1.into(); n]);
+                    }
+                    cols.last_mut().unwrap()[q] = token.into();
+                    shared = true;
+                }
+            } else {
+                cols.push(col);
+                shared = false;
+            }
+        }
+
+        let json = serde_json::json!({ "cols": cols }).to_string();
+        let mut url = String::from("https://algassert.com/quirk#circuit=");
+        for byte in json.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+                url.push(byte as char);
+            } else {
+                write!(&mut url, "%{:02X}", byte).unwrap();
+            }
+        }
+        url
+    }
+
+    /// Render the gate dependency DAG in Graphviz DOT format. Each gate is a node
+    /// labelled by its type and qubits, with an edge to the next gate on each of its qubits.
+    pub fn to_dependency_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(&mut out, "digraph circuit {{").unwrap();
+        writeln!(&mut out, "    node [shape=box];").unwrap();
+
+        let mut last: HashMap<Qubit, usize> = HashMap::new();
+        for (i, &g) in self.gates.iter().enumerate() {
+            let (name, qubits) = match g {
+                Gate::X(q) => ("X".to_string(), vec![q]),
+                Gate::CNOT(c, t) => ("CNOT".to_string(), vec![c, t]),
+                Gate::Phase(Phase(p), q) => (format!("Phase({p})"), vec![q]),
+                Gate::CZ(a, b) => ("CZ".to_string(), vec![a, b]),
+                Gate::CS(a, b) => ("CS".to_string(), vec![a, b]),
+                Gate::CCZ(a, b, c) => ("CCZ".to_string(), vec![a, b, c]),
+                Gate::SWAP(a, b) => ("SWAP".to_string(), vec![a, b]),
+                Gate::H(q) => ("H".to_string(), vec![q]),
+                Gate::I(q) => ("I".to_string(), vec![q])
+            };
+            let labels = qubits.iter().map(|Qubit(q)| q.to_string()).collect::<Vec<_>>();
+            writeln!(&mut out, "    g{i} [label=\"{name} {}\"];", labels.join(" ")).unwrap();
+
+            // A gate may follow the same predecessor on several qubits, but only needs one edge
+            let mut preds = qubits.iter().filter_map(|q| last.insert(*q, i)).collect::<Vec<_>>();
+            preds.sort();
+            preds.dedup();
+            for p in preds {
+                writeln!(&mut out, "    g{p} -> g{i};").unwrap();
+            }
+        }
+
+        writeln!(&mut out, "}}").unwrap();
+        out
+    }
+
     /// Parse a circuit from a .qasm source file.
+    /// Whole-line `//` comments are kept at the start of the circuit, since
+    /// gate definitions make their position in the gate list ambiguous.
     /// Specify opaque = true to add opaque gate definitions for 
-    /// ccz and swap to appease the typechecker.
-    pub fn from_openqasm<P: AsRef<Path>>(cache: &mut oq::SourceCache, path: P, opaque: bool) -> Result<Circuit, oq::Errors> {
+    /// ccz, cs, swap, gphase, cp and ccp to appease the typechecker,
+    /// except for those the file defines itself.
+    /// Angles are recognized as multiples of pi/4 up to `tolerance` radians.
+    /// Files ending in `.gz` are decompressed first.
+    pub fn from_openqasm<P: AsRef<Path>>(cache: &mut oq::SourceCache, path: P, opaque: bool, tolerance: f64) -> Result<Circuit, oq::Errors> {
+        // Unreadable files are parsed from the path so the parser reports the error
+        let source = read_source(&path).ok();
+        Circuit::parse_openqasm_source(cache, source, &strip_gzip(&path), path.as_ref().parent(), opaque, tolerance)
+    }
+
+    /// Parse a single circuit from `source` under the file name `name`, or from the file `name` itself if
+    /// there is no source. The parser can only read plain files, so compressed sources are decompressed
+    /// by the caller and parsed under their name without `.gz`, which keeps includes relative to `dir`.
+    fn parse_openqasm_source(cache: &mut oq::SourceCache, source: Option<String>, name: &Path, dir: Option<&Path>, opaque: bool, tolerance: f64) -> Result<Circuit, oq::Errors> {
         let mut parser = oq::Parser::new(cache);
         if opaque {
-            parser.parse_source::<String>("
-                opaque ccz a, b, c;
-                opaque cs a, b;
-                opaque swap a, b;
-            ".to_string(), None);
+            parser.parse_source::<String>(opaque_definitions(source.as_deref().unwrap_or_default(), dir), None);
+        }
+        let mut circuit = Circuit::new(Vec::new());
+        if let Some(source) = &source {
+            circuit.comments = source.lines()
+                .filter_map(|line| line.trim_start().strip_prefix("//"))
+                .map(|comment| (0, comment.to_string()))
+                .collect();
         }
-        let id = parser.parse_file(path);
+        let id = match source {
+            Some(source) => parser.parse_source(source, Some(name)),
+            None => parser.parse_file(name)
+        };
         let prog = parser.done()
             .to_errors()?;
         prog.type_check()
             .to_errors()?;
-        let mut circuit = Circuit { gates: Vec::new() };
-        let mut linear = oq::Linearize::new(&mut circuit)
+        let mut linear = oq::Linearize::new(QasmWriter::new(&mut circuit, tolerance))
             .with_policy(oq::translate::ExpansionPolicy::new()
                 .allow_file(id));
         linear.walk_program(&prog)
@@ -242,17 +839,66 @@ impl Circuit {
         Ok(circuit)
     }
 
+    /// Parse several circuits from a single .qasm source file, where the circuits are
+    /// concatenated and separated by `// CIRCUIT N` marker lines or repeated `OPENQASM` headers.
+    /// Each circuit is parsed independently, as if it were in its own file next to the original.
+    pub fn from_openqasm_multi<P: AsRef<Path>>(cache: &mut oq::SourceCache, path: P, opaque: bool, tolerance: f64) -> Result<Vec<Circuit>, oq::Errors> {
+        // Unreadable files are left to from_openqasm to report
+        let segments = read_source(&path)
+            .map(|source| split_openqasm(&source))
+            .unwrap_or_default();
+        if segments.len() <= 1 {
+            return Ok(vec![Circuit::from_openqasm(cache, path, opaque, tolerance)?])
+        }
+
+        let mut circuits = Vec::new();
+        let mut errors = oq::Errors { errors: Vec::new() };
+        for (k, segment) in segments.into_iter().enumerate() {
+            // Give each segment its own name in the cache, but keep it in the same directory for includes
+            let name = strip_gzip(&path);
+            let name = name.with_file_name(format!(
+                "{}#{}",
+                name.file_name().unwrap().to_string_lossy(),
+                k
+            ));
+            match Circuit::parse_openqasm_source(cache, Some(segment), &name, path.as_ref().parent(), opaque, tolerance) {
+                Ok(circuit) => circuits.push(circuit),
+                Err(mut err) => errors.errors.append(&mut err.errors)
+            }
+        }
+
+        if errors.errors.is_empty() {
+            Ok(circuits)
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Parse a circuit from .qc source.
+    /// Lines starting with `#` are kept as comments before the following gate.
     pub fn from_qc(source: &str) -> Result<Circuit, String> {
+        Circuit::from_qc_labeled(source).map(|(circuit, _, _)| circuit)
+    }
+
+    /// As `from_qc`, also returning the labels from the `.v` line, where the label of
+    /// `Qubit(i)` is at index i, and the input qubits from the `.i` line in order.
+    pub fn from_qc_labeled(source: &str) -> Result<(Circuit, Vec<String>, Vec<Qubit>), String> {
         let mut lines = source.lines();
+        let mut labels = Vec::new();
+        let mut inputs = Vec::new();
         let mut qubits = HashMap::new();
         let mut gates = Vec::new();
+        let mut comments = Vec::new();
 
         while let Some(line) = lines.next() {
-            if line.starts_with("#") 
+            if let Some(comment) = line.strip_prefix("#") {
+                comments.push((gates.len(), comment.to_string()));
+                continue;
+            }
+
+            if line.trim().is_empty()
                 || line.starts_with("BEGIN") 
                 || line.starts_with("END")
-                || line.starts_with(".i")
                 || line.starts_with(".o") {
                 continue;
             }
@@ -260,7 +906,11 @@ impl Circuit {
             if let Some(rest) = line.strip_prefix(".v ") {
                 for qubit in rest.split(' ') {
                     qubits.insert(qubit, Qubit(qubits.len()));
+                    labels.push(qubit.to_string());
                 }
+            } else if let Some(rest) = line.strip_prefix(".i") {
+                // Resolved once all the lines are read, in case `.v` comes later
+                inputs.extend(rest.split_whitespace());
             } else {
                 let mut parts = line.split(' ');
                 let gate = parts.next().ok_or(format!("Expected gate name in {}", line))?;
@@ -269,15 +919,29 @@ impl Circuit {
                         .copied()
                         .ok_or(format!("Unexpected qubit label {:?} in {}", arg, line))
                 }).collect::<Result<Vec<_>, _>>()?;
+
+                // Toffolis are multiply-controlled NOTs, with to_qc writing CCZ as one wrapped in Hadamards
+                if gate == "tof" || gate == "toffoli" {
+                    match args[..] {
+                        [t] => gates.push(Gate::X(t)),
+                        [c, t] => gates.push(Gate::CNOT(c, t)),
+                        [a, b, t] => gates.extend([Gate::H(t), Gate::CCZ(a, b, t), Gate::H(t)]),
+                        _ => return Err(format!("Unexpected arity {} for gate {:?}, expected 1 to 3 in {}", args.len(), gate, line))
+                    }
+                    continue;
+                }
+
                 let (con, arity): (fn(&[Qubit]) -> Gate, usize) = match gate {
                     "T" => (|args| Gate::Phase(Phase(1), args[0]), 1),
                     "T*" => (|args| Gate::Phase(Phase(7), args[0]), 1),
                     "S" => (|args| Gate::Phase(Phase(2), args[0]), 1),
                     "S*" => (|args| Gate::Phase(Phase(6), args[0]), 1),
-                    "Z" => (|args| Gate::Phase(Phase(4), args[0]), 1),
+                    "Z" | "Z*" => (|args| Gate::Phase(Phase(4), args[0]), 1),
                     "H" => (|args| Gate::H(args[0]), 1),
-                    "not" | "X" => (|args| Gate::X(args[0]), 1),
+                    "not" | "X" | "X*" => (|args| Gate::X(args[0]), 1),
                     "cz" => (|args| Gate::CZ(args[0], args[1]), 2),
+                    "cs" => (|args| Gate::CS(args[0], args[1]), 2),
+                    "ccz" => (|args| Gate::CCZ(args[0], args[1], args[2]), 3),
                     "cnot" => (|args| Gate::CNOT(args[0], args[1]), 2),
                     "swap" => (|args| Gate::SWAP(args[0], args[1]), 2),
                     _ => return Err(format!("Unknown gate name {:?} in {}", gate, line))
@@ -291,33 +955,40 @@ impl Circuit {
             }
         }
 
-        Ok(Circuit { gates })
+        let inputs = inputs.into_iter()
+            .map(|input| qubits.get(input).copied().ok_or(format!("Unexpected input qubit label {:?}", input)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut circuit = Circuit::new(gates);
+        circuit.comments = comments;
+        Ok((circuit, labels, inputs))
+    }
+
+    /// Translate the circuit to .qc, as `to_qc`, but naming `Qubit(i)` by `labels[i]` and
+    /// declaring `inputs` on the `.i` line, so that the output of `from_qc_labeled` round-trips.
+    /// Any qubits beyond the labels, such as ancillas, keep their index as their name.
+    pub fn to_qc_labeled(&self, labels: &[String], inputs: &[Qubit]) -> String {
+        let label = |i: usize| labels.get(i).cloned().unwrap_or_else(|| i.to_string());
+        self.write_qc(&DecompositionTable::default(), label, inputs)
     }
 
     /// Translate into a quizx circuit.
     pub fn to_zx(&self) -> quizx::circuit::Circuit {
+        self.to_zx_with(&DecompositionTable::default())
+    }
+
+    /// As `to_zx`, lowering CZ, CS, CCZ and SWAP gates as given by the decomposition table.
+    /// quizx has CZ, CCZ and SWAP natively, so only custom decompositions change those.
+    pub fn to_zx_with(&self, table: &DecompositionTable) -> quizx::circuit::Circuit {
         let mut circ = quizx::circuit::Circuit::new(self.qubits());
-        for &gate in &self.gates {
+        for gate in self.gates.iter().flat_map(|&g| table.expand(g)) {
             match gate {
                 Gate::H(Qubit(q)) => circ.add_gate("h", vec![q]),
+                Gate::I(_) => (),
                 Gate::X(Qubit(q)) => circ.add_gate("x", vec![q]),
-                Gate::Phase(Phase(p), Qubit(q)) => match p {
-                    0 => (),
-                    1 => circ.add_gate("t", vec![q]),
-                    2 => circ.add_gate("s", vec![q]),
-                    3 => {
-                        circ.add_gate("s", vec![q]);
-                        circ.add_gate("t", vec![q]);
-                    },
-                    4 => circ.add_gate("z", vec![q]),
-                    5 => {
-                        circ.add_gate("z", vec![q]);
-                        circ.add_gate("t", vec![q]);
-                    },
-                    6 => circ.add_gate("sdg", vec![q]),
-                    7 => circ.add_gate("tdg", vec![q]),
-                    _ => unreachable!()
-                },
+                // A single rational phase keeps the graph small, rather than e.g. S then T for 3pi/4
+                Gate::Phase(Phase(0), _) => (),
+                Gate::Phase(Phase(p), Qubit(q)) => circ.add_gate_with_phase("rz", vec![q], Rational64::new(p as i64, 4)),
                 Gate::CNOT(Qubit(c), Qubit(t)) => circ.add_gate("cx", vec![c, t]),
                 Gate::SWAP(Qubit(a), Qubit(b)) => circ.add_gate("swap", vec![a, b]),
                 Gate::CZ(Qubit(a), Qubit(b)) => circ.add_gate("cz", vec![a, b]),
@@ -343,10 +1014,8 @@ impl Circuit {
                 GType::HAD => gates.push(Gate::H(Qubit(gate.qs[0]))),
                 GType::NOT => gates.push(Gate::X(Qubit(gate.qs[0]))),
                 GType::ZPhase => {
-                    let mut phase = *gate.phase.to_rational().numer() * (4 / *gate.phase.to_rational().denom());
-                    if phase < 0 {
-                        phase = phase + 8;
-                    }
+                    // Reduce mod 8 so that phases outside of [0, 2pi) are still valid
+                    let phase = (*gate.phase.to_rational().numer() * (4 / *gate.phase.to_rational().denom())).rem_euclid(8);
                     gates.push(Gate::Phase(Phase(phase as usize), Qubit(gate.qs[0])))
                 },
                 GType::CNOT => gates.push(Gate::CNOT(Qubit(gate.qs[0]), Qubit(gate.qs[1]))),
@@ -356,10 +1025,246 @@ impl Circuit {
                 _ => panic!("Unexpected gate: {:?}", gate)
             }
         }
-        Circuit { gates }
+        Circuit::new(gates)
+    }
+
+    /// Serialize the ZX graph of `to_zx` as PyZX graph JSON, loadable with `pyzx.Graph.from_json`.
+    /// Phases are written as fractions of pi in [0, 2), and Hadamards stay as Hadamard edges.
+    pub fn to_pyzx_json(&self) -> String {
+        use quizx::graph::{GraphLike, VType, EType};
+
+        let graph = self.to_zx().to_graph::<quizx::hash_graph::Graph>();
+        let mut vertices = graph.vertices().collect::<Vec<_>>();
+        vertices.sort();
+
+        let vertices = vertices.into_iter().map(|v| {
+            // PyZX's VertexType: BOUNDARY = 0, Z = 1, X = 2, H_BOX = 3
+            let t = match graph.vertex_type(v) {
+                VType::B => 0,
+                VType::Z => 1,
+                VType::X => 2,
+                VType::H => 3,
+                t => panic!("Unexpected vertex type: {:?}", t)
+            };
+            let mut vertex = serde_json::json!({
+                "id": v,
+                "t": t,
+                "pos": [f64::from(graph.row(v)), f64::from(graph.qubit(v))]
+            });
+            let phase = graph.phase(v).to_rational();
+            let (numer, denom) = (phase.numer().rem_euclid(2 * phase.denom()), *phase.denom());
+            if numer != 0 {
+                vertex["phase"] = if denom == 1 { format!("{numer}") } else { format!("{numer}/{denom}") }.into();
+            }
+            vertex
+        }).collect::<Vec<_>>();
+
+        let mut edges = graph.edges().map(|(s, t, ty)| {
+            // PyZX's EdgeType: SIMPLE = 1, HADAMARD = 2
+            let ty = match ty {
+                EType::N => 1,
+                EType::H => 2,
+                ty => panic!("Unexpected edge type: {:?}", ty)
+            };
+            (s.min(t), s.max(t), ty)
+        }).collect::<Vec<_>>();
+        edges.sort();
+
+        serde_json::to_string(&serde_json::json!({
+            "version": 2,
+            "backend": "simple",
+            "variable_types": {},
+            "inputs": graph.inputs(),
+            "outputs": graph.outputs(),
+            "vertices": vertices,
+            "edges": edges
+        })).expect("Couldn't serialize ZX graph")
     }
 }
 
+impl Circuit {
+    /// Decompose U(theta, phi, lambda) = P(phi) Ry(theta) P(lambda) into H and phase gates.
+    /// Only supports theta a multiple of pi/2 and phi, lambda multiples of pi/4.
+    fn push_u(&mut self, theta: &oq::Value, phi: &oq::Value, lambda: &oq::Value, q: Qubit, tolerance: f64) -> Result<(), UnexpectedGate> {
+        let unexpected = || UnexpectedGate(format!("U({theta}, {phi}, {lambda})"));
+        let theta = Phase::from_value(theta, tolerance)
+            .filter(|p| p.is_clifford())
+            .ok_or_else(unexpected)?;
+        let phi = Phase::from_value(phi, tolerance).ok_or_else(unexpected)?;
+        let lambda = Phase::from_value(lambda, tolerance).ok_or_else(unexpected)?;
+        self.push_u_phases(theta, phi, lambda, q);
+        Ok(())
+    }
+
+    /// As `push_u`, with angles given as phases (theta must be Clifford).
+    fn push_u_phases(&mut self, theta: Phase, phi: Phase, lambda: Phase, q: Qubit) {
+        if lambda != Phase(0) {
+            self.gates.push(Gate::Phase(lambda, q));
+        }
+        // Ry(theta) up to a global phase:
+        match theta.0 {
+            0 => (),
+            2 => {
+                self.gates.push(Gate::Phase(Phase::Z, q));
+                self.gates.push(Gate::H(q));
+            },
+            4 => {
+                self.gates.push(Gate::Phase(Phase::Z, q));
+                self.gates.push(Gate::X(q));
+            },
+            6 => {
+                self.gates.push(Gate::H(q));
+                self.gates.push(Gate::Phase(Phase::Z, q));
+            },
+            _ => unreachable!()
+        }
+        if phi != Phase(0) {
+            self.gates.push(Gate::Phase(phi, q));
+        }
+    }
+}
+
+fn write_qasm2_gate(out: &mut String, g: Gate) -> std::fmt::Result {
+    match g {
+        Gate::X(Qubit(q)) => writeln!(out, "x q[{q}];"),
+        Gate::CNOT(Qubit(c), Qubit(t)) => writeln!(out, "cx q[{c}], q[{t}];"),
+        Gate::Phase(p, Qubit(q)) => write_phase(out, p, q),
+        Gate::CZ(Qubit(p), Qubit(q)) => writeln!(out, "cz q[{p}], q[{q}];"),
+        Gate::CS(Qubit(p), Qubit(q)) => writeln!(out, "cs q[{p}], q[{q}];"),
+        Gate::CCZ(Qubit(p), Qubit(q), Qubit(r)) => writeln!(out, "ccz q[{p}], q[{q}], q[{r}];"),
+        Gate::SWAP(Qubit(a), Qubit(b)) => writeln!(out, "cx q[{a}], q[{b}];\ncx q[{b}], q[{a}];\ncx q[{a}], q[{b}];"),
+        Gate::H(Qubit(q)) => writeln!(out, "h q[{q}];"),
+        Gate::I(_) => Ok(())
+    }
+}
+
+fn write_qasm3_gate(out: &mut String, g: Gate) -> std::fmt::Result {
+    match g {
+        Gate::X(Qubit(q)) => writeln!(out, "x q[{q}];"),
+        Gate::CNOT(Qubit(c), Qubit(t)) => writeln!(out, "cx q[{c}], q[{t}];"),
+        Gate::Phase(p, Qubit(q)) => write_phase(out, p, q),
+        Gate::CZ(Qubit(p), Qubit(q)) => writeln!(out, "cz q[{p}], q[{q}];"),
+        Gate::CS(Qubit(p), Qubit(q)) => writeln!(out, "ctrl @ s q[{p}], q[{q}];"),
+        Gate::CCZ(Qubit(p), Qubit(q), Qubit(r)) => writeln!(out, "ctrl @ ctrl @ z q[{p}], q[{q}], q[{r}];"),
+        Gate::SWAP(Qubit(a), Qubit(b)) => writeln!(out, "swap q[{a}], q[{b}];"),
+        Gate::H(Qubit(q)) => writeln!(out, "h q[{q}];"),
+        Gate::I(_) => Ok(())
+    }
+}
+
+/// Write a phase gate in terms of t, s, z and their inverses, which are spelled the same
+/// in OPENQASM 2.0 and 3.0.
+fn write_phase(out: &mut String, Phase(p): Phase, q: usize) -> std::fmt::Result {
+    match p {
+        0 => Ok(()),
+        1 => writeln!(out, "t q[{q}];"),
+        2 => writeln!(out, "s q[{q}];"),
+        3 => writeln!(out, "s q[{q}];\nt q[{q}];"),
+        4 => writeln!(out, "z q[{q}];"),
+        5 => writeln!(out, "z q[{q}];\nt q[{q}];"),
+        6 => writeln!(out, "sdg q[{q}];"),
+        7 => writeln!(out, "tdg q[{q}];"),
+        _ => panic!("unknown phase {p}")
+    }
+}
+
+/// Whether a path has a `.gz` extension, and so is read through gzip decompression.
+pub fn is_gzip<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "gz")
+}
+
+/// The name a possibly gzip-compressed file is parsed and named outputs under, with any `.gz` removed.
+pub fn strip_gzip<P: AsRef<Path>>(path: P) -> std::path::PathBuf {
+    if is_gzip(&path) {
+        path.as_ref().with_extension("")
+    } else {
+        path.as_ref().to_path_buf()
+    }
+}
+
+/// Read a source file, transparently decompressing it if it is gzip-compressed.
+fn read_source<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    if is_gzip(&path) {
+        let mut source = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(std::fs::File::open(path)?), &mut source)?;
+        Ok(source)
+    } else {
+        std::fs::read_to_string(path)
+    }
+}
+
+/// The opaque definitions added by `from_openqasm`, as a gate name and its definition.
+const OPAQUE_DEFINITIONS: [(&str, &str); 6] = [
+    ("ccz", "opaque ccz a, b, c;"),
+    ("cs", "opaque cs a, b;"),
+    ("swap", "opaque swap a, b;"),
+    ("gphase", "opaque gphase(g) a;"),
+    ("cp", "opaque cp(l) a, b;"),
+    ("ccp", "opaque ccp(l) a, b, c;")
+];
+
+/// The opaque definitions to add before a source, leaving out the gates that it, or any file it
+/// includes from `dir`, already defines, since redefining a gate fails type checking.
+fn opaque_definitions(source: &str, dir: Option<&Path>) -> String {
+    let mut sources = vec![source.to_string()];
+    for line in source.lines() {
+        let Some(name) = line.trim().strip_prefix("include") else { continue };
+        let name = name.trim().trim_end_matches(';').trim().trim_matches('"');
+        if let Ok(included) = read_source(dir.unwrap_or(Path::new(".")).join(name)) {
+            sources.push(included);
+        }
+    }
+
+    let defined = sources.iter()
+        .flat_map(|source| {
+            let tokens = source.split(|c: char| c.is_whitespace() || "(;,{".contains(c))
+                .filter(|t| !t.is_empty())
+                .collect::<Vec<_>>();
+            tokens.windows(2)
+                .filter(|w| w[0] == "gate" || w[0] == "opaque")
+                .map(|w| w[1].to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect::<HashSet<_>>();
+
+    OPAQUE_DEFINITIONS.iter()
+        .filter(|(name, _)| !defined.contains(*name))
+        .map(|(_, definition)| *definition)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split concatenated qasm sources on `// CIRCUIT` markers and repeated `OPENQASM` headers,
+/// dropping the markers themselves and any segments that are only whitespace.
+fn split_openqasm(source: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut has_header = false;
+    for line in source.lines() {
+        let trimmed = line.trim_start();
+        let marker = trimmed.strip_prefix("//").map_or(false, |c| c.trim_start().starts_with("CIRCUIT"));
+        let header = trimmed.starts_with("OPENQASM");
+        if marker || (header && has_header) {
+            if !current.trim().is_empty() {
+                segments.push(std::mem::take(&mut current));
+            }
+            current.clear();
+            has_header = false;
+        }
+
+        if !marker {
+            has_header |= header;
+            current.push_str(line);
+            current.push('\n');
+        }
+    }
+
+    if !current.trim().is_empty() {
+        segments.push(current);
+    }
+    segments
+}
+
 #[derive(Debug)]
 pub struct UnexpectedGate(String);
 
@@ -371,63 +1276,192 @@ impl std::fmt::Display for UnexpectedGate {
 
 impl std::error::Error for UnexpectedGate {}
 
-impl<'s> openqasm::GateWriter for &'s mut Circuit {
-    type Error = UnexpectedGate;
+/// Collects the gates of a qasm program into a circuit.
+struct QasmWriter<'a> {
+    circuit: &'a mut Circuit,
+    /// Tolerance in radians for recognizing angles as multiples of pi/4
+    tolerance: f64,
+    /// The condition being written, with the number of gates and the global phase before it started
+    conditional: Option<(Condition, usize, Phase)>,
+    /// Qubits measured or corrected so far, which later unitary gates can't act on
+    /// since the measurements and corrections are deferred to the end
+    deferred: HashSet<Qubit>
+}
 
-    fn initialize(&mut self, _qubits: &[openqasm::Symbol], _bits: &[openqasm::Symbol]) -> Result<(), Self::Error> {
-        Ok(())
+impl<'a> QasmWriter<'a> {
+    fn new(circuit: &'a mut Circuit, tolerance: f64) -> QasmWriter<'a> {
+        QasmWriter { circuit, tolerance, conditional: None, deferred: HashSet::new() }
     }
 
-    fn write_cx(&mut self, copy: usize, xor: usize) -> Result<(), Self::Error> {
-        self.gates.push(Gate::CNOT(Qubit(copy), Qubit(xor)));
+    /// Check that the unitary gates written from index `start` don't act on a qubit
+    /// after it was measured or corrected, which would make deferring those invalid.
+    fn check_deferred(&self, start: usize) -> Result<(), UnexpectedGate> {
+        if self.conditional.is_some() {
+            return Ok(())
+        }
+        for &g in &self.circuit.gates[start..] {
+            if g.qubits().iter().any(|q| self.deferred.contains(q)) {
+                return Err(UnexpectedGate(format!("{:?} after a measurement or classically controlled gate on the same qubit", g)))
+            }
+        }
         Ok(())
     }
 
-    fn write_opaque(&mut self, name: &openqasm::Symbol, _params: &[openqasm::Value], args: &[usize]) -> Result<(), Self::Error> {
+    fn push_opaque(&mut self, name: &openqasm::Symbol, params: &[openqasm::Value], args: &[usize]) -> Result<(), UnexpectedGate> {
         match name.as_str() {
-            "t" | "T" => self.gates.push(Gate::Phase(Phase::T, Qubit(args[0]))),
-            "s" | "S" => self.gates.push(Gate::Phase(Phase::S, Qubit(args[0]))),
-            "z" | "Z" => self.gates.push(Gate::Phase(Phase::Z, Qubit(args[0]))),
-            "sdg" | "Sdg" => self.gates.push(Gate::Phase(-Phase::S, Qubit(args[0]))),
-            "tdg" | "Tdg" => self.gates.push(Gate::Phase(-Phase::T, Qubit(args[0]))),
-            "x" | "X" => self.gates.push(Gate::X(Qubit(args[0]))),
-            "cx" | "cnot" | "CX" | "CNOT" => self.gates.push(Gate::CNOT(Qubit(args[0]), Qubit(args[1]))),
-            "cz" | "CZ" => self.gates.push(Gate::CZ(Qubit(args[0]), Qubit(args[1]))),
-            "cs" | "CS" => self.gates.push(Gate::CS(Qubit(args[0]), Qubit(args[1]))),
-            "ccz" | "CCZ" => self.gates.push(Gate::CCZ(Qubit(args[0]), Qubit(args[1]), Qubit(args[2]))),
-            "swap" | "SWAP" => self.gates.push(Gate::SWAP(Qubit(args[0]), Qubit(args[1]))),
+            "t" | "T" => self.circuit.gates.push(Gate::Phase(Phase::T, Qubit(args[0]))),
+            "s" | "S" => self.circuit.gates.push(Gate::Phase(Phase::S, Qubit(args[0]))),
+            "z" | "Z" => self.circuit.gates.push(Gate::Phase(Phase::Z, Qubit(args[0]))),
+            "sdg" | "Sdg" => self.circuit.gates.push(Gate::Phase(-Phase::S, Qubit(args[0]))),
+            "tdg" | "Tdg" => self.circuit.gates.push(Gate::Phase(-Phase::T, Qubit(args[0]))),
+            "x" | "X" => self.circuit.gates.push(Gate::X(Qubit(args[0]))),
+            "cx" | "cnot" | "CX" | "CNOT" => self.circuit.gates.push(Gate::CNOT(Qubit(args[0]), Qubit(args[1]))),
+            "cz" | "CZ" => self.circuit.gates.push(Gate::CZ(Qubit(args[0]), Qubit(args[1]))),
+            "cs" | "CS" => self.circuit.gates.push(Gate::CS(Qubit(args[0]), Qubit(args[1]))),
+            "ccz" | "CCZ" => self.circuit.gates.push(Gate::CCZ(Qubit(args[0]), Qubit(args[1]), Qubit(args[2]))),
+            "swap" | "SWAP" => self.circuit.gates.push(Gate::SWAP(Qubit(args[0]), Qubit(args[1]))),
             "ccx" | "CCX" => {
-                self.gates.push(Gate::H(Qubit(args[2])));
-                self.gates.push(Gate::CCZ(Qubit(args[0]), Qubit(args[1]), Qubit(args[2])));
-                self.gates.push(Gate::H(Qubit(args[2])));
+                self.circuit.gates.push(Gate::H(Qubit(args[2])));
+                self.circuit.gates.push(Gate::CCZ(Qubit(args[0]), Qubit(args[1]), Qubit(args[2])));
+                self.circuit.gates.push(Gate::H(Qubit(args[2])));
+            },
+            "h" | "H" => self.circuit.gates.push(Gate::H(Qubit(args[0]))),
+            "u0" => (),
+            // The qubit argument of gphase is only there to satisfy the OPENQASM 2.0 grammar
+            "gphase" => match Phase::from_value(&params[0], self.tolerance) {
+                Some(p) => self.circuit.global_phase = self.circuit.global_phase + p,
+                None => return Err(UnexpectedGate(format!("gphase({})", params[0])))
+            },
+            "u1" => match Phase::from_value(&params[0], self.tolerance) {
+                Some(p) => self.circuit.gates.push(Gate::Phase(p, Qubit(args[0]))),
+                None => return Err(UnexpectedGate(format!("u1({})", params[0])))
+            },
+            // `p` is exact, but Rz(theta) = e^(-i theta/2) P(theta), and likewise for Rx and Ry below.
+            // The e^(-i theta/2) factor is dropped rather than added to the global phase, since it is
+            // only a multiple of pi/4 when theta is a multiple of pi/2
+            "rz" | "p" => match Phase::from_value(&params[0], self.tolerance) {
+                Some(p) => self.circuit.gates.push(Gate::Phase(p, Qubit(args[0]))),
+                None => return Err(UnexpectedGate(format!("{}({})", name.as_str(), params[0])))
+            },
+            "rx" => match Phase::from_value(&params[0], self.tolerance) {
+                Some(p) => {
+                    let q = Qubit(args[0]);
+                    self.circuit.gates.extend([Gate::H(q), Gate::Phase(p, q), Gate::H(q)]);
+                },
+                None => return Err(UnexpectedGate(format!("rx({})", params[0])))
+            },
+            // Ry(theta) = S Rx(theta) Sdg
+            "ry" => match Phase::from_value(&params[0], self.tolerance) {
+                Some(p) => {
+                    let q = Qubit(args[0]);
+                    self.circuit.gates.extend([Gate::Phase(-Phase::S, q), Gate::H(q), Gate::Phase(p, q), Gate::H(q), Gate::Phase(Phase::S, q)]);
+                },
+                None => return Err(UnexpectedGate(format!("ry({})", params[0])))
+            },
+            "u2" => match (Phase::from_value(&params[0], self.tolerance), Phase::from_value(&params[1], self.tolerance)) {
+                (Some(phi), Some(lambda)) => self.circuit.push_u_phases(Phase::S, phi, lambda, Qubit(args[0])),
+                _ => return Err(UnexpectedGate(format!("u2({}, {})", params[0], params[1])))
+            },
+            "u3" | "u" => self.circuit.push_u(&params[0], &params[1], &params[2], Qubit(args[0]), self.tolerance)?,
+            // Controlled phases have phase polynomial coefficients of lambda / 2 and doubly-controlled
+            // phases lambda / 4, so only multiples of pi/2 and pi respectively stay in Clifford+T
+            "cp" | "cu1" => {
+                let (a, b) = (Qubit(args[0]), Qubit(args[1]));
+                match Phase::from_value(&params[0], self.tolerance).map(|p| p.0) {
+                    Some(0) => (),
+                    Some(2) => self.circuit.gates.push(Gate::CS(a, b)),
+                    Some(4) => self.circuit.gates.push(Gate::CZ(a, b)),
+                    Some(6) => {
+                        self.circuit.gates.push(Gate::CS(a, b));
+                        self.circuit.gates.push(Gate::CZ(a, b));
+                    },
+                    _ => return Err(UnexpectedGate(format!("{}({})", name.as_str(), params[0])))
+                }
+            },
+            "ccp" => match Phase::from_value(&params[0], self.tolerance).map(|p| p.0) {
+                Some(0) => (),
+                Some(4) => self.circuit.gates.push(Gate::CCZ(Qubit(args[0]), Qubit(args[1]), Qubit(args[2]))),
+                _ => return Err(UnexpectedGate(format!("ccp({})", params[0])))
             },
-            "h" | "H" => self.gates.push(Gate::H(Qubit(args[0]))),
             _ => return Err(UnexpectedGate(name.as_str().to_string()))
         }
         Ok(())
     }
+}
 
-    fn write_u(&mut self, theta: openqasm::Value, phi: openqasm::Value, lambda: openqasm::Value, _reg: usize) -> Result<(), Self::Error> {
-        Err(UnexpectedGate(format!("U({theta}, {phi}, {lambda})")))
+impl<'s> openqasm::GateWriter for QasmWriter<'s> {
+    type Error = UnexpectedGate;
+
+    fn initialize(&mut self, _qubits: &[openqasm::Symbol], _bits: &[openqasm::Symbol]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn write_cx(&mut self, copy: usize, xor: usize) -> Result<(), Self::Error> {
+        let start = self.circuit.gates.len();
+        self.circuit.gates.push(Gate::CNOT(Qubit(copy), Qubit(xor)));
+        self.check_deferred(start)
+    }
+
+    fn write_opaque(&mut self, name: &openqasm::Symbol, params: &[openqasm::Value], args: &[usize]) -> Result<(), Self::Error> {
+        let start = self.circuit.gates.len();
+        self.push_opaque(name, params, args)?;
+        self.check_deferred(start)
+    }
+
+    fn write_u(&mut self, theta: openqasm::Value, phi: openqasm::Value, lambda: openqasm::Value, reg: usize) -> Result<(), Self::Error> {
+        let start = self.circuit.gates.len();
+        self.circuit.push_u(&theta, &phi, &lambda, Qubit(reg), self.tolerance)?;
+        self.check_deferred(start)
     }
 
     fn write_barrier(&mut self, _regs: &[usize]) -> Result<(), Self::Error> {
         Ok(())
     }
 
-    fn write_reset(&mut self, _reg: usize) -> Result<(), Self::Error> {
-        Err(UnexpectedGate(format!("reset")))
+    // Circuits here are unitary (up to ancilla postselection), so there is no `Gate::Reset`
+    // for ancilla reuse to build on, and resets are rejected rather than silently dropped.
+    fn write_reset(&mut self, reg: usize) -> Result<(), Self::Error> {
+        Err(UnexpectedGate(format!("reset q[{reg}] (mid-circuit resets are not supported)")))
     }
 
-    fn write_measure(&mut self, _from: usize, _to: usize) -> Result<(), Self::Error> {
-        Err(UnexpectedGate(format!("measure")))
+    // Measurements and classically controlled gates are deferred to the end of the circuit,
+    // which is only valid if no later unitary gate acts on the same qubits, and no correction
+    // reads a bit before it is measured or after it is measured again
+    fn write_measure(&mut self, from: usize, to: usize) -> Result<(), Self::Error> {
+        let q = Qubit(from);
+        if self.circuit.corrections.iter().any(|(_, g)| g.qubits().contains(&q)) {
+            return Err(UnexpectedGate(format!("measure q[{from}] after a classically controlled gate on the same qubit")))
+        }
+        if self.circuit.corrections.iter().any(|(c, _)| c.bits().contains(&to)) {
+            return Err(UnexpectedGate(format!("measure q[{from}] overwriting a bit read by an earlier classically controlled gate")))
+        }
+        self.circuit.measurements.push((q, to));
+        self.deferred.insert(q);
+        Ok(())
     }
 
-    fn start_conditional(&mut self, _reg: usize, _count: usize, _val: u64) -> Result<(), Self::Error> {
-        Err(UnexpectedGate(format!("if (...)")))
+    fn start_conditional(&mut self, reg: usize, count: usize, val: u64) -> Result<(), Self::Error> {
+        let condition = Condition { start: reg, count, value: val };
+        self.conditional = Some((condition, self.circuit.gates.len(), self.circuit.global_phase));
+        Ok(())
     }
 
     fn end_conditional(&mut self) -> Result<(), Self::Error> {
-        Err(UnexpectedGate(format!("if (...)")))
+        let Some((condition, start, global_phase)) = self.conditional.take() else {
+            return Ok(())
+        };
+        // Each branch is classically distinct after the measurement, so a conditional global phase is unobservable
+        self.circuit.global_phase = global_phase;
+        if let Some(bit) = condition.bits().find(|&b| !self.circuit.measurements.iter().any(|&(_, m)| m == b)) {
+            return Err(UnexpectedGate(format!("if (...) reading classical bit {bit} before it is measured")))
+        }
+        for g in self.circuit.gates.drain(start..).collect::<Vec<_>>() {
+            if !g.is_clifford() {
+                return Err(UnexpectedGate(format!("if (...) {:?} (only Clifford gates can be classically controlled)", g)))
+            }
+            self.deferred.extend(g.qubits());
+            self.circuit.corrections.push((condition, g));
+        }
+        Ok(())
     }
 }
@@ -2,30 +2,107 @@ use openqasm as oq;
 use ndarray as nd;
 use quizx::{extract::ToCircuit, hash_graph::{Graph, GraphLike}};
 use serde::Serialize;
-use std::{io::Write, path::{Path, PathBuf}};
+use std::{io::Write, path::{Path, PathBuf}, collections::HashMap, time::Instant};
 use clap::{Parser, ValueEnum, CommandFactory};
-use crate::circuit::Circuit;
+use crate::{circuit::{Circuit, Endian, Gate, Qubit}, decomposition::{self, DecompositionTable, GateKind}, hadamard::AncillaBasis};
+
+/// Exit code when some input files failed to parse, so batch scripts can detect partial failure
+const PARSE_FAILURE_EXIT_CODE: i32 = 3;
 
 #[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Serialize)]
 enum OutputType {
     #[value(help = "Hadamard-reduced circuit in qasm format")]
     CircuitQASM,
+    #[value(name = "circuit-qasm3", help = "Hadamard-reduced circuit in OPENQASM 3.0 format")]
+    CircuitQASM3,
     #[value(help = "Hadamard-reduced circuit in qc format")]
     CircuitQC,
+    #[value(name = "circuit-pyzx", help = "ZX graph of the Hadamard-reduced circuit in PyZX JSON format")]
+    CircuitPyZX,
+    #[value(help = "Gate dependency graph of the Hadamard-reduced circuit in DOT format")]
+    DependencyDot,
     #[value(help = "Block tensors in numpy format")]
     Tensor,
+    #[value(help = "Block tensors in numpy format, with only the entries i >= j >= k as a flat array")]
+    TensorPacked,
+    #[value(name = "tensor-coo", help = "Positions of the set entries of the block tensors as i j k lines")]
+    TensorCOO,
+    #[value(help = "Phase gadgets of each block as (phase, qubits) pairs in JSON format, before Clifford separation")]
+    Gadgets,
     #[value(help = "Block synthesis matrices in numpy format")]
     Matrix,
+    #[value(help = "Block matrices, tensors and mappings bundled into a single numpy .npz archive")]
+    Npz,
     #[value(help = "Block circuits in qasm format")]
     BlockQASM,
+    #[value(help = "Block circuits in qasm format straight after partitioning, before gadgetization")]
+    PartitionQASM,
     #[value(help = "Block circuits in qc format")]
     BlockQC,
+    #[value(help = "Block circuits as quantikz LaTeX figures")]
+    BlockQuantikz,
+    #[value(help = "Links to view the block circuits in the Quirk simulator")]
+    BlockQuirk,
+    #[value(help = "Clifford block circuits in stim format")]
+    BlockStim,
+    #[value(help = "Stabilizer tableaus of the Clifford blocks in numpy format")]
+    Tableau,
+    #[value(help = "Block dependency graph in JSON format")]
+    Schedule,
+    #[value(help = "All blocks with their mappings and matrices in a single JSON document")]
+    DecompositionJson,
+    #[value(help = "Markdown summary of the resource counts and blocks of each circuit")]
+    Report,
+    #[value(help = "Dense unitary of the input circuit as a complex numpy array, for small circuits")]
+    Unitary,
     #[value(help = "Correctness proof of optimized circuit from feynver")]
     Verify,
     #[value(help = "Logfile with statistics about a circuit")]
     Log
 }
 
+#[derive(Debug, Serialize)]
+struct ScheduleNode {
+    block: usize,
+    qubits: Vec<usize>,
+    depends: Vec<usize>
+}
+
+/// The whole decomposition of a circuit. Concatenating the block circuits in
+/// order reproduces the input, with the ancillas prepared and postselected.
+#[derive(Debug, Serialize)]
+struct Decomposition<'a> {
+    qubits: usize,
+    ancilla: &'a [Qubit],
+    blocks: Vec<DecompositionBlock<'a>>
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum BlockKind {
+    Clifford,
+    CnotPhase
+}
+
+/// A single block, numbered as in the per-block output files. Gates are serialized
+/// as e.g. `{"CNOT": [0, 1]}` or `{"Phase": [1, 3]}` with phases in multiples of pi/4.
+#[derive(Debug, Serialize)]
+struct DecompositionBlock<'a> {
+    block: usize,
+    kind: BlockKind,
+    circuit: &'a Circuit,
+    /// For CNOT+Phase blocks, the qubit of each matrix row (before orientation)
+    mapping: Option<&'a [usize]>,
+    /// For CNOT+Phase blocks, the synthesis matrix in the chosen orientation
+    matrix: Option<Vec<Vec<bool>>>
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Serialize)]
+enum ResourceFormat {
+    #[value(help = "Logical counts for the Azure Quantum resource estimator")]
+    Azure
+}
+
 fn valid_directory(s: &str) -> Result<PathBuf, String> {
     match std::fs::metadata(s) {
         Ok(meta) => if meta.is_dir() {
@@ -50,11 +127,53 @@ pub struct Args {
     zx_preopt: bool,
     #[clap(short, long, default_value_t = 10000, help = "Number of iterations to find best Hadamard gadgetization splits")]
     split_iters: usize,
+    #[clap(long, help = "Stop merging blocks for Hadamard gadgetization after this many restarts without improvement, out of at most --split-iters")]
+    merge_patience: Option<usize>,
     #[clap(short, long, help = "Verify correctness of intermediate circuits with feynver")]
     verify: bool,
+    #[clap(long, default_value_t = 0, help = "Only emit matrices and tensors for blocks with at least this many T gadgets")]
+    min_block_tcount: usize,
+    #[clap(long, use_value_delimiter = true, help = "Only emit matrices and tensors for these blocks, numbered as in the output files")]
+    only_blocks: Vec<usize>,
+    #[clap(long, value_enum, default_value = "naive", help = "Algorithm to synthesize the CNOT network of each block's linear part")]
+    cnot_synth: crate::extract::LinearSynthStrategy,
+    #[clap(long, value_enum, default_value = "qubit-rows", help = "Layout of the emitted synthesis matrices")]
+    matrix_orientation: crate::extract::MatrixOrientation,
+    #[clap(long, value_enum, default_value = "zero", help = "Basis to prepare and postselect Hadamard gadget ancillas in")]
+    ancilla_basis: AncillaBasis,
+    #[clap(long, use_value_delimiter = true, help = "Qubits that must never be used as Hadamard gadget ancillas")]
+    reserved_qubits: Vec<usize>,
+    #[clap(long, value_enum, default_value = "little", help = "Qubit ordering of the input and output circuits, matrices and tensors")]
+    endian: Endian,
+    #[clap(long, default_value_t = crate::circuit::DEFAULT_ANGLE_TOLERANCE, help = "Tolerance in radians when recognizing angles as multiples of pi/4")]
+    angle_tolerance: f64,
+    #[clap(long, help = "Merge phases on the same parity within each block before extracting matrices")]
+    fold_phases: bool,
+    #[clap(long, help = "Merge phases on the same affine parity across the whole circuit, through Hadamards, before partitioning")]
+    fold_phases_global: bool,
+    #[clap(long, help = "Write SWAPs as a single swap gate in the qasm and qc outputs, rather than three CNOTs")]
+    native_swap: bool,
+    #[clap(long, help = "JSON file giving custom or native decompositions of CZ, CS, CCZ and SWAP gates")]
+    decompositions: Option<PathBuf>,
+    #[clap(long, help = "Keep leading and trailing Cliffords inside the blocks instead of hoisting them out")]
+    no_hoist_cliffords: bool,
+    #[clap(long, help = "Abort without compiling anything if any file fails to parse")]
+    strict_parse: bool,
+    #[clap(long, help = "Record the time taken by each stage of the pipeline in the log")]
+    profile: bool,
+    #[clap(long, default_value_t = 10, help = "Only emit the unitary of circuits with at most this many qubits")]
+    max_unitary_qubits: usize,
+    #[clap(long, help = "Also write the whole compiled circuit with comments marking each block, in the circuit-qasm and circuit-qc formats")]
+    annotate_blocks: bool,
+    #[clap(long, help = "Embed quantikz diagrams of the blocks in the Markdown report")]
+    report_diagrams: bool,
+    #[clap(long, help = "SQLite database to append statistics about each circuit to")]
+    sqlite: Option<PathBuf>,
+    #[clap(long, value_enum, help = "Write a resource estimate for each circuit in the given format")]
+    resource_format: Option<ResourceFormat>,
     #[clap(help = "Directory to place any output files", value_parser = valid_directory)]
     output: PathBuf,
-    #[clap(required = true, help = "List of .qasm files to compile")]
+    #[clap(required = true, help = "List of .qasm files to compile, optionally gzip-compressed as .qasm.gz")]
     files: Vec<String>
 }
 
@@ -107,23 +226,49 @@ fn put_message(i: usize, count: usize, message: String) {
 #[derive(Debug, Serialize)]
 struct Logfile {
     invocation: Args,
-    files: Vec<FileStats>
+    files: Vec<FileStats>,
+    failures: Vec<FileFailure>
+}
+
+#[derive(Debug, Serialize)]
+struct FileFailure {
+    path: PathBuf,
+    error: String
 }
 
 #[derive(Debug, Serialize, Default)]
 struct FileStats {
     path: PathBuf,
     qubits: usize,
+    ancilla: usize,
     tcount: TCountStats,
     hcount: HCountStats,
-    blocks: Vec<BlockStats>
+    twoqubit: TwoQubitStats,
+    depth: DepthStats,
+    /// The restarts made by the randomized block merging, fewer than `--split-iters` if it
+    /// found a single block or `--merge-patience` stopped it
+    merge_restarts: usize,
+    /// The CCZ and CS gadgets found in the blocks before and after `--fold-phases`
+    fold_gadgets: Option<FoldGadgetStats>,
+    blocks: Vec<BlockStats>,
+    /// Wall-clock seconds spent in each pipeline stage, if profiling
+    timings: HashMap<String, f64>
+}
+
+impl FileStats {
+    /// Add the time elapsed since `start` to the given stage.
+    fn time(&mut self, stage: &str, start: Instant) {
+        *self.timings.entry(stage.to_string()).or_default() += start.elapsed().as_secs_f64();
+    }
 }
 
 #[derive(Debug, Serialize, Default)]
 struct TCountStats {
     initial: usize,
     zx_preopt: Option<usize>,
-    basic_opt: Option<usize>
+    global_fold: Option<usize>,
+    basic_opt: Option<usize>,
+    gadgetized: usize
 }
 
 #[derive(Debug, Serialize, Default)]
@@ -132,10 +277,220 @@ struct HCountStats {
     optimized: usize
 }
 
+#[derive(Debug, Serialize, Default)]
+struct FoldGadgetStats {
+    ccz_before: usize,
+    cs_before: usize,
+    ccz_after: usize,
+    cs_after: usize
+}
+
+#[derive(Debug, Serialize, Default)]
+struct DepthStats {
+    initial: usize,
+    compiled: usize
+}
+
+#[derive(Debug, Serialize, Default)]
+struct TwoQubitStats {
+    initial: TwoQubitCounts,
+    compiled: TwoQubitCounts
+}
+
+#[derive(Debug, Serialize, Default)]
+struct TwoQubitCounts {
+    cnot: usize,
+    cz: usize,
+    swap: usize
+}
+
+impl From<&Circuit> for TwoQubitCounts {
+    fn from(circuit: &Circuit) -> Self {
+        TwoQubitCounts {
+            cnot: circuit.num_cnots(),
+            cz: circuit.count_gates(|g| matches!(g, Gate::CZ(_, _))),
+            swap: circuit.count_gates(|g| matches!(g, Gate::SWAP(_, _)))
+        }
+    }
+}
+
+/// The logical counts input format of the Azure Quantum resource estimator.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureLogicalCounts {
+    num_qubits: usize,
+    t_count: usize,
+    rotation_count: usize,
+    rotation_depth: usize,
+    ccz_count: usize,
+    ccix_count: usize,
+    measurement_count: usize
+}
+
+impl AzureLogicalCounts {
+    /// Count the resources of a compiled circuit on `qubits` logical qubits, which makes
+    /// `measurements` measurements at the end.
+    fn new(circuit: &Circuit, qubits: usize, measurements: usize) -> Self {
+        AzureLogicalCounts {
+            num_qubits: qubits,
+            // Any CS gates left in the Clifford blocks take three T gates each
+            t_count: circuit.tcount() + 3 * circuit.num_cs(),
+            // Phases are multiples of pi/4, so every non-Clifford phase is a T gate up to Cliffords,
+            // and the gate set has no arbitrary rotations or CCiX gates
+            rotation_count: 0,
+            rotation_depth: 0,
+            ccz_count: circuit.num_ccz(),
+            ccix_count: 0,
+            measurement_count: measurements
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Default)]
 struct BlockStats {
     qubits: usize,
-    initial: usize
+    initial: usize,
+    /// The GF(2) rank of the synthesis matrix, the number of independent parities
+    rank: usize,
+    /// Element w is the number of columns of the synthesis matrix with Hamming weight w
+    parity_weight_histogram: Vec<usize>,
+    /// Lower and upper bounds on the Waring rank of the block's signature tensor
+    waring_rank: (usize, usize),
+    /// Whether the matrix and tensor for this block were written
+    emitted: bool
+}
+
+/// Open a database for storing statistics, creating the tables if they don't exist.
+fn open_database<P: AsRef<Path>>(path: P) -> rusqlite::Connection {
+    let db = rusqlite::Connection::open(path)
+        .expect("Couldn't open database");
+    db.execute_batch("
+        CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY,
+            run INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            qubits INTEGER NOT NULL,
+            ancilla INTEGER NOT NULL,
+            tcount_initial INTEGER NOT NULL,
+            tcount_zx_preopt INTEGER,
+            tcount_basic_opt INTEGER,
+            tcount_gadgetized INTEGER NOT NULL,
+            hcount_initial INTEGER NOT NULL,
+            hcount_optimized INTEGER NOT NULL,
+            cnot_initial INTEGER NOT NULL,
+            cz_initial INTEGER NOT NULL,
+            swap_initial INTEGER NOT NULL,
+            cnot_compiled INTEGER NOT NULL,
+            cz_compiled INTEGER NOT NULL,
+            swap_compiled INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS blocks (
+            file INTEGER NOT NULL REFERENCES files(id),
+            block INTEGER NOT NULL,
+            qubits INTEGER NOT NULL,
+            initial INTEGER NOT NULL,
+            rank INTEGER NOT NULL,
+            parity_weight_histogram TEXT NOT NULL,
+            emitted INTEGER NOT NULL
+        );
+    ").expect("Couldn't create database tables");
+    db
+}
+
+impl FileStats {
+    /// Insert these statistics into a database created by `open_database`.
+    fn insert(&self, db: &rusqlite::Connection, run: u128) -> rusqlite::Result<()> {
+        db.execute(
+            "INSERT INTO files (
+                run, path, qubits, ancilla,
+                tcount_initial, tcount_zx_preopt, tcount_basic_opt, tcount_gadgetized,
+                hcount_initial, hcount_optimized,
+                cnot_initial, cz_initial, swap_initial,
+                cnot_compiled, cz_compiled, swap_compiled
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            rusqlite::params![
+                run as i64, self.path.to_string_lossy(), self.qubits, self.ancilla,
+                self.tcount.initial, self.tcount.zx_preopt, self.tcount.basic_opt, self.tcount.gadgetized,
+                self.hcount.initial, self.hcount.optimized,
+                self.twoqubit.initial.cnot, self.twoqubit.initial.cz, self.twoqubit.initial.swap,
+                self.twoqubit.compiled.cnot, self.twoqubit.compiled.cz, self.twoqubit.compiled.swap
+            ]
+        )?;
+
+        let file = db.last_insert_rowid();
+        for (j, block) in self.blocks.iter().enumerate() {
+            db.execute(
+                "INSERT INTO blocks (file, block, qubits, initial, rank, parity_weight_histogram, emitted)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![
+                    file, 2*j + 1, block.qubits, block.initial, block.rank,
+                    serde_json::to_string(&block.parity_weight_histogram).unwrap(), block.emitted
+                ]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FileStats {
+    /// Summarize these statistics as a Markdown document, followed by the given
+    /// (title, quantikz) diagrams in LaTeX code blocks.
+    fn to_markdown(&self, diagrams: &[(String, String)]) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(&mut out, "# Compilation report for `{}`\n", name).unwrap();
+
+        writeln!(&mut out, "## Resources\n").unwrap();
+        writeln!(&mut out, "| | Count |\n|---|---|").unwrap();
+        let mut row = |label: &str, value: Option<usize>| if let Some(value) = value {
+            writeln!(&mut out, "| {} | {} |", label, value).unwrap();
+        };
+        row("Qubits", Some(self.qubits));
+        row("Ancillas", Some(self.ancilla));
+        row("Initial T-count", Some(self.tcount.initial));
+        row("T-count after ZX pre-optimization", self.tcount.zx_preopt);
+        row("T-count after global phase folding", self.tcount.global_fold);
+        row("T-count after phase folding", self.tcount.basic_opt);
+        row("Final T-count", Some(self.tcount.gadgetized));
+        row("Initial H-count", Some(self.hcount.initial));
+        row("Optimized H-count", Some(self.hcount.optimized));
+        row("Initial depth", Some(self.depth.initial));
+        row("Compiled depth", Some(self.depth.compiled));
+        row("Initial CNOTs", Some(self.twoqubit.initial.cnot));
+        row("Compiled CNOTs", Some(self.twoqubit.compiled.cnot));
+        row("Non-Clifford blocks", Some(self.blocks.len()));
+
+        if !self.blocks.is_empty() {
+            writeln!(&mut out, "\n## Blocks\n").unwrap();
+            writeln!(&mut out, "| Block | Qubits | T-count | Rank | Waring rank | Emitted |\n|---|---|---|---|---|---|").unwrap();
+            for (j, block) in self.blocks.iter().enumerate() {
+                writeln!(&mut out, "| {} | {} | {} | {} | {}–{} | {} |",
+                    2*j + 1, block.qubits, block.initial, block.rank, block.waring_rank.0, block.waring_rank.1, if block.emitted { "yes" } else { "no" }
+                ).unwrap();
+            }
+        }
+
+        if !diagrams.is_empty() {
+            writeln!(&mut out, "\n## Diagrams").unwrap();
+            for (title, diagram) in diagrams {
+                writeln!(&mut out, "\n### {}\n\n```latex\n{}```", title, diagram).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Convert the qubits of a matrix mapping with `Endian::convert`, reordering the
+/// rows of the matrix so that the mapping stays sorted.
+fn convert_endian_rows(endian: Endian, n: usize, mapping: &[usize], matrix: &nd::Array2<bool>) -> (Vec<usize>, nd::Array2<bool>) {
+    let converted = mapping.iter()
+        .map(|&q| endian.convert(Qubit(q), n).0)
+        .collect::<Vec<_>>();
+    let mut rows = (0..mapping.len()).collect::<Vec<_>>();
+    rows.sort_by_key(|&r| converted[r]);
+    (rows.iter().map(|&r| converted[r]).collect(), matrix.select(nd::Axis(0), &rows))
 }
 
 pub fn main(args: Args) {
@@ -149,33 +504,73 @@ pub fn main(args: Args) {
             .exit()
     }
 
+    let mut table = match &args.decompositions {
+        Some(path) => match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|source| DecompositionTable::from_json(&source)) {
+            Ok(table) => table,
+            Err(e) => Args::command()
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("Couldn't load decomposition table `{}`: {}", path.display(), e)
+                )
+                .exit()
+        },
+        None => DecompositionTable::default()
+    };
+    if args.native_swap {
+        table.set(GateKind::SWAP, decomposition::Decomposition::Native)
+            .expect("A native SWAP can't make the table cyclic");
+    }
+
     let mut cache = oq::SourceCache::new();
     let mut circuits = Vec::new();
     let mut errors = oq::Errors { errors: Vec::new() };
+    let mut parse_failures = 0;
 
     for (i, file) in files.iter().enumerate() {
         with_message(i, files.len(), |pb| {
             pb.set_message(format!("  Parsing: {}", file.display()));
 
-            match Circuit::from_openqasm(&mut cache, &file, true) {
-                Ok(circuit) => {
-                    circuits.push((file.clone(), circuit));
-                    pb.set_message("  Parsing successful");
+            let start = Instant::now();
+            match Circuit::from_openqasm_multi(&mut cache, &file, true, args.angle_tolerance) {
+                Ok(parsed) => {
+                    let elapsed = start.elapsed().as_secs_f64() / parsed.len().max(1) as f64;
+                    // Name the outputs of compressed files as if they were uncompressed
+                    let file = crate::circuit::strip_gzip(file);
+                    if parsed.len() == 1 {
+                        circuits.extend(parsed.into_iter().map(|circuit| (file.clone(), circuit, elapsed)));
+                        pb.set_message("  Parsing successful");
+                    } else {
+                        // Name each circuit in a bundle after its position, so outputs don't collide
+                        let stem = file.file_stem().unwrap().to_string_lossy().to_string();
+                        pb.set_message(format!("  Parsing successful: found {} circuits", parsed.len()));
+                        circuits.extend(parsed.into_iter().enumerate().map(|(k, circuit)| {
+                            (file.with_file_name(format!("{}.{}.qasm", stem, k)), circuit, elapsed)
+                        }));
+                    }
                 },
                 Err(mut err) => {
                     pb.set_message(format!("  Parsing unsuccessful: found {} errors", err.errors.len()));
                     errors.errors.append(&mut err.errors);
+                    parse_failures += 1;
                 }
             }
         });
     }
 
     if !errors.errors.is_empty() {
-        errors.eprint(&mut cache).unwrap();
+        // Failing to print the errors shouldn't stop the files that did parse from compiling
+        if let Err(e) = errors.eprint(&mut cache) {
+            eprintln!("Couldn't print parse errors: {:?}", e);
+        }
+        if args.strict_parse {
+            eprintln!("{} of {} files failed to parse, aborting", parse_failures, files.len());
+            std::process::exit(PARSE_FAILURE_EXIT_CODE);
+        }
     }
 
-    let verify_circuits = |i, count, path: &Path, suffix, original: &str, new: &str| {
+    let verify_circuits = |i, count, path: &Path, suffix: &str, original: &str, new: &str| {
         let mut proof = Vec::new();
+        let mut failed = false;
         with_message(i, count, |pb| {
             pb.set_message("    Verifying...");
             let dir = tempfile::tempdir()
@@ -212,6 +607,8 @@ pub fn main(args: Args) {
                 pb.set_message("    Verifying done");
             } else {
                 pb.set_message(format!("    Verification failed: {}", String::from_utf8_lossy(&proof)));
+                proof.extend(output.stderr);
+                failed = true;
             }
         });
 
@@ -219,191 +616,611 @@ pub fn main(args: Args) {
             let output = args.write_output(&path, suffix, &String::from_utf8_lossy(&proof));
             put_message(i, count, format!("      Wrote verification proof to: {}", output.display()));
         }
+
+        // Keep everything needed to reproduce a failure, since the compared circuits only lived in the tempdir
+        if failed {
+            let stage = suffix.strip_suffix(".verify.txt").unwrap_or(suffix);
+            args.write_output(&path, &format!("{}.FAILED.original.qc", stage), original);
+            args.write_output(&path, &format!("{}.FAILED.new.qc", stage), new);
+            let output = args.write_output(&path, &format!("{}.FAILED.verify.txt", stage), &String::from_utf8_lossy(&proof));
+            put_message(i, count, format!("      Wrote failed verification to: {}", output.display()));
+        }
     };
 
-    let mut logfile = Logfile { invocation: args.clone(), files: Vec::new() };
+    // feynver postselects ancillas in |0>, so ancillas in |+> must be wrapped in Hadamards
+    let for_feynver = |partitioned: &crate::decompose::PartitionedCircuit| {
+        let mut circuit = partitioned.merge();
+        if args.ancilla_basis == AncillaBasis::Plus {
+            let mut wrapped = Circuit::new(partitioned.ancilla.iter().map(|&q| Gate::H(q)).collect());
+            circuit.gates.extend(partitioned.ancilla.iter().map(|&q| Gate::H(q)));
+            wrapped.merge(circuit);
+            circuit = wrapped;
+        }
+        circuit
+    };
+
+    let mut logfile = Logfile { invocation: args.clone(), files: Vec::new(), failures: Vec::new() };
+    let database = args.sqlite.as_ref().map(open_database);
+    let run = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
 
     let count = circuits.len();
-    for (i, (path, mut circuit)) in circuits.into_iter().enumerate() {
-        put_message(i, count, format!("Processing: {}", path.display()));
-        let qubits = circuit.qubits();
+    for (i, (path, mut circuit, parse_time)) in circuits.into_iter().enumerate() {
+        // Catch panics so that one bad file doesn't abort the whole batch
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            put_message(i, count, format!("Processing: {}", path.display()));
+            let qubits = circuit.qubits();
+            // Everything internal is little-endian, and converted back just before being written
+            circuit.convert_endian(args.endian, qubits);
+            let external = |circuit: &Circuit| {
+                let mut circuit = circuit.clone();
+                circuit.convert_endian(args.endian, qubits);
+                circuit
+            };
 
-        if let Some(q) = args.qubits {
-            if q < qubits {
-                put_message(i, count, format!("  Too many qubits ({} but budget is {}), skipping!", qubits, q));
-                continue
+            if let Some(q) = args.qubits {
+                if q < qubits {
+                    put_message(i, count, format!("  Too many qubits ({} but budget is {}), skipping!", qubits, q));
+                    return None
+                }
+            }
+
+            let mut filestats = FileStats::default();
+            // Circuits from a bundle file are named after it, so only the directory exists
+            filestats.path = path.parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .canonicalize()
+                .expect("Couldn't canonicalize path")
+                .join(path.file_name().unwrap());
+            filestats.qubits = qubits;
+            filestats.tcount.initial = circuit.tcount() + 7 * circuit.num_ccz() + 3 * circuit.num_cs();
+            filestats.hcount.initial = circuit.hcount_accurate();
+            filestats.twoqubit.initial = TwoQubitCounts::from(&circuit);
+            filestats.depth.initial = circuit.depth();
+            if args.profile {
+                filestats.timings.insert("parse".into(), parse_time);
+            }
+            // Only record timings when profiling
+            let time = |filestats: &mut FileStats, stage: &str, start: Instant| if args.profile {
+                filestats.time(stage, start);
+            };
+
+            let original_qc = circuit.to_qc(qubits);
+
+            if args.emit.contains(&OutputType::Unitary) {
+                match (qubits <= args.max_unitary_qubits).then(|| external(&circuit).to_unitary()).flatten() {
+                    Some(unitary) => {
+                        let output = args.output_path(&path, ".unitary.npy");
+                        ndarray_npy::write_npy(&output, &unitary)
+                            .expect("Couldn't write output file!");
+                        put_message(i, count, format!("    Wrote circuit unitary to: {}", output.display()));
+                    },
+                    None => put_message(i, count, format!(
+                        "  Too many qubits to write the unitary ({} but the limit is {}), skipping it",
+                        qubits, args.max_unitary_qubits.min(crate::simulate::MAX_UNITARY_QUBITS)
+                    ))
+                }
+            }
+
+            if args.zx_preopt {
+                let start = Instant::now();
+                let mut failed = false;
+                with_message(i, count, |pb| {
+                    pb.set_message("  Pre-optimizing with ZX..");
+                    let zxcirc = circuit.to_zx_with(&table);
+                    let mut graph = zxcirc.to_graph::<Graph>();
+                    let before = graph.tcount();
+                    quizx::simplify::full_simp(&mut graph);
+                    let zxcirc = if let Ok(circ) = graph.to_circuit() {
+                        let after = circ.to_graph::<Graph>().tcount();
+                        pb.set_message(format!("  Pre-optimization with ZX done: initial tcount = {}, final tcount = {}", before, after));
+                        circ
+                    } else {
+                        // full_simp can leave a graph we can't extract, so retry with only Clifford
+                        // simplifications, which are more likely to keep it extractable
+                        let lost = before - graph.tcount();
+                        let mut graph = zxcirc.to_graph::<Graph>();
+                        quizx::simplify::clifford_simp(&mut graph);
+                        if let Ok(circ) = graph.to_circuit() {
+                            let after = circ.to_graph::<Graph>().tcount();
+                            pb.set_message(format!("  Pre-optimization with ZX fell back to Clifford simplification: initial tcount = {}, final tcount = {} ({} more possible with full simplification)", before, after, lost));
+                            circ
+                        } else {
+                            pb.set_message(format!("  Pre-optimization with ZX failed: skipping! (full simplification would have removed {} T gates)", lost));
+                            failed = true;
+                            return
+                        }
+                    };
+                    // The deferred measurements, corrections and comments aren't part of the ZX diagram
+                    let mut optimized = Circuit::from_zx(zxcirc);
+                    optimized.measurements = std::mem::take(&mut circuit.measurements);
+                    optimized.corrections = std::mem::take(&mut circuit.corrections);
+                    optimized.comments = std::mem::take(&mut circuit.comments);
+                    optimized.hoist_comments();
+                    circuit = optimized;
+                });
+
+                filestats.tcount.zx_preopt = Some(circuit.tcount());
+                time(&mut filestats, "zx-preopt", start);
+
+                if failed {
+                    return None
+                }
+
+                if args.verify {
+                    verify_circuits(i, count, &path, ".zx.verify.txt", &original_qc, &circuit.to_qc(qubits));
+                }
+            }
+
+            if args.fold_phases_global {
+                with_message(i, count, |pb| {
+                    pb.set_message("  Folding phases across the circuit...");
+                    let start = Instant::now();
+                    let folded = circuit.fold_phases_global();
+                    time(&mut filestats, "fold-phases-global", start);
+                    filestats.tcount.global_fold = Some(circuit.tcount());
+                    pb.set_message(format!("  Global phase folding done: tcount reduced by {}", folded));
+                });
+
+                if args.verify {
+                    verify_circuits(i, count, &path, ".fold.verify.txt", &original_qc, &circuit.to_qc(qubits));
+                }
             }
-        }
 
-        let mut filestats = FileStats::default();
-        filestats.path = path.canonicalize()
-            .expect("Couldn't canonicalize path");
-        filestats.qubits = qubits;
-        filestats.tcount.initial = circuit.tcount() + 7 * circuit.gates.iter()
-            .filter(|g| matches!(g, crate::circuit::Gate::CCZ(_, _, _)))
-            .count() + 3 * circuit.gates.iter()
-            .filter(|g| matches!(g, crate::circuit::Gate::CS(_, _)))
-            .count();
-        filestats.hcount.initial = circuit.hcount_accurate();
-
-        let original_qc = circuit.to_qc(qubits);
-
-        if args.zx_preopt {
-            let mut failed = false;
             with_message(i, count, |pb| {
-                pb.set_message("  Pre-optimizing with ZX..");
-                let zxcirc = circuit.to_zx();
-                let mut graph = zxcirc.to_graph::<Graph>();
-                let before = graph.tcount();
-                quizx::simplify::full_simp(&mut graph);
-                let zxcirc = if let Ok(circ) = graph.to_circuit() {
-                    let after = circ.to_graph::<Graph>().tcount();
-                    pb.set_message(format!("  Pre-optimization with ZX done: initial tcount = {}, final tcount = {}", before, after));
-                    circ
-                } else {
-                    pb.set_message("  Pre-optimization with ZX failed: skipping!");
-                    failed = true;
-                    return
-                };
-                circuit = Circuit::from_zx(zxcirc);
+                pb.set_message("  Optimizing internal Hadamards...");
+                let timer = Instant::now();
+                let start = circuit.hcount_accurate();
+                circuit.move_h_optimal();
+                let end = circuit.hcount_accurate();
+                filestats.hcount.optimized = end;
+                time(&mut filestats, "hadamard-opt", timer);
+                pb.set_message(format!("  Hadamard optimization done: initial hcount = {}, final hcount = {}", start, end));
             });
 
-            filestats.tcount.zx_preopt = Some(circuit.tcount());
+            if args.emit.contains(&OutputType::CircuitQASM) {
+                let output = args.write_output(&path, ".hopt.qasm", &external(&circuit).to_openqasm_with_table(false, &table));
+                put_message(i, count, format!("    Wrote optimized circuit to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::CircuitQASM3) {
+                let output = args.write_output(&path, ".hopt.qasm3", &external(&circuit).to_openqasm3());
+                put_message(i, count, format!("    Wrote optimized circuit to: {}", output.display()));
+            }
 
-            if failed {
-                continue
+            if args.emit.contains(&OutputType::CircuitQC) {
+                let output = args.write_output(&path, ".hopt.qc", &external(&circuit).to_qc_with_table(qubits, &table));
+                put_message(i, count, format!("    Wrote optimized circuit to: {}", output.display()));
             }
 
-            if args.verify {
-                verify_circuits(i, count, &path, ".zx.verify.txt", &original_qc, &circuit.to_qc(qubits));
+            if args.emit.contains(&OutputType::CircuitPyZX) {
+                let output = args.write_output(&path, ".hopt.zx.json", &external(&circuit).to_pyzx_json());
+                put_message(i, count, format!("    Wrote optimized ZX graph to: {}", output.display()));
             }
-        }
 
-        with_message(i, count, |pb| {
-            pb.set_message("  Optimizing internal Hadamards...");
-            let start = circuit.hcount_accurate();
-            circuit.move_h_optimal();
-            let end = circuit.hcount_accurate();
-            filestats.hcount.optimized = end;
-            pb.set_message(format!("  Hadamard optimization done: initial hcount = {}, final hcount = {}", start, end));
-        });
+            if args.emit.contains(&OutputType::DependencyDot) {
+                let output = args.write_output(&path, ".hopt.dot", &external(&circuit).to_dependency_dot());
+                put_message(i, count, format!("    Wrote dependency graph to: {}", output.display()));
+            }
 
-        if args.emit.contains(&OutputType::CircuitQASM) {
-            let output = args.write_output(&path, ".hopt.qasm", &circuit.to_openqasm(false));
-            put_message(i, count, format!("    Wrote optimized circuit to: {}", output.display()));
-        }
+            if args.verify {
+                verify_circuits(i, count, &path, ".hopt.verify.txt", &original_qc, &circuit.to_qc(qubits));
+            }
 
-        if args.emit.contains(&OutputType::CircuitQC) {
-            let output = args.write_output(&path, ".hopt.qc", &circuit.to_qc(qubits));
-            put_message(i, count, format!("    Wrote optimized circuit to: {}", output.display()));
-        }
+            let start = Instant::now();
+            let measurements = circuit.measurements.len();
+            let mut partitioned = circuit.partition_with(!args.no_hoist_cliffords);
+            time(&mut filestats, "partition", start);
+            let budget = args.ancilla
+                .unwrap_or(usize::MAX)
+                .min(args.qubits
+                    .map(|q| q - qubits)
+                    .unwrap_or(usize::MAX));
 
-        if args.verify {
-            verify_circuits(i, count, &path, ".hopt.verify.txt", &original_qc, &circuit.to_qc(qubits));
-        }
+            let mut raw_partition = None;
+            with_message(i, count, |pb| {
+                pb.set_message("  Gadgetizing Hadamards...");
+                let before = (partitioned.blocks.len() + 1) / 2;
+                let start = Instant::now();
+                let (_, restarts) = partitioned.pick_gadgets_with(budget, args.split_iters, args.merge_patience, &mut rand::thread_rng());
+                filestats.merge_restarts = restarts;
+                let removed = partitioned.remove_redundant_ancillas();
+                time(&mut filestats, "pick-gadgets", start);
+                if args.emit.contains(&OutputType::PartitionQASM) {
+                    raw_partition = Some(partitioned.clone());
+                }
+                let start = Instant::now();
+                partitioned.to_cnot_phase_with(args.ancilla_basis, &args.reserved_qubits.iter().map(|&q| args.endian.convert(Qubit(q), qubits).0).collect(), &table);
+                if let Err(err) = partitioned.validate_ancillas() {
+                    panic!("Invalid ancilla allocation in {}: {}", path.display(), err);
+                }
+                if budget == usize::MAX && !args.no_hoist_cliffords {
+                    // With no budget every internal Hadamard is gadgetized. Without hoisting, the
+                    // leading and trailing Hadamards are gadgetized too, so the counts differ
+                    debug_assert_eq!(partitioned.ancilla.len() + removed, filestats.hcount.optimized);
+                }
+                let merged = partitioned.merge_gadgets_across_blocks();
+                time(&mut filestats, "to-cnot-phase", start);
+                let after = (partitioned.blocks.len() + 1) / 2;
+                pb.set_message(format!("  Gadgetizing done: {} blocks => {} blocks after {} restarts, {} redundant ancilla avoided, tcount reduced by {} across blocks", before, after, restarts, removed, merged));
+            });
 
-        let mut partitioned = circuit.partition();
-        let budget = args.ancilla
-            .unwrap_or(usize::MAX)
-            .min(args.qubits
-                .map(|q| q - qubits)
-                .unwrap_or(usize::MAX));
+            if let Some(raw) = raw_partition {
+                let blocks = std::iter::once(&raw.front)
+                    .chain(&raw.blocks)
+                    .chain(std::iter::once(&raw.back));
+                for (j, block) in blocks.enumerate() {
+                    let output = args.write_output(&path, &format!(".partition.block{}.qasm", j), &external(block).to_openqasm_with_table(false, &table));
+                    put_message(i, count, format!("    Wrote partition block to: {}", output.display()));
+                }
+            }
 
-        with_message(i, count, |pb| {
-            pb.set_message("  Gadgetizing Hadamards...");
-            let before = (partitioned.blocks.len() + 1) / 2;
-            partitioned.pick_gadgets(budget, args.split_iters);
-            partitioned.to_cnot_phase();
-            let after = (partitioned.blocks.len() + 1) / 2;
-            pb.set_message(format!("  Gadgetizing done: {} blocks => {} blocks", before, after));
-        });
+            if args.fold_phases {
+                with_message(i, count, |pb| {
+                    pb.set_message("  Folding phases...");
+                    let (ccz_before, cs_before) = partitioned.count_gadgets();
+                    let start = Instant::now();
+                    let folded = partitioned.fold_phases();
+                    time(&mut filestats, "fold-phases", start);
+                    let (ccz_after, cs_after) = partitioned.count_gadgets();
+                    filestats.tcount.basic_opt = Some(partitioned.merge().tcount());
+                    filestats.fold_gadgets = Some(FoldGadgetStats { ccz_before, cs_before, ccz_after, cs_after });
+                    pb.set_message(format!(
+                        "  Folding phases done: tcount reduced by {}, CCZ gadgets {} => {}, CS gadgets {} => {}",
+                        folded, ccz_before, ccz_after, cs_before, cs_after
+                    ));
+                });
+            }
         
-        if args.verify {
-            verify_circuits(i, count, &path, ".partition.verify.txt", &original_qc, &partitioned.merge().to_qc(qubits));
-        }
+            if args.verify {
+                verify_circuits(i, count, &path, ".partition.verify.txt", &original_qc, &for_feynver(&partitioned).to_qc(qubits));
+            }
 
-        let matrices = partitioned.extract_gadgets();
+            filestats.ancilla = partitioned.ancilla.len();
+            filestats.tcount.gadgetized = std::iter::once(&partitioned.front)
+                .chain(&partitioned.blocks)
+                .chain(std::iter::once(&partitioned.back))
+                .map(Circuit::tcount)
+                .sum();
+            // Each Hadamard gadget ancilla is measured out at the end, along with the deferred measurements
+            let resources = args.resource_format.map(|format| match format {
+                ResourceFormat::Azure => AzureLogicalCounts::new(&partitioned.merge(), qubits + filestats.ancilla, filestats.ancilla + measurements)
+            });
 
-        if args.verify {
-            verify_circuits(i, count, &path, ".resynth.verify.txt", &original_qc, &partitioned.merge().to_qc(qubits));
-        }
+            // Extraction rewrites the blocks, so the phase polynomials have to be taken first
+            let gadgets = args.emit.contains(&OutputType::Gadgets)
+                .then(|| partitioned.phase_gadgets())
+                .map(|gadgets| gadgets.into_iter()
+                    .map(|block| block.into_iter()
+                        .map(|(phase, parity)| {
+                            let mut parity = parity.into_iter().map(|q| args.endian.convert(q, qubits)).collect::<Vec<_>>();
+                            parity.sort();
+                            (phase, parity)
+                        })
+                        .collect::<Vec<_>>())
+                    .collect::<Vec<_>>());
 
-        if args.emit.contains(&OutputType::BlockQASM) {
-            let output = args.write_output(&path, ".block0.cliffords.qasm", &partitioned.front.to_openqasm(false));
-            put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
-        }
-        if args.emit.contains(&OutputType::BlockQC) {
-            let output = args.write_output(&path, ".block0.cliffords.qc", &partitioned.front.to_qc(qubits));
-            put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
-        }
+            let start = Instant::now();
+            let matrices = partitioned.extract_gadgets_with(args.cnot_synth);
+            time(&mut filestats, "extract-gadgets", start);
+            let compiled = partitioned.merge();
+            filestats.twoqubit.compiled = TwoQubitCounts::from(&compiled);
+            filestats.depth.compiled = compiled.depth();
 
-        for (j, block) in partitioned.blocks.iter().enumerate() {
-            let suffix = if j % 2 == 0 {
-                format!(".block{}.cnotphase", j + 1)
-            } else {
-                format!(".block{}.cliffords", j + 1)
-            };
+            if args.verify {
+                verify_circuits(i, count, &path, ".resynth.verify.txt", &original_qc, &for_feynver(&partitioned).to_qc(qubits));
+            }
+
+            // From here on only outputs are produced, so convert to the external qubit order
+            partitioned.convert_endian(args.endian, qubits);
+            let matrices = matrices.into_iter()
+                .map(|(mapping, matrix)| convert_endian_rows(args.endian, qubits, &mapping, &matrix))
+                .collect::<Vec<_>>();
+
+            let emit_start = Instant::now();
+            if args.annotate_blocks {
+                let annotated = partitioned.merge_annotated();
+                if args.emit.contains(&OutputType::CircuitQASM) {
+                    let output = args.write_output(&path, ".blocks.qasm", &annotated.to_openqasm_with_table(false, &table));
+                    put_message(i, count, format!("    Wrote annotated circuit to: {}", output.display()));
+                }
+                if args.emit.contains(&OutputType::CircuitQC) {
+                    let output = args.write_output(&path, ".blocks.qc", &annotated.to_qc_with_table(qubits, &table));
+                    put_message(i, count, format!("    Wrote annotated circuit to: {}", output.display()));
+                }
+            }
 
             if args.emit.contains(&OutputType::BlockQASM) {
-                let output = args.write_output(&path, &format!("{}.qasm", suffix) , &block.to_openqasm(false));
+                let output = args.write_output(&path, ".block0.cliffords.qasm", &partitioned.front.to_openqasm_with_table(false, &table));
                 put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
             }
             if args.emit.contains(&OutputType::BlockQC) {
-                let output = args.write_output(&path, &format!("{}.qc", suffix), &block.to_qc(qubits));
+                let output = args.write_output(&path, ".block0.cliffords.qc", &partitioned.front.to_qc_with_table(qubits, &table));
                 put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
             }
-        }
 
-        let suffix = format!(".block{}.cliffords", 1 + partitioned.blocks.len());
-        if args.emit.contains(&OutputType::BlockQASM) {
-            let output = args.write_output(&path, &format!("{}.qasm", suffix) , &partitioned.back.to_openqasm(false));
-            put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
-        }
-        if args.emit.contains(&OutputType::BlockQC) {
-            let output = args.write_output(&path, &format!("{}.qc", suffix), &partitioned.back.to_qc(qubits));
-            put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
-        }
+            if args.emit.contains(&OutputType::BlockQuantikz) {
+                let output = args.write_output(&path, ".block0.cliffords.tex", &partitioned.front.to_quantikz(qubits));
+                put_message(i, count, format!("    Wrote block figure to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::BlockQuirk) {
+                let output = args.write_output(&path, ".block0.cliffords.quirk.txt", &format!("{}\n", partitioned.front.to_quirk_url()));
+                put_message(i, count, format!("    Wrote block Quirk link to: {}", output.display()));
+            }
+
+            let write_stim = |suffix: &str, block: &Circuit| match block.to_stim() {
+                Ok(stim) => {
+                    let output = args.write_output(&path, &format!("{}.stim", suffix), &stim);
+                    put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
+                },
+                Err(e) => put_message(i, count, format!("    Couldn't write block circuit in stim format: {}", e))
+            };
+            if args.emit.contains(&OutputType::BlockStim) {
+                write_stim(".block0.cliffords", &partitioned.front);
+            }
 
-        for (j, (mapping, matrix)) in matrices.iter().enumerate() {
-            if args.emit.contains(&OutputType::Matrix) {
-                let output = args.write_output(&path, &format!(".block{}.mapping.txt", 2*j + 1), &format!("{:?}", mapping));
-                put_message(i, count, format!("    Wrote block mapping to: {}", output.display()));
+            // All tableaus act on every qubit, so that they are directly comparable
+            let width = partitioned.merge().qubits();
+            let write_tableau = |suffix: &str, block: &Circuit| match block.to_tableau(width) {
+                Ok(tableau) => {
+                    let output = args.output_path(&path, &format!("{}.tableau.npy", suffix));
+                    ndarray_npy::write_npy(&output, &tableau.table)
+                        .expect("Couldn't write output file!");
+                    put_message(i, count, format!("    Wrote block tableau to: {}", output.display()));
+                },
+                Err(e) => put_message(i, count, format!("    Couldn't compute block tableau: {}", e))
+            };
+            if args.emit.contains(&OutputType::Tableau) {
+                write_tableau(".block0.cliffords", &partitioned.front);
             }
 
-            let mut blockstats = BlockStats::default();
-            blockstats.qubits = matrix.shape()[0];
-            blockstats.initial = matrix.shape()[1];
-            filestats.blocks.push(blockstats);
+            for (j, block) in partitioned.blocks.iter().enumerate() {
+                let suffix = if j % 2 == 0 {
+                    format!(".block{}.cnotphase", j + 1)
+                } else {
+                    format!(".block{}.cliffords", j + 1)
+                };
 
+                if args.emit.contains(&OutputType::BlockQASM) {
+                    let output = args.write_output(&path, &format!("{}.qasm", suffix) , &block.to_openqasm_with_table(false, &table));
+                    put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
+                }
+                if args.emit.contains(&OutputType::BlockQC) {
+                    let output = args.write_output(&path, &format!("{}.qc", suffix), &block.to_qc_with_table(qubits, &table));
+                    put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
+                }
+                if args.emit.contains(&OutputType::BlockQuantikz) {
+                    let output = args.write_output(&path, &format!("{}.tex", suffix), &block.to_quantikz(qubits));
+                    put_message(i, count, format!("    Wrote block figure to: {}", output.display()));
+                }
+                if args.emit.contains(&OutputType::BlockQuirk) {
+                    let output = args.write_output(&path, &format!("{}.quirk.txt", suffix), &format!("{}\n", block.to_quirk_url()));
+                    put_message(i, count, format!("    Wrote block Quirk link to: {}", output.display()));
+                }
+                if j % 2 == 1 && args.emit.contains(&OutputType::BlockStim) {
+                    write_stim(&suffix, block);
+                }
+                if j % 2 == 1 && args.emit.contains(&OutputType::Tableau) {
+                    write_tableau(&suffix, block);
+                }
+            }
 
-            let suffix = format!(".block{}.matrix", 2*j + 1);
-            if args.emit.contains(&OutputType::Matrix) {
-                let output = args.output_path(&path, &format!("{}.npy", suffix));
-                ndarray_npy::write_npy(&output, matrix)
+            let suffix = format!(".block{}.cliffords", 1 + partitioned.blocks.len());
+            if args.emit.contains(&OutputType::BlockQASM) {
+                let output = args.write_output(&path, &format!("{}.qasm", suffix) , &partitioned.back.to_openqasm_with_table(false, &table));
+                put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::BlockQC) {
+                let output = args.write_output(&path, &format!("{}.qc", suffix), &partitioned.back.to_qc_with_table(qubits, &table));
+                put_message(i, count, format!("    Wrote block circuit to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::BlockQuantikz) {
+                let output = args.write_output(&path, &format!("{}.tex", suffix), &partitioned.back.to_quantikz(qubits));
+                put_message(i, count, format!("    Wrote block figure to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::BlockQuirk) {
+                let output = args.write_output(&path, &format!("{}.quirk.txt", suffix), &format!("{}\n", partitioned.back.to_quirk_url()));
+                put_message(i, count, format!("    Wrote block Quirk link to: {}", output.display()));
+            }
+            if args.emit.contains(&OutputType::BlockStim) {
+                write_stim(&suffix, &partitioned.back);
+            }
+            if args.emit.contains(&OutputType::Tableau) {
+                write_tableau(&suffix, &partitioned.back);
+            }
+
+            if args.emit.contains(&OutputType::Schedule) {
+                let schedule = partitioned.block_dependencies()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(block, (qubits, depends))| ScheduleNode {
+                        block,
+                        qubits: qubits.into_iter().map(|q| q.0).collect(),
+                        depends
+                    })
+                    .collect::<Vec<_>>();
+                let schedule = serde_json::to_string_pretty(&schedule)
+                    .expect("Couldn't serialize block schedule");
+                let output = args.write_output(&path, ".schedule.json", &schedule);
+                put_message(i, count, format!("    Wrote block schedule to: {}", output.display()));
+            }
+
+            if args.emit.contains(&OutputType::DecompositionJson) {
+                let mut blocks = vec![DecompositionBlock {
+                    block: 0, kind: BlockKind::Clifford, circuit: &partitioned.front, mapping: None, matrix: None
+                }];
+                for (j, block) in partitioned.blocks.iter().enumerate() {
+                    blocks.push(if j % 2 == 0 {
+                        let (mapping, matrix) = &matrices[j / 2];
+                        let matrix = args.matrix_orientation.orient(matrix.clone())
+                            .rows()
+                            .into_iter()
+                            .map(|row| row.to_vec())
+                            .collect();
+                        DecompositionBlock {
+                            block: j + 1, kind: BlockKind::CnotPhase, circuit: block, mapping: Some(mapping), matrix: Some(matrix)
+                        }
+                    } else {
+                        DecompositionBlock { block: j + 1, kind: BlockKind::Clifford, circuit: block, mapping: None, matrix: None }
+                    });
+                }
+                blocks.push(DecompositionBlock {
+                    block: partitioned.blocks.len() + 1, kind: BlockKind::Clifford, circuit: &partitioned.back, mapping: None, matrix: None
+                });
+
+                let decomposition = Decomposition { qubits, ancilla: &partitioned.ancilla, blocks };
+                let decomposition = serde_json::to_string_pretty(&decomposition)
+                    .expect("Couldn't serialize decomposition");
+                let output = args.write_output(&path, ".decomposition.json", &decomposition);
+                put_message(i, count, format!("    Wrote decomposition to: {}", output.display()));
+            }
+
+            let npz_output = args.output_path(&path, ".blocks.npz");
+            let mut npz = args.emit.contains(&OutputType::Npz).then(|| {
+                let file = std::fs::File::create(&npz_output)
                     .expect("Couldn't write output file!");
-                put_message(i, count, format!("    Wrote block matrix to: {}", output.display()));
-            }
-
-            if args.emit.contains(&OutputType::Tensor) {
-                let output = args.output_path(&path, &format!(".block{}.tensor.npy", 2*j + 1));
-                let n = matrix.shape()[0];
-                let r = matrix.shape()[1];
-                let mut tensor = nd::Array3::from_elem((n, n, n), false);
-                for i in 0..n {
-                    for j in 0..n {
-                        for k in 0..n {
-                            let mut elem = false;
-                            for l in 0..r {
-                                elem ^= matrix[[i, l]] & matrix[[j, l]] & matrix[[k, l]];
+                ndarray_npy::NpzWriter::new(file)
+            });
+
+            for (j, (mapping, matrix)) in matrices.iter().enumerate() {
+                let emitted = matrix.shape()[1] >= args.min_block_tcount
+                    && (args.only_blocks.is_empty() || args.only_blocks.contains(&(2*j + 1)));
+
+                if emitted && args.emit.contains(&OutputType::Matrix) {
+                    let output = args.write_output(&path, &format!(".block{}.mapping.txt", 2*j + 1), &format!("{:?}", mapping));
+                    put_message(i, count, format!("    Wrote block mapping to: {}", output.display()));
+                }
+
+                let mut blockstats = BlockStats::default();
+                blockstats.qubits = matrix.shape()[0];
+                blockstats.initial = matrix.shape()[1];
+                blockstats.rank = crate::extract::gf2_rank(matrix);
+                blockstats.waring_rank = crate::extract::waring_rank_bound(matrix);
+                blockstats.parity_weight_histogram = vec![0; matrix.shape()[0] + 1];
+                for col in matrix.columns() {
+                    blockstats.parity_weight_histogram[col.iter().filter(|&&v| v).count()] += 1;
+                }
+                blockstats.emitted = emitted;
+                filestats.blocks.push(blockstats);
+
+                if !emitted {
+                    continue
+                }
+
+                if let Some(gadgets) = &gadgets {
+                    let json = serde_json::to_string(&gadgets[j])
+                        .expect("Couldn't serialize phase gadgets");
+                    let output = args.write_output(&path, &format!(".block{}.gadgets.json", 2*j + 1), &json);
+                    put_message(i, count, format!("    Wrote block phase gadgets to: {}", output.display()));
+                }
+
+                let suffix = format!(".block{}.matrix", 2*j + 1);
+                if args.emit.contains(&OutputType::Matrix) {
+                    let output = args.output_path(&path, &format!("{}.npy", suffix));
+                    ndarray_npy::write_npy(&output, &args.matrix_orientation.orient(matrix.clone()))
+                        .expect("Couldn't write output file!");
+                    put_message(i, count, format!("    Wrote block matrix to: {}", output.display()));
+                }
+
+                if args.emit.contains(&OutputType::Tensor) {
+                    let output = args.output_path(&path, &format!(".block{}.tensor.npy", 2*j + 1));
+                    let n = matrix.shape()[0];
+                    let r = matrix.shape()[1];
+                    let mut tensor = nd::Array3::from_elem((n, n, n), false);
+                    for i in 0..n {
+                        for j in 0..n {
+                            for k in 0..n {
+                                let mut elem = false;
+                                for l in 0..r {
+                                    elem ^= matrix[[i, l]] & matrix[[j, l]] & matrix[[k, l]];
+                                }
+                                tensor[[i, j, k]] = elem;
                             }
-                            tensor[[i, j, k]] = elem;
                         }
                     }
+                    ndarray_npy::write_npy(&output, &tensor)
+                        .expect("Couldn't write output file!");
+                    put_message(i, count, format!("    Wrote block tensor to: {}", output.display()));
                 }
-                ndarray_npy::write_npy(&output, &tensor)
-                    .expect("Couldn't write output file!");
-                put_message(i, count, format!("    Wrote block tensor to: {}", output.display()));
+
+                if let Some(npz) = &mut npz {
+                    let mapping = mapping.iter().map(|&q| q as u64).collect::<nd::Array1<u64>>();
+                    npz.add_array(format!("block{}_matrix", 2*j + 1), &args.matrix_orientation.orient(matrix.clone()))
+                        .and_then(|_| npz.add_array(format!("block{}_tensor", 2*j + 1), &crate::extract::find_signature_tensor(matrix)))
+                        .and_then(|_| npz.add_array(format!("block{}_mapping", 2*j + 1), &mapping))
+                        .expect("Couldn't write output file!");
+                }
+
+                if args.emit.contains(&OutputType::TensorCOO) {
+                    // Entries are visited in row-major order, so the triples come out sorted
+                    let coo = crate::extract::find_signature_tensor(matrix)
+                        .indexed_iter()
+                        .filter(|(_, &v)| v)
+                        .map(|((i, j, k), _)| format!("{} {} {}\n", i, j, k))
+                        .collect::<String>();
+                    let output = args.write_output(&path, &format!(".block{}.tensor.coo.txt", 2*j + 1), &coo);
+                    put_message(i, count, format!("    Wrote sparse block tensor to: {}", output.display()));
+                }
+
+                if args.emit.contains(&OutputType::TensorPacked) {
+                    let output = args.output_path(&path, &format!(".block{}.tensor.packed.npy", 2*j + 1));
+                    let packed = crate::extract::pack_symmetric_tensor(&crate::extract::find_signature_tensor(matrix));
+                    ndarray_npy::write_npy(&output, &packed)
+                        .expect("Couldn't write output file!");
+                    put_message(i, count, format!("    Wrote packed block tensor to: {}", output.display()));
+                }
+            }
+
+            if let Some(npz) = npz {
+                npz.finish().expect("Couldn't write output file!");
+                put_message(i, count, format!("    Wrote block archive to: {}", npz_output.display()));
             }
+
+            if let Some(resources) = resources {
+                let resources = serde_json::to_string_pretty(&resources)
+                    .expect("Couldn't serialize resource estimate");
+                let output = args.write_output(&path, ".resources.json", &resources);
+                put_message(i, count, format!("    Wrote resource estimate to: {}", output.display()));
+            }
+
+            if args.emit.contains(&OutputType::Report) {
+                let mut diagrams = Vec::new();
+                if args.report_diagrams {
+                    diagrams.push(("Block 0 (Clifford)".to_string(), partitioned.front.to_quantikz(qubits)));
+                    for (j, block) in partitioned.blocks.iter().enumerate() {
+                        let kind = if j % 2 == 0 { "CNOT + Phase" } else { "Clifford" };
+                        diagrams.push((format!("Block {} ({})", j + 1, kind), block.to_quantikz(qubits)));
+                    }
+                    diagrams.push((format!("Block {} (Clifford)", partitioned.blocks.len() + 1), partitioned.back.to_quantikz(qubits)));
+                }
+                let output = args.write_output(&path, ".report.md", &filestats.to_markdown(&diagrams));
+                put_message(i, count, format!("    Wrote report to: {}", output.display()));
+            }
+
+            time(&mut filestats, "emit", emit_start);
+
+            Some(filestats)
+        }));
+
+        let filestats = match result {
+            Ok(Some(filestats)) => filestats,
+            Ok(None) => continue,
+            Err(panic) => {
+                let error = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".into());
+                put_message(i, count, format!("  Failed with error: {}, skipping", error));
+                logfile.failures.push(FileFailure { path, error });
+                continue
+            }
+        };
+
+        if let Some(db) = &database {
+            filestats.insert(db, run)
+                .expect("Couldn't write to database");
+            put_message(i, count, format!("    Wrote statistics to database: {}", args.sqlite.as_ref().unwrap().display()));
         }
 
         logfile.files.push(filestats);
@@ -426,4 +1243,13 @@ pub fn main(args: Args) {
             path.display()
         );
     }
+
+    // Bundle files can hold several circuits, so circuits and files are counted separately
+    println!(
+        "Compiled {} of {} circuits from {} of {} files, {} parse failures",
+        logfile.files.len(), count, files.len() - parse_failures, files.len(), parse_failures
+    );
+    if parse_failures > 0 {
+        std::process::exit(PARSE_FAILURE_EXIT_CODE);
+    }
 }
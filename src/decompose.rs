@@ -1,7 +1,11 @@
-use std::collections::HashSet;
-use crate::circuit::{Gate, Circuit, Qubit, Phase};
+use std::collections::{HashMap, HashSet};
+use crate::circuit::{Gate, Circuit, Endian, Qubit, Phase};
+use crate::hadamard::AncillaBasis;
+use crate::decomposition::DecompositionTable;
+use crate::extract::LinearSynthStrategy;
 use ndarray as nd;
-use rand::seq::SliceRandom;
+use rand::{Rng, seq::SliceRandom};
+use serde::Serialize;
 
 impl Circuit {
     /// Pull out all non-obstructed gates that satisfy the 
@@ -20,12 +24,14 @@ impl Circuit {
             }
     
             if !progress {
-                return Circuit { gates: front }
+                return Circuit::new(front)
             }
         }
     }
 
     /// Extract Clifford gates from the front and back of the circuit.
+    /// This is maximal with respect to gate overlaps: a Clifford is left behind
+    /// only if it shares a qubit with an earlier (resp. later) gate that stays.
     pub fn extract_cliffords(&mut self) -> (Circuit, Circuit) {
         let front = self.pull_gates(Gate::is_clifford);
         self.gates.reverse();
@@ -37,8 +43,23 @@ impl Circuit {
     }
 
     /// Split this circuit into alternating blocks of CNOT+Phase and Clifford gates.
-    pub fn partition(mut self) -> PartitionedCircuit {
-        let (front, back) = self.extract_cliffords();
+    pub fn partition(self) -> PartitionedCircuit {
+        self.partition_with(true)
+    }
+
+    /// Split this circuit into alternating blocks of CNOT+Phase and Clifford gates,
+    /// optionally leaving the leading and trailing Cliffords in place within the blocks
+    /// instead of hoisting them into `front` and `back`, which are then empty.
+    pub fn partition_with(mut self, hoist_cliffords: bool) -> PartitionedCircuit {
+        let (mut front, back) = if hoist_cliffords {
+            self.extract_cliffords()
+        } else {
+            (Circuit::new(Vec::new()), Circuit::new(Vec::new()))
+        };
+        front.global_phase = std::mem::replace(&mut self.global_phase, Phase(0));
+        // Gates move between blocks here, so keep the comments together at the start
+        front.comments = std::mem::take(&mut self.comments);
+        front.hoist_comments();
 
         let mut blocks = Vec::new();
         while self.gates.len() > 0 {
@@ -48,19 +69,100 @@ impl Circuit {
             }
             blocks.push(self.pull_gates(Gate::is_clifford));
         }
-        PartitionedCircuit { front, back, blocks }
+        PartitionedCircuit { front, back, blocks, ancilla: Vec::new(), x_corrections: Vec::new() }
+    }
+
+    /// Cut the circuit into contiguous slices that each contain at most `max_t_per_slice`
+    /// non-Clifford gates. Concatenating the slices reproduces the original circuit.
+    pub fn slice_by_t_depth(&self, max_t_per_slice: usize) -> Vec<Circuit> {
+        assert!(max_t_per_slice > 0, "each slice must be allowed at least one non-Clifford gate");
+
+        let mut slices = Vec::new();
+        let mut current = Circuit::new(Vec::new());
+        current.global_phase = self.global_phase;
+        let mut tcount = 0;
+        for &gate in &self.gates {
+            if !gate.is_clifford() {
+                if tcount == max_t_per_slice {
+                    slices.push(std::mem::replace(&mut current, Circuit::new(Vec::new())));
+                    tcount = 0;
+                }
+                tcount += 1;
+            }
+            current.gates.push(gate);
+        }
+
+        if !current.gates.is_empty() || slices.is_empty() && current.global_phase != Phase(0) {
+            slices.push(current);
+        }
+        slices
+    }
+
+    /// Remove pairs of Hadamards on the same qubit with no gates between them on that qubit.
+    pub fn cancel_hadamards(&mut self) {
+        // The index of the last remaining gate acting on each qubit
+        let mut last: HashMap<Qubit, usize> = HashMap::new();
+        let mut removed = vec![false; self.gates.len()];
+        for i in 0..self.gates.len() {
+            let gate = self.gates[i];
+            if let Gate::H(q) = gate {
+                if let Some(&j) = last.get(&q) {
+                    if matches!(self.gates[j], Gate::H(_)) {
+                        removed[i] = true;
+                        removed[j] = true;
+                        last.remove(&q);
+                        continue
+                    }
+                }
+            }
+
+            for q in gate.qubits() {
+                last.insert(q, i);
+            }
+        }
+
+        self.remove_gates(&removed);
+    }
+
+    /// Relabel the given ancilla qubits onto the physical qubits `positions`, shifting the
+    /// other qubits up to fill the remaining positions in order. Returns the layout, so that
+    /// qubit i is now qubit layout[i].
+    pub fn place_ancillas(&mut self, ancilla: &[Qubit], positions: &[usize]) -> Vec<usize> {
+        let layout = ancilla_layout(self.qubits(), ancilla, positions);
+        for gate in &mut self.gates {
+            gate.map_qubits(|Qubit(q)| Qubit(layout[q]));
+        }
+        layout
     }
 
     /// Append another circuit's gates after this one.
     pub fn merge(&mut self, mut other: Circuit) -> &mut Circuit {
+        let offset = self.gates.len();
+        self.comments.extend(other.comments.into_iter().map(|(i, c)| (i + offset, c)));
         self.gates.append(&mut other.gates);
+        self.global_phase = self.global_phase + other.global_phase;
         self
     }
 
     /// Convert a CNOT + Phase + CCZ + CS + X + SWAP circuit into CNOT + Phase
     /// and emit an extra Clifford block
     pub fn to_cnot_phase(&mut self) -> Circuit {
-        // First, decompose CZ, CS, SWAP, and CCZ, into CNOT + Phase
+        self.to_cnot_phase_with(&DecompositionTable::default())
+    }
+
+    /// As `to_cnot_phase`, first substituting any custom decompositions from the table, which
+    /// must only introduce CNOT, Phase, X, CZ, CS, CCZ and SWAP gates. Native entries use the
+    /// built-in decompositions, since a CNOT + Phase circuit can't keep them.
+    pub fn to_cnot_phase_with(&mut self, table: &DecompositionTable) -> Circuit {
+        for i in (0..self.gates.len()).rev() {
+            let gates = table.expand(self.gates[i]);
+            if let Some(g) = gates.iter().find(|g| matches!(g, Gate::H(_))) {
+                panic!("Custom decomposition of {:?} contains {:?}, which can't be lowered to CNOT + Phase", self.gates[i], g);
+            }
+            self.gates.splice(i..=i, gates);
+        }
+
+        // Then, decompose CZ, CS, SWAP, and CCZ, into CNOT + Phase
         for i in (0..self.gates.len()).rev() {
             match self.gates[i] {
                 Gate::CZ(a, b) => {
@@ -126,68 +228,270 @@ impl Circuit {
                 }
             }
         }
-        let mut xswaps = Vec::new();
-        for q in total_x {
-            xswaps.push(Gate::X(q));
+        let mut total_x = total_x.into_iter().collect::<Vec<_>>();
+        total_x.sort();
+        Circuit::new(total_x.into_iter().map(Gate::X).collect())
+    }
+
+    /// Commute all X and Z gates to the end of the circuit and remove them, returning
+    /// the resulting Pauli correction as a frame to be applied to the output.
+    /// Gates that do not normalize the Pauli group are corrected by Clifford gates.
+    pub fn extract_pauli_frame(&mut self) -> PauliFrame {
+        let n = self.qubits();
+        let mut frame = PauliFrame { x: vec![false; n], z: vec![false; n] };
+        let mut gates = Vec::new();
+        for &gate in &self.gates {
+            match gate {
+                Gate::X(Qubit(q)) => frame.x[q] ^= true,
+                Gate::Phase(Phase::Z, Qubit(q)) => frame.z[q] ^= true,
+                Gate::Phase(p, q) => gates.push(Gate::Phase(if frame.x[q.0] { -p } else { p }, q)),
+                Gate::CNOT(Qubit(c), Qubit(t)) => {
+                    frame.x[t] ^= frame.x[c];
+                    frame.z[c] ^= frame.z[t];
+                    gates.push(gate);
+                },
+                Gate::H(Qubit(q)) => {
+                    std::mem::swap(&mut frame.x[q], &mut frame.z[q]);
+                    gates.push(gate);
+                },
+                Gate::I(_) => gates.push(gate),
+                Gate::SWAP(Qubit(a), Qubit(b)) => {
+                    frame.x.swap(a, b);
+                    frame.z.swap(a, b);
+                    gates.push(gate);
+                },
+                Gate::CZ(Qubit(a), Qubit(b)) => {
+                    frame.z[b] ^= frame.x[a];
+                    frame.z[a] ^= frame.x[b];
+                    gates.push(gate);
+                },
+                Gate::CS(a, b) => {
+                    // Flipping the inputs of CS changes it by a diagonal Clifford
+                    gates.push(gate);
+                    match (frame.x[a.0], frame.x[b.0]) {
+                        (true, true) => {
+                            gates.push(Gate::Phase(-Phase::S, a));
+                            gates.push(Gate::Phase(-Phase::S, b));
+                        },
+                        (true, false) => {
+                            gates.push(Gate::CZ(a, b));
+                            gates.push(Gate::Phase(Phase::S, b));
+                        },
+                        (false, true) => {
+                            gates.push(Gate::CZ(a, b));
+                            gates.push(Gate::Phase(Phase::S, a));
+                        },
+                        (false, false) => ()
+                    }
+                },
+                Gate::CCZ(a, b, c) => {
+                    // Flipping the inputs of CCZ changes it by CZs and Zs
+                    gates.push(gate);
+                    let (xa, xb, xc) = (frame.x[a.0], frame.x[b.0], frame.x[c.0]);
+                    if xa { gates.push(Gate::CZ(b, c)); }
+                    if xb { gates.push(Gate::CZ(a, c)); }
+                    if xc { gates.push(Gate::CZ(a, b)); }
+                    frame.z[c.0] ^= xa & xb;
+                    frame.z[b.0] ^= xa & xc;
+                    frame.z[a.0] ^= xb & xc;
+                }
+            }
         }
-        
 
-        Circuit { gates: xswaps }
+        self.gates = gates;
+        frame
     }
 
-    /// Given a CNOT + Phase circuit, diagonalize it in place and extract its gate 
-    /// synthesis matrix along with an extra Clifford block. Returns the list of qubits 
-    /// forming matrix rows, the gate synthesis matrix, and a Clifford block.
-    pub fn extract_gadgets(&mut self) -> (Vec<usize>, nd::Array2<bool>, Circuit) {
-        // This CNOT circuit implements the linear transformation part of the circuit
-        let mut cnots = self.gates.iter()
-            .filter(|g| matches!(g, Gate::CNOT(_, _)))
-            .cloned()
-            .collect::<Vec<_>>();
+    /// Given a CNOT + Phase circuit, merge all phase gates that act on the same parity
+    /// of the input, as if the Cliffords between them were commuted out of the way.
+    /// Phase gadgets are diagonal so they all commute, and the result is the merged gadgets
+    /// followed by the CNOTs. This exposes more complete CCZ/CS gadgets to `extract_gadgets`.
+    /// Returns the number of T gates removed, or zero if this is not a CNOT + Phase circuit.
+    pub fn fold_phases(&mut self) -> usize {
+        if !self.gates.iter().all(|g| matches!(g, Gate::CNOT(_, _) | Gate::Phase(_, _))) {
+            return 0
+        }
 
         let n = self.qubits();
+        let before = self.tcount();
         let mut matrix = nd::Array::from_shape_fn((n, n), |(i, j)| i == j);
-        let mut gadgets = Vec::new();
+        let mut gadgets: Vec<(Phase, nd::Array1<bool>)> = Vec::new();
+        let mut cnots = Vec::new();
         for &gate in &self.gates {
             match gate {
                 Gate::CNOT(Qubit(a), Qubit(b)) => {
                     let (row_a, mut row_b) = matrix.multi_slice_mut((nd::s![a, ..], nd::s![b, ..]));
                     row_b ^= &row_a;
+                    cnots.push(gate);
                 },
                 Gate::Phase(p, Qubit(q)) => {
-                    gadgets.push((p, matrix.slice(nd::s![q, ..]).to_owned()));
+                    let parity = matrix.row(q);
+                    match gadgets.iter_mut().find(|(_, other)| *other == parity) {
+                        Some((phase, _)) => *phase = *phase + p,
+                        None => gadgets.push((p, parity.to_owned()))
+                    }
                 },
-                _ => ()
+                _ => unreachable!()
             }
         }
 
-        fn synth_gadget(target: &mut Vec<Gate>, phase: Phase, parity: &nd::Array1<bool>) {
-            if phase == Phase(0) { return }
+        self.gates.clear();
+        for (phase, parity) in &gadgets {
+            synth_phase_gadget(&mut self.gates, *phase, parity);
+        }
+        self.gates.append(&mut cnots);
+        before - self.tcount()
+    }
 
-            if let Some(q) = parity.iter().position(|&e| e == true) {
-                for i in 0..parity.len() {
-                    if i != q && parity[i] {
-                        target.push(Gate::CNOT(Qubit(i), Qubit(q)));
-                    }
+    /// Merge phase gates on the same affine parity anywhere in the circuit, before it is
+    /// partitioned. Each qubit holds a parity of path variables, starting with one per qubit,
+    /// and a Hadamard replaces its qubit's parity with a fresh variable. A later phase gate on
+    /// a parity already seen is added to the first one, negated (with a global phase) if the
+    /// two differ by an X. CZ, CS and CCZ are diagonal so they leave the parities unchanged.
+    /// Returns the number of T gates removed.
+    pub fn fold_phases_global(&mut self) -> usize {
+        // Symmetric difference of two sorted sets of variables
+        fn xor(a: &[usize], b: &[usize]) -> Vec<usize> {
+            let (mut i, mut j) = (0, 0);
+            let mut out = Vec::with_capacity(a.len() + b.len());
+            while i < a.len() || j < b.len() {
+                if j == b.len() || (i < a.len() && a[i] < b[j]) {
+                    out.push(a[i]);
+                    i += 1;
+                } else if i == a.len() || b[j] < a[i] {
+                    out.push(b[j]);
+                    j += 1;
+                } else {
+                    i += 1;
+                    j += 1;
                 }
-                target.push(Gate::Phase(phase, Qubit(q)));
-                for i in (0..parity.len()).rev() {
-                    if i != q && parity[i] {
-                        target.push(Gate::CNOT(Qubit(i), Qubit(q)));
+            }
+            out
+        }
+
+        let before = self.tcount();
+        let mut parities = (0..self.qubits()).map(|q| (vec![q], false)).collect::<Vec<_>>();
+        let mut next_var = parities.len();
+        let mut first: HashMap<Vec<usize>, (usize, bool)> = HashMap::new();
+        let mut remove = vec![false; self.gates.len()];
+        for i in 0..self.gates.len() {
+            match self.gates[i] {
+                Gate::CNOT(Qubit(c), Qubit(t)) => {
+                    parities[t] = (xor(&parities[t].0, &parities[c].0), parities[t].1 ^ parities[c].1);
+                },
+                Gate::X(Qubit(q)) => parities[q].1 ^= true,
+                Gate::SWAP(Qubit(a), Qubit(b)) => parities.swap(a, b),
+                Gate::H(Qubit(q)) => {
+                    parities[q] = (vec![next_var], false);
+                    next_var += 1;
+                },
+                Gate::Phase(p, Qubit(q)) => {
+                    let (parity, negated) = &parities[q];
+                    match first.get(parity) {
+                        Some(&(j, first_negated)) => {
+                            let Gate::Phase(pj, qj) = self.gates[j] else { unreachable!() };
+                            if *negated == first_negated {
+                                self.gates[j] = Gate::Phase(pj + p, qj);
+                            } else {
+                                // p (1 + f) = p - p f, as X P(p) X = e^(i p pi/4) P(-p)
+                                self.gates[j] = Gate::Phase(pj - p, qj);
+                                self.global_phase = self.global_phase + p;
+                            }
+                            remove[i] = true;
+                        },
+                        None => {
+                            first.insert(parity.clone(), (i, *negated));
+                        }
                     }
-                }
+                },
+                Gate::CZ(_, _) | Gate::CS(_, _) | Gate::CCZ(_, _, _) | Gate::I(_) => ()
             }
         }
 
+        // Merging may cancel the first phase entirely
+        for (i, g) in self.gates.iter().enumerate() {
+            remove[i] |= matches!(g, Gate::Phase(Phase(0), _));
+        }
+        self.remove_gates(&remove);
+        before - self.tcount()
+    }
+
+    /// Given a CNOT + Phase circuit, the number of CCZ and CS gadgets `extract::extract_gadgets`
+    /// finds in its gate synthesis matrix with the default gadget costs.
+    pub fn count_gadgets(&self) -> (usize, usize) {
+        let (map, matrix, _) = self.clone().extract_gadgets();
+        let costs = Some(crate::extract::GadgetCosts::default());
+        let (_, nccz, ncs, _) = crate::extract::extract_gadgets(&matrix, &map, costs, crate::extract::GadgetOrder::None, None);
+        (nccz, ncs)
+    }
+
+    /// Given a CNOT + Phase circuit, compute its phase gadgets in order as a phase and
+    /// the parity of input qubits it acts on, along with the linear map of its CNOTs.
+    fn phase_polynomial(&self) -> (Vec<(Phase, nd::Array1<bool>)>, nd::Array2<bool>) {
+        let n = self.qubits();
+        let mut matrix = nd::Array::from_shape_fn((n, n), |(i, j)| i == j);
+        let mut gadgets = Vec::new();
+        for &gate in &self.gates {
+            match gate {
+                Gate::CNOT(Qubit(a), Qubit(b)) => {
+                    let (row_a, mut row_b) = matrix.multi_slice_mut((nd::s![a, ..], nd::s![b, ..]));
+                    row_b ^= &row_a;
+                },
+                Gate::Phase(p, Qubit(q)) => {
+                    gadgets.push((p, matrix.slice(nd::s![q, ..]).to_owned()));
+                },
+                _ => ()
+            }
+        }
+        (gadgets, matrix)
+    }
+
+    /// Given a CNOT + Phase circuit, list its phase gadgets in order as a phase and the
+    /// input qubits whose parity it acts on. This is the phase polynomial before the
+    /// Clifford gadgets are separated out by `extract_gadgets`.
+    pub fn phase_gadgets(&self) -> Vec<(Phase, Vec<Qubit>)> {
+        self.phase_polynomial().0
+            .into_iter()
+            .map(|(phase, parity)| {
+                let qubits = parity.iter()
+                    .enumerate()
+                    .filter(|(_, &v)| v)
+                    .map(|(q, _)| Qubit(q))
+                    .collect();
+                (phase, qubits)
+            })
+            .collect()
+    }
+
+    /// Given a CNOT + Phase circuit, diagonalize it in place and extract its gate 
+    /// synthesis matrix along with an extra Clifford block. Returns the list of qubits 
+    /// forming matrix rows, the gate synthesis matrix, and a Clifford block.
+    pub fn extract_gadgets(&mut self) -> (Vec<usize>, nd::Array2<bool>, Circuit) {
+        self.extract_gadgets_with(LinearSynthStrategy::Naive)
+    }
+
+    /// As `extract_gadgets`, synthesizing the linear part of the Clifford block with the given strategy.
+    pub fn extract_gadgets_with(&mut self, strategy: LinearSynthStrategy) -> (Vec<usize>, nd::Array2<bool>, Circuit) {
+        // This CNOT circuit implements the linear transformation part of the circuit
+        let mut cnots = self.gates.iter()
+            .filter(|g| matches!(g, Gate::CNOT(_, _)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (gadgets, matrix) = self.phase_polynomial();
+        if let Some(synthesized) = strategy.synthesize(&matrix) {
+            cnots = synthesized;
+        }
+
         self.gates.clear();
         let mut cliffords = Vec::new();
         let mut columns = Vec::new();
         for (phase, parity) in &gadgets {
             if phase.is_clifford() {
-                synth_gadget(&mut cliffords, *phase, &parity);
+                synth_phase_gadget(&mut cliffords, *phase, &parity);
             } else {
-                synth_gadget(&mut cliffords, *phase - Phase::T, &parity);
-                synth_gadget(&mut self.gates, Phase::T, &parity);
+                synth_phase_gadget(&mut cliffords, *phase - Phase::T, &parity);
+                synth_phase_gadget(&mut self.gates, Phase::T, &parity);
                 columns.push(parity.view());
             }
         }
@@ -205,15 +509,40 @@ impl Circuit {
         }
         qubits.reverse();
         
-        (qubits, matrix, Circuit { gates: cliffords })
+        (qubits, matrix, Circuit::new(cliffords))
     }
 }
 
-#[derive(Debug)]
+/// A Pauli operator (up to a global phase) that should be applied after a circuit,
+/// given as which qubits have an X and which have a Z.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PauliFrame {
+    pub x: Vec<bool>,
+    pub z: Vec<bool>
+}
+
+impl PauliFrame {
+    /// Construct a circuit implementing this Pauli operator.
+    pub fn to_circuit(&self) -> Circuit {
+        let mut gates = Vec::new();
+        for (q, (&x, &z)) in self.x.iter().zip(&self.z).enumerate() {
+            if z { gates.push(Gate::Phase(Phase::Z, Qubit(q))); }
+            if x { gates.push(Gate::X(Qubit(q))); }
+        }
+        Circuit::new(gates)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct PartitionedCircuit {
     pub front: Circuit,
     pub back: Circuit,
-    pub blocks: Vec<Circuit>
+    pub blocks: Vec<Circuit>,
+    /// The ancilla qubits introduced by Hadamard gadgetization
+    pub ancilla: Vec<Qubit>,
+    /// The net X Pauli pushed out of the end of each CNOT + Phase block by `to_cnot_phase`,
+    /// which is applied at the start of the following Clifford block
+    pub x_corrections: Vec<Vec<Qubit>>
 }
 
 impl PartitionedCircuit {
@@ -227,29 +556,83 @@ impl PartitionedCircuit {
         circuit
     }
 
+    /// As `merge`, but with comments marking where each block begins and ends, numbering the
+    /// front Cliffords as block 0, the internal blocks from 1, and the back Cliffords last.
+    pub fn merge_annotated(&self) -> Circuit {
+        let mut circuit = Circuit::new(Vec::new());
+        let blocks = std::iter::once(&self.front)
+            .chain(&self.blocks)
+            .chain(std::iter::once(&self.back));
+        for (j, block) in blocks.enumerate() {
+            circuit.comments.push((circuit.gates.len(), format!(" block {} begin", j)));
+            circuit.merge(block.clone());
+            circuit.comments.push((circuit.gates.len(), format!(" block {} end", j)));
+        }
+        circuit
+    }
+
+    /// Compute the data dependencies between blocks, numbering the front Cliffords as block 0,
+    /// the internal blocks from 1, and the back Cliffords last. For each block this returns the
+    /// qubits it acts on and the earlier blocks it directly depends on, i.e. the last earlier
+    /// block acting on each of those qubits. Blocks on disjoint qubits are independent.
+    pub fn block_dependencies(&self) -> Vec<(Vec<Qubit>, Vec<usize>)> {
+        let mut last: HashMap<Qubit, usize> = HashMap::new();
+        let mut deps = Vec::new();
+        let blocks = std::iter::once(&self.front)
+            .chain(&self.blocks)
+            .chain(std::iter::once(&self.back));
+        for (i, block) in blocks.enumerate() {
+            let mut qubits = block.gates.iter()
+                .flat_map(|g| g.qubits())
+                .collect::<Vec<_>>();
+            qubits.sort();
+            qubits.dedup();
+
+            let mut depends = qubits.iter()
+                .filter_map(|q| last.insert(*q, i))
+                .collect::<Vec<_>>();
+            depends.sort();
+            depends.dedup();
+            deps.push((qubits, depends));
+        }
+        deps
+    }
+
     /// Merge as many blocks as possible while keeping
     /// the number of ancilla per block below budget.
     pub fn pick_gadgets(&mut self, budget: usize, iters: usize) -> usize {
+        self.pick_gadgets_with(budget, iters, None, &mut rand::thread_rng()).0
+    }
+
+    /// As `pick_gadgets`, but with `patience`, stop early once that many restarts in a row
+    /// haven't improved on the best merge. The window grows to the number of restarts it took
+    /// to find the current best, so instances where improvements come late get searched longer.
+    /// At most `iters` restarts are made either way, and with the same `rng` the restarts are
+    /// a prefix of those without patience, so the result can only be worse if a later restart
+    /// would have improved on it. Returns the number of blocks and the restarts made.
+    pub fn pick_gadgets_with(&mut self, budget: usize, iters: usize, patience: Option<usize>, rng: &mut impl Rng) -> (usize, usize) {
         if self.blocks.len() <= 1 {
-            return self.blocks.len()
+            return (self.blocks.len(), 0)
         }
 
-        let mut rng = rand::thread_rng();
-
         let blocks = self.blocks.iter()
             .enumerate().map(|(i, b)| (b.count_hadamards(), i, i+1))
             .collect::<Vec<_>>();
         let mut best_cost = blocks.len();
         let mut best_blocks = blocks.clone();
 
+        let mut last_improvement = 0;
+
         // We do randomized greedy merging
-        for _ in 0..iters {
+        let mut restarts = 0;
+        for iter in 0..iters {
+            restarts += 1;
             let mut run_blocks = blocks.clone();
             // Merge blocks while possible
             'outer: loop {
                 let n = run_blocks[1..].len() / 2;
                 let mut perm = (1..2*n).step_by(2).collect::<Vec<_>>();
-                perm.shuffle(&mut rng);
+                perm.shuffle(rng);
                 for i in perm {
                     if run_blocks[i - 1].0 + run_blocks[i].0 + run_blocks[i + 1].0 <= budget {
                         let c2 = run_blocks.remove(i + 1);
@@ -269,11 +652,18 @@ impl PartitionedCircuit {
             if cost < best_cost {
                 best_cost = cost;
                 best_blocks = run_blocks;
+                last_improvement = iter;
             }
 
             if best_cost == 1 {
                 break
             }
+
+            if let Some(patience) = patience {
+                if iter - last_improvement >= patience.max(last_improvement) {
+                    break
+                }
+            }
         }
 
         let mut nblocks = Vec::new();
@@ -286,27 +676,32 @@ impl PartitionedCircuit {
         }
         self.blocks = nblocks;
 
-        best_cost
+        (best_cost, restarts)
     }
 
-    /// Convert the non-Clifford blocks into CNOT+Phase circuits
-    pub fn to_cnot_phase(&mut self) {
-        // Find the max number of qubits across all blocks
-        let qubits = self.blocks.iter()
-            .map(|block| block.gates.iter())
-            .flatten()
-            .map(|g| { 
-                let q = g.qubits(); 
-                q[0].0.max(q[1].0).max(q[2].0)
-            })
-            .max().map(|q| q + 1).unwrap_or(0);
+    /// Convert the non-Clifford blocks into CNOT+Phase circuits, with Hadamard
+    /// gadget ancillas prepared and postselected in the given basis, and never
+    /// allocated on the `reserved` qubits.
+    pub fn to_cnot_phase(&mut self, basis: AncillaBasis, reserved: &HashSet<usize>) {
+        self.to_cnot_phase_with(basis, reserved, &DecompositionTable::default())
+    }
+
+    /// As `to_cnot_phase`, lowering the non-Clifford blocks with the given decomposition table.
+    pub fn to_cnot_phase_with(&mut self, basis: AncillaBasis, reserved: &HashSet<usize>, table: &DecompositionTable) {
+        // Find the number of qubits, including any only touched by the front and back
+        // Cliffords, so that ancillas never collide with a data qubit
+        let qubits = self.merge().qubits();
 
         let mut next_id = qubits;
+        self.x_corrections = vec![Vec::new(); (self.blocks.len() + 1) / 2];
         for i in (0..self.blocks.len()).step_by(2).rev() {
             // Taking even blocks (CNOT + T), decompose all hadamards
-            self.blocks[i].decomp_hads(&mut next_id, &mut self.front, &mut self.back);
+            self.blocks[i].decomp_hads(&mut next_id, &mut self.front, &mut self.back, basis, reserved);
             // Extract the X and SWAPS to make this CNOT + Phase only
-            let xswaps = self.blocks[i].to_cnot_phase();
+            let xswaps = self.blocks[i].to_cnot_phase_with(table);
+            self.x_corrections[i / 2] = xswaps.gates.iter()
+                .filter_map(|g| if let Gate::X(q) = g { Some(*q) } else { None })
+                .collect();
             // Merge these into the corresponding Clifford block
             if i == self.blocks.len() - 1 {
                 let back = std::mem::replace(&mut self.back, xswaps);
@@ -316,16 +711,174 @@ impl PartitionedCircuit {
                 self.blocks[i + 1].merge(nblock);
             }
         }
+
+        self.ancilla.extend((qubits..next_id).filter(|q| !reserved.contains(q)).map(Qubit));
+    }
+
+    /// Check that the ancillas introduced by `to_cnot_phase` never collide with a data qubit:
+    /// each ancilla is allocated once, the front Cliffords at most prepare it, and it is only
+    /// acted on by the CNOT + Phase block that allocated it and the Clifford block right after,
+    /// which undoes its SWAP. A collision otherwise only shows up as a failed verification.
+    pub fn validate_ancillas(&self) -> Result<(), String> {
+        let mut ancilla = HashSet::new();
+        for &q in &self.ancilla {
+            if !ancilla.insert(q) {
+                return Err(format!("ancilla {} is allocated more than once", q.0))
+            }
+        }
+
+        for &g in &self.front.gates {
+            if matches!(g, Gate::H(_)) {
+                continue
+            }
+            if let Some(q) = g.qubits().into_iter().find(|q| ancilla.contains(q)) {
+                return Err(format!("ancilla {} is used as a data qubit by {:?} in the front Cliffords", q.0, g))
+            }
+        }
+
+        // The first block to touch an ancilla allocates it
+        let mut allocated = HashMap::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            for &g in &block.gates {
+                for q in g.qubits().into_iter().filter(|q| ancilla.contains(q)) {
+                    let first = *allocated.entry(q).or_insert(i);
+                    if first % 2 != 0 || i > first + 1 {
+                        return Err(format!("ancilla {} is used by {:?} in block {} outside of its lifetime", q.0, g, i + 1))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Relabel the ancilla qubits onto the physical qubits `positions`, as in
+    /// `Circuit::place_ancillas`, consistently across all blocks. Returns the layout.
+    pub fn place_ancillas(&mut self, positions: &[usize]) -> Vec<usize> {
+        let layout = ancilla_layout(self.merge().qubits(), &self.ancilla, positions);
+        let blocks = std::iter::once(&mut self.front)
+            .chain(&mut self.blocks)
+            .chain(std::iter::once(&mut self.back));
+        for block in blocks {
+            for gate in &mut block.gates {
+                gate.map_qubits(|Qubit(q)| Qubit(layout[q]));
+            }
+        }
+        for q in self.ancilla.iter_mut().chain(self.x_corrections.iter_mut().flatten()) {
+            *q = Qubit(layout[q.0]);
+        }
+        layout
+    }
+
+    /// Cancel pairs of Hadamards in the blocks with no gates between them on their qubit. After
+    /// `pick_gadgets` merges blocks, pairs like this can end up in a non-Clifford block, where
+    /// `to_cnot_phase` would otherwise give each of them an ancilla that does nothing. Returns the
+    /// number of Hadamards removed, which is the number of ancillas saved in the merged blocks.
+    pub fn remove_redundant_ancillas(&mut self) -> usize {
+        let mut removed = 0;
+        for block in &mut self.blocks {
+            let before = block.count_hadamards();
+            block.cancel_hadamards();
+            removed += before - block.count_hadamards();
+        }
+        removed
+    }
+
+    /// Relabel the qubits of every block with `Endian::convert`.
+    pub fn convert_endian(&mut self, endian: Endian, n: usize) {
+        for block in std::iter::once(&mut self.front).chain(&mut self.blocks).chain(std::iter::once(&mut self.back)) {
+            block.convert_endian(endian, n);
+        }
+        let f = |q| endian.convert(q, n);
+        self.ancilla.iter_mut().for_each(|q| *q = f(*q));
+        self.x_corrections.iter_mut().flatten().for_each(|q| *q = f(*q));
+    }
+
+    /// Merge T gadgets in a CNOT+Phase block into T gadgets of the previous CNOT+Phase block
+    /// when the Clifford block between them maps the gadget onto the same parity. Each merge
+    /// turns two T gadgets into Clifford phases. Returns the reduction in T-count.
+    pub fn merge_gadgets_across_blocks(&mut self) -> usize {
+        let n = self.merge().qubits();
+        let mut reduction = 0;
+        for i in (0..self.blocks.len().saturating_sub(2)).step_by(2) {
+            // Find the odd phase gates in the earlier block by their parity over the block inputs
+            let (gadgets, outputs) = phase_parities(&self.blocks[i], n);
+            let mut targets: HashMap<Vec<bool>, Vec<usize>> = HashMap::new();
+            for (idx, parity) in gadgets {
+                if matches!(self.blocks[i].gates[idx], Gate::Phase(p, _) if !p.is_clifford()) {
+                    targets.entry(parity.to_vec()).or_default().push(idx);
+                }
+            }
+
+            let (gadgets, _) = phase_parities(&self.blocks[i + 2], n);
+            for (idx, parity) in gadgets {
+                let Gate::Phase(p, q) = self.blocks[i + 2].gates[idx] else { continue };
+                if p.is_clifford() {
+                    continue
+                }
+
+                // Pull the Z-type Pauli of the gadget back through the Clifford block
+                let Some((negated, support)) = pull_back_z(&self.blocks[i + 1], &parity) else { continue };
+                // And express it as a parity over the inputs of the earlier block
+                let mut parity = nd::Array1::from_elem(n, false);
+                for (j, &v) in support.iter().enumerate() {
+                    if v { parity ^= &outputs.row(j); }
+                }
+
+                let Some(idx_prev) = targets.get_mut(&parity.to_vec()).and_then(|idxs| idxs.pop()) else { continue };
+                let Gate::Phase(p_prev, q_prev) = self.blocks[i].gates[idx_prev] else { unreachable!() };
+                self.blocks[i + 2].gates[idx] = Gate::Phase(p - Phase::T, q);
+                // Pulled back through a negation, T on the parity becomes e^(i pi/4) T^dag
+                if negated {
+                    self.blocks[i].gates[idx_prev] = Gate::Phase(p_prev - Phase::T, q_prev);
+                    self.blocks[i].global_phase = self.blocks[i].global_phase + Phase::T;
+                } else {
+                    self.blocks[i].gates[idx_prev] = Gate::Phase(p_prev + Phase::T, q_prev);
+                }
+                reduction += 2;
+            }
+        }
+        reduction
+    }
+
+    /// The number of CCZ and CS gadgets `extract::extract_gadgets` finds in the CNOT + Phase
+    /// blocks with the default gadget costs, e.g. to measure how many `fold_phases` exposes.
+    pub fn count_gadgets(&self) -> (usize, usize) {
+        self.blocks.iter()
+            .step_by(2)
+            .map(|block| block.count_gadgets())
+            .fold((0, 0), |(a, b), (c, d)| (a + c, b + d))
+    }
+
+    /// Merge phases on the same parity within each CNOT + Phase block, see `Circuit::fold_phases`.
+    /// Returns the total number of T gates removed.
+    pub fn fold_phases(&mut self) -> usize {
+        self.blocks.iter_mut()
+            .step_by(2)
+            .map(|block| block.fold_phases())
+            .sum()
+    }
+
+    /// List the phase gadgets of each non-Clifford block, as in `Circuit::phase_gadgets`.
+    pub fn phase_gadgets(&self) -> Vec<Vec<(Phase, Vec<Qubit>)>> {
+        self.blocks.iter()
+            .step_by(2)
+            .map(|block| block.phase_gadgets())
+            .collect()
     }
 
     /// Generate phases and gate synthesis matrices for all blocks in this circuit.
     /// Returns a list of phases and matrices for each non-Clifford block, along with
     /// the overall before and after of tcount.
     pub fn extract_gadgets(&mut self) -> Vec<(Vec<usize>, nd::Array2<bool>)> {
+        self.extract_gadgets_with(LinearSynthStrategy::Naive)
+    }
+
+    /// As `extract_gadgets`, synthesizing the linear part of each block with the given strategy.
+    pub fn extract_gadgets_with(&mut self, strategy: LinearSynthStrategy) -> Vec<(Vec<usize>, nd::Array2<bool>)> {
         let mut matrices = Vec::new();
         for i in (0..self.blocks.len()).step_by(2) {
             // Generate the qubit mapping, matrix, and cliffords per block.
-            let (mapping, matrix, cliffords) = self.blocks[i].extract_gadgets();
+            let (mapping, matrix, cliffords) = self.blocks[i].extract_gadgets_with(strategy);
             matrices.push((mapping, matrix));
             // Append the cliffords to the subsequent clifford block
             if i == self.blocks.len() - 1 {
@@ -340,3 +893,116 @@ impl PartitionedCircuit {
     }
 }
     
+
+/// Synthesize exp(i pi phase/4 x.parity) with CNOTs onto the first qubit in the parity.
+fn synth_phase_gadget(target: &mut Vec<Gate>, phase: Phase, parity: &nd::Array1<bool>) {
+    if phase == Phase(0) { return }
+
+    if let Some(q) = parity.iter().position(|&e| e == true) {
+        for i in 0..parity.len() {
+            if i != q && parity[i] {
+                target.push(Gate::CNOT(Qubit(i), Qubit(q)));
+            }
+        }
+        target.push(Gate::Phase(phase, Qubit(q)));
+        for i in (0..parity.len()).rev() {
+            if i != q && parity[i] {
+                target.push(Gate::CNOT(Qubit(i), Qubit(q)));
+            }
+        }
+    }
+}
+
+/// Compute the layout placing `ancilla[i]` at `positions[i]`, with the other
+/// qubits of a register of size n filling the remaining positions in order.
+fn ancilla_layout(n: usize, ancilla: &[Qubit], positions: &[usize]) -> Vec<usize> {
+    assert_eq!(ancilla.len(), positions.len(), "each ancilla needs exactly one position");
+    let n = n.max(positions.iter().map(|&p| p + 1).max().unwrap_or(0));
+    let mut layout = vec![usize::MAX; n];
+    for (&Qubit(a), &p) in ancilla.iter().zip(positions) {
+        assert!(!layout.contains(&p), "ancilla positions must be distinct");
+        layout[a] = p;
+    }
+
+    let mut free = (0..n).filter(|p| !positions.contains(p));
+    for l in layout.iter_mut().filter(|l| **l == usize::MAX) {
+        *l = free.next().unwrap();
+    }
+    layout
+}
+
+/// For a CNOT + Phase circuit on n qubits, find the parity over the circuit inputs at each
+/// phase gate (by gate index), along with the parities held by each qubit at the end.
+fn phase_parities(circuit: &Circuit, n: usize) -> (Vec<(usize, nd::Array1<bool>)>, nd::Array2<bool>) {
+    let mut matrix = nd::Array2::from_shape_fn((n, n), |(i, j)| i == j);
+    let mut gadgets = Vec::new();
+    for (idx, &gate) in circuit.gates.iter().enumerate() {
+        match gate {
+            Gate::CNOT(Qubit(a), Qubit(b)) => {
+                let (row_a, mut row_b) = matrix.multi_slice_mut((nd::s![a, ..], nd::s![b, ..]));
+                row_b ^= &row_a;
+            },
+            Gate::Phase(_, Qubit(q)) => gadgets.push((idx, matrix.row(q).to_owned())),
+            _ => ()
+        }
+    }
+    (gadgets, matrix)
+}
+
+/// Conjugate the Pauli Z^parity backwards through a Clifford circuit C, returning C^dag Z^parity C
+/// if it is also Z-type, as a sign (true if negative) and support. Uses the sign update rules of
+/// Aaronson and Gottesman [arXiv:quant-ph/0406196].
+fn pull_back_z(clifford: &Circuit, parity: &nd::Array1<bool>) -> Option<(bool, Vec<bool>)> {
+    let n = parity.len();
+    let mut x = vec![false; n];
+    let mut z = parity.to_vec();
+    let mut r = false;
+
+    let h = |x: &mut Vec<bool>, z: &mut Vec<bool>, r: &mut bool, q: usize| {
+        *r ^= x[q] & z[q];
+        std::mem::swap(&mut x[q], &mut z[q]);
+    };
+    let s = |x: &mut Vec<bool>, z: &mut Vec<bool>, r: &mut bool, q: usize| {
+        *r ^= x[q] & z[q];
+        z[q] ^= x[q];
+    };
+    let cnot = |x: &mut Vec<bool>, z: &mut Vec<bool>, r: &mut bool, c: usize, t: usize| {
+        *r ^= x[c] & z[t] & !(x[t] ^ z[c]);
+        x[t] ^= x[c];
+        z[c] ^= z[t];
+    };
+
+    for &gate in clifford.gates.iter().rev() {
+        match gate {
+            Gate::H(Qubit(q)) => h(&mut x, &mut z, &mut r, q),
+            Gate::X(Qubit(q)) => r ^= z[q],
+            Gate::Phase(p, Qubit(q)) => {
+                if !p.is_clifford() {
+                    return None
+                }
+                // Conjugating by P(p)^dag is the same as conjugating by S^(4 - p/2)
+                for _ in 0..(4 - p.0 / 2) % 4 {
+                    s(&mut x, &mut z, &mut r, q);
+                }
+            },
+            Gate::CNOT(Qubit(c), Qubit(t)) => cnot(&mut x, &mut z, &mut r, c, t),
+            Gate::CZ(Qubit(a), Qubit(b)) => {
+                h(&mut x, &mut z, &mut r, b);
+                cnot(&mut x, &mut z, &mut r, a, b);
+                h(&mut x, &mut z, &mut r, b);
+            },
+            Gate::SWAP(Qubit(a), Qubit(b)) => {
+                x.swap(a, b);
+                z.swap(a, b);
+            },
+            Gate::I(_) => (),
+            Gate::CS(_, _) | Gate::CCZ(_, _, _) => return None
+        }
+    }
+
+    if x.iter().any(|&v| v) {
+        None
+    } else {
+        Some((r, z))
+    }
+}
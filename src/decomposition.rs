@@ -0,0 +1,184 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use crate::circuit::{Circuit, Gate, Qubit};
+
+/// The gates whose lowering can be configured with a `DecompositionTable`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub enum GateKind {
+    CZ,
+    CS,
+    CCZ,
+    SWAP
+}
+
+impl GateKind {
+    pub const ALL: [GateKind; 4] = [GateKind::CZ, GateKind::CS, GateKind::CCZ, GateKind::SWAP];
+
+    /// The kind of this gate and the qubits it acts on, if it is configurable.
+    pub fn of(gate: Gate) -> Option<(GateKind, Vec<Qubit>)> {
+        match gate {
+            Gate::CZ(a, b) => Some((GateKind::CZ, vec![a, b])),
+            Gate::CS(a, b) => Some((GateKind::CS, vec![a, b])),
+            Gate::CCZ(a, b, c) => Some((GateKind::CCZ, vec![a, b, c])),
+            Gate::SWAP(a, b) => Some((GateKind::SWAP, vec![a, b])),
+            _ => None
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            GateKind::CCZ => 3,
+            _ => 2
+        }
+    }
+
+    /// The gate of this kind on qubits `0..arity`.
+    pub fn gate(self) -> Gate {
+        match self {
+            GateKind::CZ => Gate::CZ(Qubit(0), Qubit(1)),
+            GateKind::CS => Gate::CS(Qubit(0), Qubit(1)),
+            GateKind::CCZ => Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+            GateKind::SWAP => Gate::SWAP(Qubit(0), Qubit(1))
+        }
+    }
+}
+
+/// How the lowering paths (`to_cnot_phase`, `to_zx`, `to_qc` and `to_openqasm`) handle one kind of gate.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decomposition {
+    /// The lowering path's own decomposition, which keeps the gate if the target has it natively.
+    #[default]
+    Builtin,
+    /// Keep the gate if the target can express it, otherwise fall back to the built-in decomposition.
+    Native,
+    /// Replace the gate by this sequence, where `Qubit(i)` stands for the gate's i-th qubit.
+    /// The sequence may contain other configurable gates, which are lowered in turn, but no
+    /// Hadamards, since every kind can end up in a CNOT + Phase block.
+    Custom(Vec<Gate>)
+}
+
+/// A decomposition for each configurable gate, defaulting to the built-in ones.
+#[derive(Debug, Clone, Default)]
+pub struct DecompositionTable {
+    entries: HashMap<GateKind, Decomposition>
+}
+
+impl DecompositionTable {
+    /// Parse a table from a JSON object mapping gate kinds to decompositions, e.g.
+    /// `{"CCZ": "native", "SWAP": {"custom": [{"CNOT": [1, 0]}, {"CNOT": [0, 1]}, {"CNOT": [1, 0]}]}}`.
+    pub fn from_json(source: &str) -> Result<DecompositionTable, String> {
+        let entries = serde_json::from_str::<HashMap<GateKind, Decomposition>>(source)
+            .map_err(|e| format!("Couldn't parse decomposition table: {}", e))?;
+        let mut table = DecompositionTable::default();
+        for (kind, decomposition) in entries {
+            table.set(kind, decomposition)?;
+        }
+        Ok(table)
+    }
+
+    pub fn get(&self, kind: GateKind) -> &Decomposition {
+        self.entries.get(&kind).unwrap_or(&Decomposition::Builtin)
+    }
+
+    /// Set the decomposition of a kind of gate. Fails if a custom sequence acts outside of the
+    /// gate's qubits, has an invalid phase, contains a Hadamard, doesn't implement the gate
+    /// (up to a global phase), or would make the lowering recurse forever.
+    pub fn set(&mut self, kind: GateKind, decomposition: Decomposition) -> Result<(), String> {
+        if let Decomposition::Custom(gates) = &decomposition {
+            for &gate in gates {
+                if let Some(q) = gate.qubits().iter().find(|q| q.0 >= kind.arity()) {
+                    return Err(format!("{:?} in the decomposition of {:?} acts on qubit {}, but {:?} only has {} qubits", gate, kind, q.0, kind, kind.arity()))
+                }
+                if let Gate::Phase(p, _) = gate {
+                    if p.0 >= 8 {
+                        return Err(format!("{:?} in the decomposition of {:?} has a phase outside of 0 to 7", gate, kind))
+                    }
+                }
+                // CZs reach the CNOT + Phase blocks by propagating Xs through CCZs, and the
+                // other kinds are there from the start, so none of them may use a Hadamard
+                if let Gate::H(_) = gate {
+                    return Err(format!("{:?} in the decomposition of {:?} can't be lowered to CNOT + Phase", gate, kind))
+                }
+            }
+            if !implements(kind, gates) {
+                return Err(format!("The decomposition of {:?} doesn't implement it", kind))
+            }
+        }
+
+        let previous = self.entries.insert(kind, decomposition);
+        if let Some(cycle) = self.find_cycle() {
+            match previous {
+                Some(previous) => self.entries.insert(kind, previous),
+                None => self.entries.remove(&kind)
+            };
+            return Err(format!("Custom decompositions are cyclic: {:?}", cycle))
+        }
+        Ok(())
+    }
+
+    pub fn is_native(&self, kind: GateKind) -> bool {
+        matches!(self.get(kind), Decomposition::Native)
+    }
+
+    /// Find a chain of custom decompositions that contain each other, if any.
+    fn find_cycle(&self) -> Option<Vec<GateKind>> {
+        fn visit(table: &DecompositionTable, kind: GateKind, stack: &mut Vec<GateKind>) -> bool {
+            if stack.contains(&kind) {
+                stack.push(kind);
+                return true
+            }
+            let Decomposition::Custom(gates) = table.get(kind) else { return false };
+            stack.push(kind);
+            for &gate in gates {
+                if let Some((inner, _)) = GateKind::of(gate) {
+                    if visit(table, inner, stack) {
+                        return true
+                    }
+                }
+            }
+            stack.pop();
+            false
+        }
+
+        GateKind::ALL.into_iter().find_map(|kind| {
+            let mut stack = Vec::new();
+            visit(self, kind, &mut stack).then_some(stack)
+        })
+    }
+
+    /// Substitute the custom decompositions into a gate, recursively, so that none of the
+    /// resulting gates have one. Other gates are returned unchanged.
+    pub fn expand(&self, gate: Gate) -> Vec<Gate> {
+        let Some((kind, qubits)) = GateKind::of(gate) else { return vec![gate] };
+        let Decomposition::Custom(gates) = self.get(kind) else { return vec![gate] };
+        gates.iter()
+            .flat_map(|&g| {
+                let mut g = g;
+                g.map_qubits(|Qubit(i)| qubits[i]);
+                self.expand(g)
+            })
+            .collect()
+    }
+}
+
+/// Check with quizx that a sequence of gates is equal to the gate of the given kind, up to a
+/// global phase. Nested configurable gates use their built-in decompositions, which is enough
+/// since any custom ones are checked when they are set.
+fn implements(kind: GateKind, gates: &[Gate]) -> bool {
+    use quizx::tensor::ToTensor;
+
+    // Pad both circuits so that they have the same number of qubits
+    let pad = Gate::I(Qubit(kind.arity() - 1));
+    let tensor = |gates: Vec<Gate>| Circuit::new(gates).to_zx()
+        .to_graph::<quizx::hash_graph::Graph>()
+        .to_tensorf()
+        .iter().copied()
+        .collect::<Vec<_>>();
+    let a = tensor(vec![kind.gate(), pad]);
+    let b = tensor(gates.iter().copied().chain([pad]).collect());
+
+    let norm = |v: &[num_complex::Complex64]| v.iter().map(|x| x.norm_sqr()).sum::<f64>().sqrt();
+    let overlap = a.iter().zip(&b).map(|(x, y)| x.conj() * y).sum::<num_complex::Complex64>();
+    a.len() == b.len() && overlap.norm() >= (1.0 - 1e-6) * norm(&a) * norm(&b)
+}
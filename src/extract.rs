@@ -1,6 +1,149 @@
 use ndarray as nd;
+use serde::Serialize;
 use crate::circuit::{Circuit, Gate, Phase, Qubit};
 
+/// The order in which to emit the T gadgets that were not absorbed into CCZ or CS gadgets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+pub enum GadgetOrder {
+    #[value(help = "Emit gadgets in the order they appear in the matrix")]
+    None,
+    #[value(help = "Sort gadgets by target qubit and then by parity")]
+    SortByParity,
+    #[value(help = "Greedily pick the next gadget sharing the most CNOTs with the previous one")]
+    GreedySharedPrefix
+}
+
+/// The layout of gate synthesis matrices when reading or writing them to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+pub enum MatrixOrientation {
+    #[value(help = "One row per qubit and one column per T gadget")]
+    QubitRows,
+    #[value(help = "One column per qubit and one row per T gadget")]
+    QubitCols
+}
+
+impl MatrixOrientation {
+    /// Convert a matrix with qubits as rows to or from this orientation.
+    pub fn orient(self, a: nd::Array2<bool>) -> nd::Array2<bool> {
+        match self {
+            MatrixOrientation::QubitRows => a,
+            MatrixOrientation::QubitCols => a.reversed_axes().as_standard_layout().into_owned()
+        }
+    }
+}
+
+/// The algorithm used to synthesize the CNOT network implementing the linear part of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+pub enum LinearSynthStrategy {
+    #[value(help = "Replay the CNOTs of the original block")]
+    Naive,
+    #[value(help = "Patel-Markov-Hayes synthesis of the linear map")]
+    Pmh,
+    #[value(help = "Gauss-Jordan elimination of the linear map")]
+    Gauss
+}
+
+impl LinearSynthStrategy {
+    /// Synthesize CNOTs implementing the invertible linear map where row q of `matrix`
+    /// is the parity of the inputs held by qubit q at the end. Returns None for `Naive`,
+    /// where the caller should reuse the CNOTs it already has.
+    pub fn synthesize(self, matrix: &nd::Array2<bool>) -> Option<Vec<Gate>> {
+        // Both reduce the matrix to the identity with row operations (target ^= control),
+        // and as each is self-inverse the circuit is those operations in reverse
+        let mut state = matrix.clone();
+        let gates = match self {
+            LinearSynthStrategy::Naive => return None,
+            LinearSynthStrategy::Gauss => {
+                let mut ops = gaussian_elimination(&mut state);
+                ops.reverse();
+                ops.into_iter().map(|(c, t)| Gate::CNOT(Qubit(c), Qubit(t))).collect()
+            },
+            LinearSynthStrategy::Pmh => {
+                let n = state.shape()[0];
+                let section = ((n.max(2) as f64).log2() / 2.0).round().max(1.0) as usize;
+                let mut lower = pmh_lower(&mut state, section);
+                let mut state = state.reversed_axes().as_standard_layout().into_owned();
+                let upper = pmh_lower(&mut state, section);
+                lower.reverse();
+                // Row operations on the transpose are column operations on the original
+                upper.into_iter()
+                    .map(|(c, t)| Gate::CNOT(Qubit(t), Qubit(c)))
+                    .chain(lower.into_iter().map(|(c, t)| Gate::CNOT(Qubit(c), Qubit(t))))
+                    .collect()
+            }
+        };
+        Some(gates)
+    }
+}
+
+/// Add row c to row t of a matrix.
+fn add_row(state: &mut nd::Array2<bool>, c: usize, t: usize) {
+    let (row_c, mut row_t) = state.multi_slice_mut((nd::s![c, ..], nd::s![t, ..]));
+    row_t ^= &row_c;
+}
+
+/// Reduce an invertible matrix to the identity, returning the row operations (control, target).
+fn gaussian_elimination(state: &mut nd::Array2<bool>) -> Vec<(usize, usize)> {
+    let n = state.shape()[0];
+    let mut ops = Vec::new();
+    for col in 0..n {
+        if !state[(col, col)] {
+            let pivot = (col + 1..n).find(|&r| state[(r, col)])
+                .expect("Linear part of a block must be invertible");
+            add_row(state, pivot, col);
+            ops.push((pivot, col));
+        }
+        for row in 0..n {
+            if row != col && state[(row, col)] {
+                add_row(state, col, row);
+                ops.push((col, row));
+            }
+        }
+    }
+    ops
+}
+
+/// The lower-triangular pass of Patel-Markov-Hayes, which eliminates everything below the
+/// diagonal of an invertible matrix, first cancelling duplicate row patterns within each
+/// section of columns. Returns the row operations (control, target).
+fn pmh_lower(state: &mut nd::Array2<bool>, section: usize) -> Vec<(usize, usize)> {
+    let n = state.shape()[0];
+    let mut ops = Vec::new();
+    for start in (0..n).step_by(section) {
+        let end = (start + section).min(n);
+        let mut patterns: Vec<(Vec<bool>, usize)> = Vec::new();
+        for row in start..n {
+            let pattern = state.slice(nd::s![row, start..end]).to_vec();
+            if pattern.iter().all(|&v| !v) {
+                continue
+            }
+            match patterns.iter().find(|(p, _)| *p == pattern) {
+                Some(&(_, first)) => {
+                    add_row(state, first, row);
+                    ops.push((first, row));
+                },
+                None => patterns.push((pattern, row))
+            }
+        }
+
+        for col in start..end {
+            let mut diagonal = state[(col, col)];
+            for row in col + 1..n {
+                if state[(row, col)] {
+                    if !diagonal {
+                        add_row(state, row, col);
+                        ops.push((row, col));
+                        diagonal = true;
+                    }
+                    add_row(state, col, row);
+                    ops.push((col, row));
+                }
+            }
+        }
+    }
+    ops
+}
+
 /// Construct the signature tensor from a gate synthesis matrix.
 /// Up to a rescaling and modulus, this is the same as the phase polynomial tensor.
 pub fn find_signature_tensor(a: &nd::Array2<bool>) -> nd::Array3<bool> {
@@ -11,11 +154,214 @@ pub fn find_signature_tensor(a: &nd::Array2<bool>) -> nd::Array3<bool> {
     })
 }
 
+/// The largest number of qubits `optimal_decomposition` will search over.
+pub const MAX_OPTIMAL_QUBITS: usize = 7;
+
+/// The most sets of columns `optimal_decomposition` will try before giving up.
+pub const MAX_OPTIMAL_SUBSETS: usize = 50_000_000;
+
+/// Exhaustively search for a gate synthesis matrix with the fewest columns that has the same
+/// signature tensor as `a`, trying every set of distinct nonzero columns in order of size.
+/// This is exponential in both the number of qubits and columns, so is only for small blocks.
+/// Returns `a` itself if nothing smaller exists, and None if the search is too large, either
+/// above `MAX_OPTIMAL_QUBITS` qubits or after trying `MAX_OPTIMAL_SUBSETS` sets of columns,
+/// in which case the caller should keep `a`.
+pub fn optimal_decomposition(a: &nd::Array2<bool>) -> Option<nd::Array2<bool>> {
+    optimal_decomposition_with(a, MAX_OPTIMAL_SUBSETS)
+}
+
+/// As `optimal_decomposition`, giving up after trying `max_subsets` sets of columns.
+pub fn optimal_decomposition_with(a: &nd::Array2<bool>, max_subsets: usize) -> Option<nd::Array2<bool>> {
+    let n = a.shape()[0];
+    if n > MAX_OPTIMAL_QUBITS {
+        return None
+    }
+
+    // Each column contributes c x c x c to the tensor, which is symmetric so
+    // it is determined by the entries i >= j >= k, and those fit in a u128
+    let mut entries = Vec::new();
+    for i in 0..n {
+        for j in 0..=i {
+            for k in 0..=j {
+                entries.push((i, j, k));
+            }
+        }
+    }
+    let pack = |col: &[bool]| entries.iter()
+        .enumerate()
+        .fold(0u128, |m, (b, &(i, j, k))| m | (((col[i] & col[j] & col[k]) as u128) << b));
+
+    let target = a.columns().into_iter().fold(0, |t, col| t ^ pack(&col.to_vec()));
+    let candidates = (1usize..1 << n)
+        .map(|x| {
+            let col = (0..n).map(|i| x & (1 << i) != 0).collect::<Vec<_>>();
+            let term = pack(&col);
+            (col, term)
+        })
+        .collect::<Vec<_>>();
+
+    // Returns None once the budget of complete sets to try runs out
+    fn search(candidates: &[(Vec<bool>, u128)], start: usize, remaining: usize, residual: u128, chosen: &mut Vec<usize>, budget: &mut usize) -> Option<bool> {
+        if remaining == 0 {
+            *budget = budget.checked_sub(1)?;
+            return Some(residual == 0)
+        }
+
+        for c in start..=candidates.len().saturating_sub(remaining) {
+            chosen.push(c);
+            if search(candidates, c + 1, remaining - 1, residual ^ candidates[c].1, chosen, budget)? {
+                return Some(true)
+            }
+            chosen.pop();
+        }
+        Some(false)
+    }
+
+    let mut chosen = Vec::new();
+    let mut budget = max_subsets;
+    for r in 0..a.shape()[1] {
+        if search(&candidates, 0, r, target, &mut chosen, &mut budget)? {
+            return Some(nd::Array2::from_shape_fn((n, r), |(i, l)| candidates[chosen[l]].0[i]))
+        }
+    }
+    Some(a.clone())
+}
+
+/// The rank of a matrix over GF(2), i.e. the number of linearly independent columns.
+pub fn gf2_rank(a: &nd::Array2<bool>) -> usize {
+    let mut a = a.clone();
+    let (n, r) = (a.shape()[0], a.shape()[1]);
+    let mut rank = 0;
+    for col in 0..r {
+        let Some(pivot) = (rank..n).find(|&i| a[(i, col)]) else {
+            continue
+        };
+        for j in 0..r {
+            a.swap((rank, j), (pivot, j));
+        }
+        for i in 0..n {
+            if i != rank && a[(i, col)] {
+                let (pivot_row, mut row) = a.multi_slice_mut((nd::s![rank, ..], nd::s![i, ..]));
+                row ^= &pivot_row;
+            }
+        }
+        rank += 1;
+    }
+    rank
+}
+
+/// Bounds on the Waring rank of the signature tensor of a gate synthesis matrix, the fewest
+/// cubes of parities that sum to it over GF(2). The upper bound is the number of nonzero columns
+/// left after cancelling pairs of equal ones, and the lower bound is the GF(2) rank of the tensor
+/// flattened to an n x n^2 matrix, which each cube can increase by at most one.
+pub fn waring_rank_bound(matrix: &nd::Array2<bool>) -> (usize, usize) {
+    let n = matrix.shape()[0];
+    let mut odd = std::collections::HashSet::new();
+    for col in matrix.columns() {
+        if col.iter().any(|&v| v) && !odd.remove(&col) {
+            odd.insert(col);
+        }
+    }
+
+    let flattened = find_signature_tensor(matrix)
+        .into_shape((n, n * n))
+        .expect("Signature tensor should be contiguous");
+    (gf2_rank(&flattened), odd.len())
+}
+
+/// Find a gate synthesis matrix whose signature tensor is the given tensor, or None if it
+/// isn't one, i.e. it isn't symmetric or has `t[i, i, j] != t[i, j, j]`. The cubic entries are
+/// cleared first with weight 3 columns, then the remaining quadratic ones with weight 2 columns,
+/// then the diagonal with weight 1 columns, as each kind of column only affects lower degree
+/// entries after the first. The result is valid but generally far from the smallest one.
+pub fn waring_decompose(tensor: &nd::Array3<bool>) -> Option<nd::Array2<bool>> {
+    let n = tensor.shape()[0];
+    if tensor.shape() != [n, n, n] {
+        return None
+    }
+
+    let mut residual = tensor.clone();
+    let mut columns = Vec::new();
+    let mut add = |residual: &mut nd::Array3<bool>, support: &[usize]| {
+        for &i in support {
+            for &j in support {
+                for &k in support {
+                    residual[(i, j, k)] ^= true;
+                }
+            }
+        }
+        columns.push(nd::Array1::from_shape_fn(n, |q| support.contains(&q)));
+    };
+
+    for i in 0..n {
+        for j in 0..i {
+            for k in 0..j {
+                if residual[(i, j, k)] {
+                    add(&mut residual, &[i, j, k]);
+                }
+            }
+        }
+    }
+    for i in 0..n {
+        for j in 0..i {
+            if residual[(i, i, j)] {
+                add(&mut residual, &[i, j]);
+            }
+        }
+    }
+    for i in 0..n {
+        if residual[(i, i, i)] {
+            add(&mut residual, &[i]);
+        }
+    }
+
+    if residual.iter().any(|&v| v) {
+        return None
+    }
+
+    let columns = columns.iter().map(|c| c.view()).collect::<Vec<_>>();
+    Some(nd::stack(nd::Axis(1), &columns)
+        .unwrap_or_else(|_| nd::Array2::from_elem((n, 0), false)))
+}
+
+/// Pack a symmetric n x n x n tensor into a flat array of its entries with i >= j >= k,
+/// in lexicographic order of (i, j, k). Entry (i, j, k) is at index
+/// i(i+1)(i+2)/6 + j(j+1)/2 + k, for a total length of n(n+1)(n+2)/6.
+pub fn pack_symmetric_tensor(t: &nd::Array3<bool>) -> nd::Array1<bool> {
+    let n = t.shape()[0];
+    let mut packed = Vec::with_capacity(n * (n + 1) * (n + 2) / 6);
+    for i in 0..n {
+        for j in 0..=i {
+            for k in 0..=j {
+                packed.push(t[(i, j, k)]);
+            }
+        }
+    }
+    nd::Array1::from(packed)
+}
+
+/// Reconstruct a symmetric tensor from `pack_symmetric_tensor`,
+/// or None if the length is not that of a packed tensor.
+pub fn unpack_symmetric_tensor(packed: &nd::Array1<bool>) -> Option<nd::Array3<bool>> {
+    let n = (0..).find(|n| n * (n + 1) * (n + 2) / 6 >= packed.len())?;
+    if n * (n + 1) * (n + 2) / 6 != packed.len() {
+        return None
+    }
+
+    Some(nd::Array3::from_shape_fn((n, n, n), |(i, j, k)| {
+        // Sort the indices descending to find the stored entry
+        let mut ix = [i, j, k];
+        ix.sort_unstable_by(|a, b| b.cmp(a));
+        let [i, j, k] = ix;
+        packed[i * (i + 1) * (i + 2) / 6 + j * (j + 1) / 2 + k]
+    }))
+}
+
 /// Construct a tensor representing the phase polynomial corresponding
 /// to a gate synthesis matrix. The coefficient of the x_i*x_j*x_k term
 /// with i > j > k is element (i, j, k), for the x_i*x_j term with i > j 
 /// it is element (i, j, j) and for the x_i term is is element (i, i, i).
-fn find_phase_polynomial(a: &nd::Array2<bool>) -> nd::Array3<usize> {
+pub fn find_phase_polynomial(a: &nd::Array2<bool>) -> nd::Array3<usize> {
     let n = a.shape()[0];
     let r = a.shape()[1];
 
@@ -65,6 +411,51 @@ fn find_phase_polynomial(a: &nd::Array2<bool>) -> nd::Array3<usize> {
     s
 }
 
+/// Write a phase polynomial from `find_phase_polynomial` as a sum of terms like
+/// `3·x0·x1·x2 + 2·x0·x1 + x3`, cubic terms first, where row i is the variable `x{map[i]}`.
+pub fn format_phase_polynomial(s: &nd::Array3<usize>, map: &[usize]) -> String {
+    let n = s.shape()[0];
+    let mut terms = Vec::new();
+    let mut term = |coeff: usize, vars: &[usize]| {
+        if coeff % 8 == 0 { return }
+        let vars = vars.iter().map(|&v| format!("x{}", map[v])).collect::<Vec<_>>().join("·");
+        terms.push(if coeff % 8 == 1 { vars } else { format!("{}·{}", coeff % 8, vars) });
+    };
+
+    for i in 0..n {
+        for j in 0..i {
+            for k in 0..j {
+                term(s[(i, j, k)], &[k, j, i]);
+            }
+        }
+    }
+    for i in 0..n {
+        for j in 0..i {
+            term(s[(i, j, j)], &[j, i]);
+        }
+    }
+    for i in 0..n {
+        term(s[(i, i, i)], &[i]);
+    }
+
+    if terms.is_empty() {
+        String::from("0")
+    } else {
+        terms.join(" + ")
+    }
+}
+
+/// Check whether two gate synthesis matrices implement exactly the same diagonal unitary,
+/// by comparing their phase polynomials mod 8. Unlike comparing signature tensors, this
+/// does not allow the two to differ by a Clifford. Both matrices share the qubit mapping `map`.
+pub fn matrices_equivalent(a: &nd::Array2<bool>, b: &nd::Array2<bool>, map: &[usize]) -> bool {
+    if a.shape()[0] != b.shape()[0] || a.shape()[0] != map.len() {
+        return false
+    }
+
+    find_phase_polynomial(a) == find_phase_polynomial(b)
+}
+
 /// Find a clifford circuit C such that C*U(a) = U(b) for a and b such that
 /// S(a) = S(b) (mod 2) and where U(x) is a diagonal CNOT+T unitary implementing 
 /// the gate synthesis matrix x. This function does NOT verify that S(a) = S(b) (mod 2).
@@ -91,11 +482,70 @@ pub fn clifford_correction(a: &nd::Array2<bool>, b: &nd::Array2<bool>, map: &[us
         }
     }
 
-    Circuit { gates }
+    Circuit::new(gates)
 }
 
 
 impl Circuit {
+    /// If this is a CNOT + Phase circuit, find its linear part and its phase polynomial mod 8
+    /// (as in `find_phase_polynomial`), both over all qubits of the circuit. Together these
+    /// determine the unitary exactly, up to a global phase. Returns None for any other circuit.
+    pub fn cnot_phase_form(&self) -> Option<(nd::Array2<bool>, nd::Array3<usize>)> {
+        let n = self.qubits();
+        let mut parities = nd::Array2::from_shape_fn((n, n), |(i, j)| i == j);
+        let mut columns = Vec::new();
+        for &gate in &self.gates {
+            match gate {
+                Gate::CNOT(Qubit(a), Qubit(b)) => {
+                    let (row_a, mut row_b) = parities.multi_slice_mut((nd::s![a, ..], nd::s![b, ..]));
+                    row_b ^= &row_a;
+                },
+                // A phase of p is p T gates on the same parity
+                Gate::Phase(Phase(p), Qubit(q)) => {
+                    for _ in 0..p {
+                        columns.push(parities.row(q).to_owned());
+                    }
+                },
+                Gate::I(_) => (),
+                _ => return None
+            }
+        }
+
+        let views = columns.iter().map(|c| c.view()).collect::<Vec<_>>();
+        let matrix = nd::stack(nd::Axis(1), &views)
+            .unwrap_or_else(|_| nd::Array2::from_elem((n, 0), false));
+        Some((parities, find_phase_polynomial(&matrix)))
+    }
+
+    /// Construct the signature tensor of a CNOT + Phase circuit directly, without
+    /// building its gate synthesis matrix. Rows correspond to all qubits of the circuit.
+    pub fn signature_tensor(&self) -> nd::Array3<bool> {
+        let n = self.qubits();
+        let mut parities = nd::Array2::from_shape_fn((n, n), |(i, j)| i == j);
+        let mut tensor = nd::Array3::from_elem((n, n, n), false);
+        for &gate in &self.gates {
+            match gate {
+                Gate::CNOT(Qubit(a), Qubit(b)) => {
+                    let (row_a, mut row_b) = parities.multi_slice_mut((nd::s![a, ..], nd::s![b, ..]));
+                    row_b ^= &row_a;
+                },
+                Gate::Phase(p, Qubit(q)) if !p.is_clifford() => {
+                    let parity = parities.row(q);
+                    let support = (0..n).filter(|&i| parity[i]).collect::<Vec<_>>();
+                    for &i in &support {
+                        for &j in &support {
+                            for &k in &support {
+                                tensor[(i, j, k)] ^= true;
+                            }
+                        }
+                    }
+                },
+                _ => ()
+            }
+        }
+        tensor
+    }
+
     fn try_synth_ccz_gadget(&mut self, cols: nd::ArrayView2<'_, bool>, map: &[usize]) -> bool {
         let a = cols.slice(nd::s![.., 0]).to_owned();
         let b = cols.slice(nd::s![.., 1]).to_owned();
@@ -105,7 +555,8 @@ impl Circuit {
             cols.slice(nd::s![.., 5]), cols.slice(nd::s![.., 6])
         ];
         // If a, b, c are not linearly independent
-        if a == b || a == c || b == c || a.iter().zip(&b).zip(&c)
+        let zero = |x: &nd::Array1<bool>| x.iter().all(|&v| !v);
+        if zero(&a) || zero(&b) || zero(&c) || a == b || a == c || b == c || a.iter().zip(&b).zip(&c)
             .all(|((&aa, &bb), &cc)| aa ^ bb == cc) {
             return false
         }
@@ -186,7 +637,7 @@ impl Circuit {
         let c = cols.slice(nd::s![.., 2]);
 
         // If a, b are not linearly independent
-        if a == b {
+        if a == b || a.iter().all(|&v| !v) || b.iter().all(|&v| !v) {
             return false
         }
 
@@ -231,7 +682,8 @@ impl Circuit {
         true
     }
 
-    pub fn synth_gadget(&mut self, col: nd::ArrayView1<'_, bool>, map: &[usize]) {
+    /// Synthesize a phase gadget applying `phase` to the parity `col`.
+    pub fn synth_gadget(&mut self, col: nd::ArrayView1<'_, bool>, map: &[usize], phase: Phase) {
         let Some(t) = col.iter().position(|&v| v) else { return };
 
         for (i, &v) in col.iter().enumerate() {
@@ -242,7 +694,7 @@ impl Circuit {
             }
         }
 
-        self.gates.push(Gate::Phase(Phase::T, Qubit(map[t])));
+        self.gates.push(Gate::Phase(phase, Qubit(map[t])));
 
         for (i, &v) in col.iter().enumerate().rev() {
             if i != t && v {
@@ -252,39 +704,322 @@ impl Circuit {
             }
         }
     }
+
+    /// Synthesize a phase gadget with its phase gate placed on the qubit `target`. If `target` is
+    /// not one of the qubits in `map`, it is treated as an ancilla that starts and ends in |0>,
+    /// so it must be above all of them to not collide with a qubit of the circuit.
+    pub fn synth_gadget_at(&mut self, col: nd::ArrayView1<'_, bool>, map: &[usize], target: usize, phase: Phase) {
+        assert!(is_valid_target(map, target), "Target qubit {} is neither in the mapping nor above all of its qubits", target);
+        let Some(s) = col.iter().position(|&v| v) else { return };
+
+        let Some(t) = map.iter().position(|&m| m == target) else {
+            // Copy the parity onto the ancilla and back
+            for (i, &v) in col.iter().enumerate() {
+                if v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(target))); }
+            }
+            self.gates.push(Gate::Phase(phase, Qubit(target)));
+            for (i, &v) in col.iter().enumerate().rev() {
+                if v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(target))); }
+            }
+            return
+        };
+
+        if col[t] {
+            // The target is in the parity, so we can compute the parity onto it directly
+            for (i, &v) in col.iter().enumerate() {
+                if i != t && v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(target))); }
+            }
+            self.gates.push(Gate::Phase(phase, Qubit(target)));
+            for (i, &v) in col.iter().enumerate().rev() {
+                if i != t && v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(target))); }
+            }
+        } else {
+            // Otherwise compute the parity as usual and swap it onto the target
+            for (i, &v) in col.iter().enumerate() {
+                if i != s && v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(map[s]))); }
+            }
+            self.gates.push(Gate::SWAP(Qubit(map[s]), Qubit(target)));
+            self.gates.push(Gate::Phase(phase, Qubit(target)));
+            self.gates.push(Gate::SWAP(Qubit(map[s]), Qubit(target)));
+            for (i, &v) in col.iter().enumerate().rev() {
+                if i != s && v { self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(map[s]))); }
+            }
+        }
+    }
+
+    /// Synthesize a sequence of phase gadgets, cancelling the CNOTs shared between
+    /// consecutive gadgets with the same target qubit.
+    pub fn synth_gadgets(&mut self, cols: &[(nd::ArrayView1<'_, bool>, Phase)], map: &[usize]) {
+        // The target qubit and the parity currently computed onto it
+        let mut open: Option<(usize, nd::ArrayView1<'_, bool>)> = None;
+        for &(col, phase) in cols {
+            let Some(t) = col.iter().position(|&v| v) else { continue };
+
+            match open {
+                Some((pt, parity)) if pt == t => {
+                    // All these CNOTs share a target so they commute, only the difference is needed
+                    for (i, (&v, &w)) in parity.iter().zip(&col).enumerate() {
+                        if i != t && v != w {
+                            self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(map[t])));
+                        }
+                    }
+                },
+                _ => {
+                    if let Some((pt, parity)) = open {
+                        self.close_gadget(parity, pt, map);
+                    }
+
+                    for (i, &v) in col.iter().enumerate() {
+                        if i != t && v {
+                            self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(map[t])));
+                        }
+                    }
+                }
+            }
+
+            self.gates.push(Gate::Phase(phase, Qubit(map[t])));
+            open = Some((t, col));
+        }
+
+        if let Some((pt, parity)) = open {
+            self.close_gadget(parity, pt, map);
+        }
+    }
+
+    fn close_gadget(&mut self, parity: nd::ArrayView1<'_, bool>, t: usize, map: &[usize]) {
+        for (i, &v) in parity.iter().enumerate().rev() {
+            if i != t && v {
+                self.gates.push(Gate::CNOT(Qubit(map[i]), Qubit(map[t])));
+            }
+        }
+    }
 }
 
-pub fn extract_gadgets(a: &nd::Array2<bool>, map: &[usize], gadgets: bool) -> (Circuit, usize, usize, usize) {
+/// Find an order in which to emit the given T gadgets.
+fn order_gadgets(cols: &[nd::ArrayView1<'_, bool>], order: GadgetOrder) -> Vec<usize> {
+    let target = |l: usize| cols[l].iter().position(|&v| v).unwrap_or(usize::MAX);
+    let weight = |l: usize| cols[l].iter().filter(|&&v| v).count();
+
+    let mut idxs = (0..cols.len()).collect::<Vec<_>>();
+    match order {
+        GadgetOrder::None => (),
+        GadgetOrder::SortByParity => {
+            idxs.sort_by_key(|&l| (target(l), cols[l].to_vec()));
+        },
+        GadgetOrder::GreedySharedPrefix => {
+            let mut ordered = Vec::new();
+            while !idxs.is_empty() {
+                let next = match ordered.last() {
+                    None => 0,
+                    Some(&prev) => (0..idxs.len()).min_by_key(|&m| {
+                        let l = idxs[m];
+                        if target(l) == target(prev) {
+                            cols[l].iter().zip(&cols[prev]).filter(|(v, w)| v != w).count()
+                        } else {
+                            (weight(l) + weight(prev)).saturating_sub(2)
+                        }
+                    }).unwrap()
+                };
+                ordered.push(idxs.remove(next));
+            }
+            idxs = ordered;
+        }
+    }
+    idxs
+}
+
+/// The relative costs of the gates used to synthesize a gate synthesis matrix.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct GadgetCosts {
+    pub ccz: f64,
+    pub cs: f64,
+    pub t: f64
+}
+
+impl Default for GadgetCosts {
+    fn default() -> Self {
+        GadgetCosts { ccz: 4.0, cs: 2.0, t: 1.0 }
+    }
+}
+
+/// Check that `target` can hold the phase gates of the gadgets synthesized with `map`: it is
+/// either one of the mapped qubits, or an ancilla above all of them.
+pub fn is_valid_target(map: &[usize], target: usize) -> bool {
+    map.contains(&target) || map.iter().all(|&m| m < target)
+}
+
+/// Synthesize a circuit from a gate synthesis matrix. If `costs` is given, windows of
+/// columns forming CCZ and CS gadgets are synthesized as CCZ and CS gates, choosing the
+/// set of gadgets that minimizes the total cost. Otherwise, only T gates are used.
+/// If `target` is given, all T gates are placed on that qubit.
+pub fn extract_gadgets(a: &nd::Array2<bool>, map: &[usize], costs: Option<GadgetCosts>, order: GadgetOrder, target: Option<usize>) -> (Circuit, usize, usize, usize) {
+    extract_signed_gadgets(a, &vec![false; a.shape()[1]], map, costs, order, target)
+}
+
+/// As `extract_gadgets`, but columns with `signs[l] = true` represent T-dagger gadgets rather
+/// than T gadgets. CCZ and CS gadgets are only recognized in windows of unsigned columns.
+pub fn extract_signed_gadgets(a: &nd::Array2<bool>, signs: &[bool], map: &[usize], costs: Option<GadgetCosts>, order: GadgetOrder, target: Option<usize>) -> (Circuit, usize, usize, usize) {
     let cols = a.shape()[1];
-    let mut circuit = Circuit { gates: Vec::new() };
+    let phase = |l: usize| if signs[l] { -Phase::T } else { Phase::T };
+    let mut circuit = Circuit::new(Vec::new());
+    let mut singles = Vec::new();
     let mut nccz = 0;
     let mut ncs = 0;
     let mut nt = 0;
 
+    // Find the synthesized gadget (if any) and its width for each starting column
+    let mut ccz = vec![None; cols];
+    let mut cs = vec![None; cols];
+    if costs.is_some() {
+        for idx in 0..cols {
+            let mut gadget = Circuit::new(Vec::new());
+            if idx + 7 <= cols && !signs[idx..idx+7].contains(&true) && gadget.try_synth_ccz_gadget(a.slice(nd::s![.., idx..idx+7]), map) {
+                ccz[idx] = Some(gadget);
+            }
+
+            let mut gadget = Circuit::new(Vec::new());
+            if idx + 3 <= cols && !signs[idx..idx+3].contains(&true) && gadget.try_synth_cs_gadget(a.slice(nd::s![.., idx..idx+3]), map) {
+                cs[idx] = Some(gadget);
+            }
+        }
+    }
+
+    // Pick the cheapest way to cover the columns, preferring gadgets on ties
+    let costs = costs.unwrap_or_default();
+    let mut best = vec![0.0; cols + 1];
+    let mut width = vec![1; cols];
+    for idx in (0..cols).rev() {
+        best[idx] = costs.t + best[idx + 1];
+        if cs[idx].is_some() && costs.cs + best[idx + 3] <= best[idx] {
+            best[idx] = costs.cs + best[idx + 3];
+            width[idx] = 3;
+        }
+        if ccz[idx].is_some() && costs.ccz + best[idx + 7] <= best[idx] {
+            best[idx] = costs.ccz + best[idx + 7];
+            width[idx] = 7;
+        }
+    }
+
     let mut idx = 0;
     while idx < cols {
-        if gadgets {
-            if idx + 7 <= cols && circuit.try_synth_ccz_gadget(a.slice(nd::s![.., idx..idx+7]), map)  {
-                idx += 7;
+        match width[idx] {
+            7 => {
+                circuit.merge(ccz[idx].take().unwrap());
                 nccz += 1;
-                continue
-            }
-
-            if idx + 3 <= cols && circuit.try_synth_cs_gadget(a.slice(nd::s![.., idx..idx+3]), map) {
-                idx += 3;
+            },
+            3 => {
+                circuit.merge(cs[idx].take().unwrap());
                 ncs += 1;
-                continue
+            },
+            _ => {
+                if let (GadgetOrder::None, Some(target)) = (order, target) {
+                    circuit.synth_gadget_at(a.column(idx), map, target, phase(idx));
+                } else if order == GadgetOrder::None {
+                    circuit.synth_gadget(a.column(idx), map, phase(idx));
+                } else {
+                    singles.push((a.column(idx), phase(idx)));
+                }
+                nt += 1;
             }
         }
+        idx += width[idx];
+    }
 
-        circuit.synth_gadget(a.slice(nd::s![.., idx]), map);
-        idx += 1;
-        nt += 1;
+    // The T gadgets are diagonal so they commute with everything else and can be reordered
+    if !singles.is_empty() {
+        let views = singles.iter().map(|&(col, _)| col).collect::<Vec<_>>();
+        let ordered = order_gadgets(&views, order)
+            .into_iter()
+            .map(|l| singles[l])
+            .collect::<Vec<_>>();
+        if let Some(target) = target {
+            for (col, phase) in ordered {
+                circuit.synth_gadget_at(col, map, target, phase);
+            }
+        } else {
+            circuit.synth_gadgets(&ordered, map);
+        }
     }
 
     (circuit, nccz, ncs, nt)
 }
 
+/// Expand a signed gate synthesis matrix into an unsigned one implementing the same diagonal
+/// unitary, by repeating each T-dagger column seven times.
+pub fn expand_signs(a: &nd::Array2<bool>, signs: &[bool]) -> nd::Array2<bool> {
+    let cols = (0..a.shape()[1])
+        .flat_map(|l| std::iter::repeat(l).take(if signs[l] { 7 } else { 1 }))
+        .collect::<Vec<_>>();
+    a.select(nd::Axis(1), &cols)
+}
+
+/// Reorder the columns of a gate synthesis matrix so that any CCZ or CS gadgets found
+/// within a sliding window of `window` columns are laid out contiguously in the order
+/// expected by `extract_gadgets`. The columns commute, so this preserves the phase polynomial.
+pub fn group_gadget_columns(a: &nd::Array2<bool>, window: usize) -> nd::Array2<bool> {
+    a.select(nd::Axis(1), &gadget_column_order(a, window))
+}
+
+/// The column order used by `group_gadget_columns`.
+pub fn gadget_column_order(a: &nd::Array2<bool>, window: usize) -> Vec<usize> {
+    let col = |i: usize| a.column(i);
+    let xor = |cols: &[usize]| {
+        let mut sum = nd::Array1::from_elem(a.shape()[0], false);
+        for &i in cols {
+            sum.zip_mut_with(&col(i), |x, &y| *x ^= y);
+        }
+        sum
+    };
+
+    let mut remaining = (0..a.shape()[1]).collect::<Vec<_>>();
+    let mut order = Vec::new();
+    while !remaining.is_empty() {
+        let w = &remaining[..window.max(1).min(remaining.len())];
+        let first = w[0];
+        // Find the column in the window (other than those already used) equal to a parity
+        let find = |target: &nd::Array1<bool>, used: &[usize]| {
+            w.iter().copied().find(|&i| !used.contains(&i) && col(i) == target.view())
+        };
+
+        let mut found = None;
+        'ccz: for (x, &b) in w.iter().enumerate().skip(1) {
+            for &c in &w[x + 1..] {
+                let mut used = vec![first, b, c];
+                let (ab, ac, bc) = (xor(&[first, b]), xor(&[first, c]), xor(&[b, c]));
+                if col(first) == col(b) || col(first) == col(c) || col(b) == col(c) || ab == col(c) {
+                    continue
+                }
+                for parity in [ab, ac, bc, xor(&[first, b, c])] {
+                    match find(&parity, &used) {
+                        Some(i) => used.push(i),
+                        None => continue 'ccz
+                    }
+                }
+                found = Some(used);
+                break 'ccz
+            }
+        }
+
+        if found.is_none() {
+            for &b in &w[1..] {
+                if col(first) == col(b) {
+                    continue
+                }
+                if let Some(c) = find(&xor(&[first, b]), &[first, b]) {
+                    found = Some(vec![first, b, c]);
+                    break
+                }
+            }
+        }
+
+        let group = found.unwrap_or_else(|| vec![first]);
+        remaining.retain(|i| !group.contains(i));
+        order.extend(group);
+    }
+
+    order
+}
+
 pub fn has_zero_columns(a: &nd::Array2<bool>) -> bool {
     a.columns().into_iter().any(|col| col.iter().all(|&v| v == false))
 }
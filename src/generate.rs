@@ -0,0 +1,101 @@
+use clap::{Parser, ValueEnum};
+use rand::{Rng, SeedableRng};
+use std::{io::Write, path::PathBuf};
+use crate::circuit::{Circuit, Gate, Phase, Qubit};
+
+fn valid_directory(s: &str) -> Result<PathBuf, String> {
+    match std::fs::metadata(s) {
+        Ok(meta) => if meta.is_dir() {
+            Ok(PathBuf::from(s))
+        } else {
+            Err(String::from("The output path must be a directory"))
+        },
+        Err(e) => Err(format!("{}", e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+enum OutputType {
+    #[value(help = "Circuit in qasm format")]
+    CircuitQASM,
+    #[value(help = "Circuit in qc format")]
+    CircuitQC
+}
+
+#[derive(Debug, Clone, Parser)]
+#[clap(version, about = "Generate random Clifford+T circuits for benchmarking")]
+pub struct Args {
+    #[clap(short, long, default_value = "circuit-qasm", use_value_delimiter = true, help = "Formats to write each circuit in")]
+    emit: Vec<OutputType>,
+    #[clap(short, long, help = "Number of qubits in each circuit")]
+    qubits: usize,
+    #[clap(short, long, help = "Number of gates in each circuit")]
+    gates: usize,
+    #[clap(long, default_value_t = 0.1, help = "Probability of each gate being a Hadamard")]
+    p_h: f32,
+    #[clap(long, default_value_t = 0.4, help = "Probability of each gate being a phase gate, the rest are CNOTs")]
+    p_phase: f32,
+    #[clap(short = 'n', long, default_value_t = 10, help = "Number of circuits to generate")]
+    count: usize,
+    #[clap(short, long, help = "Seed for the random number generator, chosen randomly and printed if not given")]
+    seed: Option<u64>,
+    #[clap(long, default_value = "random", help = "Prefix of the generated file names")]
+    prefix: String,
+    #[clap(help = "Directory to place the generated circuits", value_parser = valid_directory)]
+    output: PathBuf
+}
+
+/// Generate a random CNOT + H + Phase circuit on q qubits with g gates, where each gate is
+/// a Hadamard with probability p_h, a phase with probability p_phase, and otherwise a CNOT.
+/// The same generator state always gives the same circuit.
+pub fn random_circuit(q: usize, g: usize, p_h: f32, p_phase: f32, rng: &mut impl Rng) -> Circuit {
+    let mut gates = Vec::new();
+    for _ in 0..g {
+        let r = rng.gen::<f32>();
+        if r < p_h {
+            gates.push(Gate::H(Qubit(rng.gen_range(0..q))));
+        } else if r < p_h + p_phase {
+            gates.push(Gate::Phase(Phase(rng.gen_range(0..8)), Qubit(rng.gen_range(0..q))));
+        } else {
+            let (i, j) = loop { let (i, j) = (rng.gen_range(0..q), rng.gen_range(0..q)); if i == j { continue } else { break (i, j) } };
+            gates.push(Gate::CNOT(Qubit(i), Qubit(j)));
+        }
+    }
+    Circuit::new(gates)
+}
+
+pub fn main(args: Args) {
+    if args.qubits < 2 {
+        eprintln!("At least two qubits are needed to place CNOTs");
+        std::process::exit(1);
+    }
+    if args.p_h < 0.0 || args.p_phase < 0.0 || args.p_h + args.p_phase > 1.0 {
+        eprintln!("The gate probabilities must be non-negative and sum to at most 1");
+        std::process::exit(1);
+    }
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    if args.seed.is_none() {
+        println!("Using seed {}", seed);
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+
+    // Pad the numbers so that the files sort in the order they were generated
+    let width = args.count.saturating_sub(1).to_string().len();
+    for i in 0..args.count {
+        let circuit = random_circuit(args.qubits, args.gates, args.p_h, args.p_phase, &mut rng);
+        for &emit in &args.emit {
+            let (extension, source) = match emit {
+                OutputType::CircuitQASM => ("qasm", circuit.to_openqasm(false)),
+                OutputType::CircuitQC => ("qc", circuit.to_qc(args.qubits))
+            };
+            let path = args.output.join(format!("{}{:0width$}.{}", args.prefix, i, extension));
+            let mut file = std::fs::File::create(&path)
+                .expect("Couldn't open output file!");
+            write!(file, "{}", source)
+                .expect("Couldn't write to output file!");
+        }
+    }
+
+    println!("Wrote {} circuits to {}", args.count, args.output.display());
+}
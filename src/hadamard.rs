@@ -1,4 +1,15 @@
 use crate::circuit::{Gate, Circuit, Qubit, Phase};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// The basis in which Hadamard gadget ancillas are prepared and postselected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+pub enum AncillaBasis {
+    #[value(help = "Prepare and postselect in |0>, using Hadamards to create |+>")]
+    Zero,
+    #[value(help = "Prepare and postselect in |+> directly")]
+    Plus
+}
 
 impl Circuit {
     pub fn hcount_accurate(&self) -> usize {
@@ -9,15 +20,13 @@ impl Circuit {
 
     /// Count all hadamards in the circuit (even non-obstructed)
     pub fn count_hadamards(&self) -> usize {
-        self.gates
-            .iter()
-            .filter(|g| matches!(g, Gate::H(_)))
-            .count()
+        self.count_gates(|g| matches!(g, Gate::H(_)))
     }
 
     /// Decompose a hadamard gate at the given index into an ancilla
-    /// requires access to the front and back clifford blocks, and qubit id counter
-    fn decomp_had(&mut self, idx: usize, next_id: &mut usize, front: &mut Circuit, back: &mut Circuit) {
+    /// requires access to the front and back clifford blocks, and qubit id counter.
+    /// Qubits in `reserved` are never allocated as ancillas.
+    fn decomp_had(&mut self, idx: usize, next_id: &mut usize, front: &mut Circuit, back: &mut Circuit, basis: AncillaBasis, reserved: &HashSet<usize>) {
         let q = if let Gate::H(q) = self.gates[idx] {
             q
         } else {
@@ -25,6 +34,9 @@ impl Circuit {
         };
 
         // Allocate a new qubit
+        while reserved.contains(next_id) {
+            *next_id += 1;
+        }
         let n = Qubit(*next_id);
         *next_id += 1;
 
@@ -32,17 +44,22 @@ impl Circuit {
         self.gates[idx] = Gate::SWAP(n, q);
         self.gates.insert(idx + 1, Gate::CZ(n, q));
         // This must be prepared and post-selected in the plus state
-        // but this ancilla is only used once so we can do this in front and back
-        front.gates.push(Gate::H(n));
-        back.gates.push(Gate::H(n));
+        // but this ancilla is only used once so we can do this in front and back.
+        // There is no unitary uncompute here: n now holds the Z-basis value of q, which
+        // the rest of the block entangles with, so only postselection (or measurement
+        // plus a Clifford correction) can return it to |0> without another Hadamard.
+        if basis == AncillaBasis::Zero {
+            front.gates.push(Gate::H(n));
+            back.gates.push(Gate::H(n));
+        }
     }
 
     /// Decompose all hadamards in this block as ancilla
-    pub fn decomp_hads(&mut self, next_id: &mut usize, front: &mut Circuit, back: &mut Circuit) {
+    pub fn decomp_hads(&mut self, next_id: &mut usize, front: &mut Circuit, back: &mut Circuit, basis: AncillaBasis, reserved: &HashSet<usize>) {
         'outer: loop {
             for i in 0..self.gates.len() {
                 if matches!(self.gates[i], Gate::H(_)) {
-                    self.decomp_had(i, next_id, front, back);
+                    self.decomp_had(i, next_id, front, back, basis, reserved);
                     continue 'outer;
                 }
             }
@@ -53,9 +70,7 @@ impl Circuit {
     /// Count the number of T gates required to implement this circuit.
     /// (Counts the number of non-Clifford phase gates)
     pub fn tcount(&self) -> usize {
-        self.gates.iter()
-            .filter(|g| matches!(g, Gate::Phase(p, _) if !p.is_clifford()))
-            .count()
+        self.count_gates(|g| matches!(g, Gate::Phase(p, _) if !p.is_clifford()))
     }
 
     /// Minimize the number of H gates in the circuit using the routine of Vandaele et al [arXiv:2302.07040]
@@ -107,6 +122,7 @@ impl Circuit {
                     circ.circ.push(("cx".into(), vec![a.0, b.0]));
                 },
                 Gate::H(q) => circ.circ.push(("h".into(), vec![q.0])),
+                Gate::I(_) => (),
                 Gate::CCZ(a, b, c) => circ.circ.push(("ccz".into(), vec![a.0, b.0, c.0])),
                 Gate::SWAP(a, b) => {
                     circ.circ.push(("cx".into(), vec![a.0, b.0]));
@@ -118,6 +134,7 @@ impl Circuit {
 
         let optimized = vandaele_et_al::h_opt::internal_h_opt(&circ);
         self.gates.clear();
+        self.hoist_comments();
         for (name, qs) in optimized.circ {
             match name.as_str() {
                 "z" => self.gates.push(Gate::Phase(Phase::Z, Qubit(qs[0]))),
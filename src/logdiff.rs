@@ -0,0 +1,152 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Parser)]
+#[clap(version, about = "Compare the logfiles of two compile runs and report regressions")]
+pub struct Args {
+    #[clap(long, help = "Print the comparison as JSON instead of a table")]
+    json: bool,
+    #[clap(long, help = "Only show circuits that got worse")]
+    regressions_only: bool,
+    #[clap(required = true, help = "Logfile of the baseline run")]
+    before: PathBuf,
+    #[clap(required = true, help = "Logfile of the new run")]
+    after: PathBuf
+}
+
+// Only the parts of the compile logfile that we compare, anything else is ignored
+
+#[derive(Debug, Deserialize)]
+struct Logfile {
+    files: Vec<FileStats>
+}
+
+#[derive(Debug, Deserialize)]
+struct FileStats {
+    path: PathBuf,
+    tcount: TCountStats,
+    hcount: HCountStats,
+    twoqubit: TwoQubitStats,
+    blocks: Vec<serde::de::IgnoredAny>
+}
+
+#[derive(Debug, Deserialize)]
+struct TCountStats {
+    gadgetized: usize
+}
+
+#[derive(Debug, Deserialize)]
+struct HCountStats {
+    optimized: usize
+}
+
+#[derive(Debug, Deserialize)]
+struct TwoQubitStats {
+    compiled: TwoQubitCounts
+}
+
+#[derive(Debug, Deserialize)]
+struct TwoQubitCounts {
+    cnot: usize
+}
+
+#[derive(Debug, Serialize)]
+struct Delta {
+    before: usize,
+    after: usize,
+    delta: isize
+}
+
+impl Delta {
+    fn new(before: usize, after: usize) -> Delta {
+        Delta { before, after, delta: after as isize - before as isize }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileDiff {
+    path: PathBuf,
+    tcount: Delta,
+    hcount: Delta,
+    blocks: Delta,
+    cnot: Delta,
+    regression: bool
+}
+
+#[derive(Debug, Serialize)]
+struct LogDiff {
+    files: Vec<FileDiff>,
+    /// Circuits that only appear in one of the two runs
+    only_before: Vec<PathBuf>,
+    only_after: Vec<PathBuf>
+}
+
+fn read_logfile(path: &PathBuf) -> Logfile {
+    let file = std::fs::File::open(path)
+        .expect("Couldn't open logfile!");
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .expect("Couldn't parse logfile!")
+}
+
+fn diff(before: Logfile, after: Logfile) -> LogDiff {
+    let mut files = Vec::new();
+    let mut only_after = Vec::new();
+    let mut before = before.files;
+    for new in after.files {
+        let Some(i) = before.iter().position(|old| old.path == new.path) else {
+            only_after.push(new.path);
+            continue
+        };
+        let old = before.remove(i);
+
+        let tcount = Delta::new(old.tcount.gadgetized, new.tcount.gadgetized);
+        let hcount = Delta::new(old.hcount.optimized, new.hcount.optimized);
+        let blocks = Delta::new(old.blocks.len(), new.blocks.len());
+        let cnot = Delta::new(old.twoqubit.compiled.cnot, new.twoqubit.compiled.cnot);
+        let regression = [&tcount, &hcount, &blocks, &cnot].iter().any(|d| d.delta > 0);
+        files.push(FileDiff { path: new.path, tcount, hcount, blocks, cnot, regression });
+    }
+
+    LogDiff {
+        files,
+        only_before: before.into_iter().map(|old| old.path).collect(),
+        only_after
+    }
+}
+
+pub fn main(args: Args) {
+    let mut result = diff(read_logfile(&args.before), read_logfile(&args.after));
+    if args.regressions_only {
+        result.files.retain(|f| f.regression);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+        return
+    }
+
+    let delta = |d: &Delta| format!("{} -> {} ({:+})", d.before, d.after, d.delta);
+    println!("{:<40} {:>20} {:>20} {:>20} {:>20}", "circuit", "tcount", "hcount", "blocks", "cnot");
+    for f in &result.files {
+        let name = f.path.file_name().map_or_else(|| f.path.display().to_string(), |n| n.to_string_lossy().to_string());
+        println!(
+            "{:<40} {:>20} {:>20} {:>20} {:>20}{}",
+            name,
+            delta(&f.tcount),
+            delta(&f.hcount),
+            delta(&f.blocks),
+            delta(&f.cnot),
+            if f.regression { "  REGRESSION" } else { "" }
+        );
+    }
+
+    let regressions = result.files.iter().filter(|f| f.regression).count();
+    println!("\n{} circuits compared, {} regressions", result.files.len(), regressions);
+    for path in &result.only_before {
+        println!("Only in {}: {}", args.before.display(), path.display());
+    }
+    for path in &result.only_after {
+        println!("Only in {}: {}", args.after.display(), path.display());
+    }
+}
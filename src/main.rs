@@ -1,12 +1,17 @@
 use clap::Parser;
 
 mod circuit;
+mod decomposition;
 mod hadamard;
 mod decompose;
 mod extract;
+mod simulate;
+mod tableau;
 mod resynth;
 mod compile;
 mod verify;
+mod logdiff;
+mod generate;
 
 #[cfg(test)]
 mod tests;
@@ -17,13 +22,17 @@ mod tests;
 enum Args {
     Compile(compile::Args),
     Resynth(resynth::Args),
-    Verify(verify::Args)
+    Verify(verify::Args),
+    LogDiff(logdiff::Args),
+    Generate(generate::Args)
 }
 
 fn main() {
     match Args::parse() {
         Args::Compile(args) => compile::main(args),
         Args::Resynth(args) => resynth::main(args),
-        Args::Verify(args) => verify::main(args)
+        Args::Verify(args) => verify::main(args),
+        Args::LogDiff(args) => logdiff::main(args),
+        Args::Generate(args) => generate::main(args)
     }
 }
\ No newline at end of file
@@ -2,7 +2,7 @@ use clap::{ValueEnum, Parser, CommandFactory};
 use serde::Serialize;
 use std::{io::Write, path::{Path, PathBuf}};
 use ndarray as nd;
-use crate::extract;
+use crate::{circuit::Qubit, extract, simulate};
 
 fn valid_directory(s: &str) -> Result<PathBuf, String> {
     match std::fs::metadata(s) {
@@ -21,6 +21,8 @@ enum OutputType {
     CircuitQASM,
     #[value(help = "Synthesized circuit in qc format")]
     CircuitQC,
+    #[value(help = "Phase polynomial of the decomposition as text")]
+    Phasepoly,
     #[value(help = "Logfile with statistics about the circuit")]
     Log
 }
@@ -37,7 +39,17 @@ struct FileStats {
     mapping: Vec<usize>,
     nccz: usize,
     ncs: usize,
-    nt: usize
+    nt: usize,
+    original_tcount: Option<usize>,
+    /// The weighted cost of the synthesized gadgets over the cost of the original's T gates
+    cost_ratio: Option<f64>,
+    gadget_tcount: Option<usize>,
+    flat_tcount: Option<usize>,
+    gadgets_verified: Option<bool>,
+    /// The fraction of entries of the decomposition matrix that are set
+    density: f64,
+    /// Whether synthesis was skipped because the matrix was denser than `--max-matrix-density`
+    skipped: bool
 }
 
 #[derive(Debug, Clone, Parser, Serialize)]
@@ -47,10 +59,42 @@ pub struct Args {
     emit: Vec<OutputType>,
     #[clap(short, long, help = "Enable CCZ and CS gadget synthesis")]
     gadgets: bool,
+    #[clap(long, default_value_t = 4.0, help = "Relative cost of a CCZ gate when choosing gadgets")]
+    ccz_cost: f64,
+    #[clap(long, default_value_t = 2.0, help = "Relative cost of a CS gate when choosing gadgets")]
+    cs_cost: f64,
+    #[clap(long, default_value_t = 1.0, help = "Relative cost of a T gate when choosing gadgets")]
+    t_cost: f64,
+    #[clap(long, help = "Also synthesize with and without gadgets and check that the two circuits are equivalent before emitting")]
+    verify_gadgets: bool,
+    #[clap(long, default_value_t = 16, help = "Number of random inputs to try when verifying gadget synthesis")]
+    samples: usize,
+    #[clap(long, requires = "gadgets", help = "Search for CCZ and CS gadgets in windows of this many columns, rather than only contiguous ones")]
+    gadget_window: Option<usize>,
+    #[clap(long, value_enum, default_value = "none", help = "Order in which to emit the T gadgets")]
+    phase_gadget_order: extract::GadgetOrder,
+    #[clap(long, help = "Skip synthesizing decompositions with a larger fraction of set entries than this, recording them as skipped in the log")]
+    max_matrix_density: Option<f64>,
+    #[clap(long, help = "Place all T gates on this qubit, which must be in the mapping or above all of its qubits, in which case it is used as a |0> ancilla")]
+    target_qubit: Option<usize>,
+    #[clap(long, help = "Exhaustively search for a decomposition with the fewest columns for blocks with at most this many qubits")]
+    optimal_small: Option<usize>,
     #[clap(short = 'O', long, help = "Files containing the original circuit decomposition matrices")]
     original: Vec<String>,
+    #[clap(long, help = "Files containing the tensor each decomposition must reproduce, either dense or packed")]
+    tensor: Vec<String>,
+    #[clap(long, default_value_t = 0.5, help = "Threshold for rounding decompositions given as float matrices to booleans")]
+    threshold: f64,
+    #[clap(long, help = "Require decompositions to match the originals exactly, not just up to a Clifford")]
+    strict: bool,
+    #[clap(long, value_enum, default_value = "qubit-rows", help = "Layout of the decomposition and original matrices")]
+    matrix_orientation: extract::MatrixOrientation,
+    #[clap(long, help = "JSON array giving the output qubit of each synthesized circuit qubit, applied after the mapping")]
+    layout: Option<PathBuf>,
     #[clap(short, long, help = "Mapping files containing qubit mappings for each circuit")]
     mapping: Vec<String>,
+    #[clap(long, help = "Files containing a boolean vector for each circuit, marking the columns that are T-dagger rather than T gadgets")]
+    signs: Vec<String>,
     #[clap(help = "Directory to place any output files", value_parser = valid_directory)]
     output: PathBuf,
     #[clap(required = true, help = "List of .npy files containing decompositions to synthesize")]
@@ -74,6 +118,14 @@ impl Args {
             .collect()
     }
 
+    fn tensor(&self) -> Vec<PathBuf> {
+        self.tensor
+            .iter()
+            .filter_map(|path| PathBuf::try_from(path).ok())
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
     fn mapping(&self) -> Vec<PathBuf> {
         self.mapping
             .iter()
@@ -82,6 +134,14 @@ impl Args {
             .collect()
     }
 
+    fn signs(&self) -> Vec<PathBuf> {
+        self.signs
+            .iter()
+            .filter_map(|path| PathBuf::try_from(path).ok())
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
     fn output_path<P: AsRef<Path>>(&self, input: P, suffix: &str) -> PathBuf {
         let mut file_name = input.as_ref()
             .file_stem()
@@ -102,6 +162,40 @@ impl Args {
     }
 }
 
+/// Entries of a float decomposition further than this from both 0 and 1 are reported.
+const AMBIGUOUS_MARGIN: f64 = 0.1;
+
+/// Read a decomposition matrix, either boolean or as floats (e.g. from a relaxed solver)
+/// which are rounded to booleans at the threshold. For floats, also returns the number of
+/// entries further than `AMBIGUOUS_MARGIN` from both 0 and 1.
+fn read_matrix(path: &Path, threshold: f64) -> Option<(nd::Array2<bool>, Option<usize>)> {
+    if let Ok(matrix) = ndarray_npy::read_npy::<_, nd::Array2<bool>>(path) {
+        return Some((matrix, None))
+    }
+
+    let float = ndarray_npy::read_npy::<_, nd::Array2<f64>>(path).ok()
+        .or_else(|| ndarray_npy::read_npy::<_, nd::Array2<f32>>(path).ok().map(|m| m.mapv(f64::from)))?;
+    let ambiguous = float.iter()
+        .filter(|&&v| v.abs().min((v - 1.0).abs()) > AMBIGUOUS_MARGIN)
+        .count();
+    Some((float.mapv(|v| v >= threshold), Some(ambiguous)))
+}
+
+/// Read a layout file, which must be a JSON array holding a permutation of 0..n.
+fn read_layout(path: &Path) -> Result<Vec<usize>, String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read layout file `{}`: {}", path.display(), e))?;
+    let layout = serde_json::from_str::<Vec<usize>>(&source)
+        .map_err(|e| format!("Couldn't parse layout file `{}`: {}", path.display(), e))?;
+    let mut seen = vec![false; layout.len()];
+    for &q in &layout {
+        if q >= layout.len() || std::mem::replace(&mut seen[q], true) {
+            return Err(format!("Layout in `{}` is not a permutation of 0 to {}", path.display(), layout.len()))
+        }
+    }
+    Ok(layout)
+}
+
 fn with_message<T>(i: usize, count: usize, f: impl FnOnce(&indicatif::ProgressBar) -> T) -> T {
     let pb = indicatif::ProgressBar::new_spinner()
         .with_style(indicatif::ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}").unwrap());
@@ -167,23 +261,78 @@ pub fn main(args: Args) {
         original.into_iter().map(Some).collect()
     };
 
+    let signs = args.signs();
+    let signs = if signs.is_empty() {
+        vec![None; files.len()]
+    } else if signs.len() != files.len() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::InvalidValue, 
+                "A signs file must be provided for each input file"
+            )
+            .exit()
+    } else {
+        signs.into_iter().map(Some).collect()
+    };
+
+    let tensor = args.tensor();
+    let tensor = if tensor.is_empty() {
+        vec![None; files.len()]
+    } else if tensor.len() != files.len() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::InvalidValue, 
+                "A tensor file must be provided for each input file"
+            )
+            .exit()
+    } else {
+        tensor.into_iter().map(Some).collect()
+    };
+
+    let layout = args.layout.as_ref().map(|path| read_layout(path).unwrap_or_else(|e| {
+        Args::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit()
+    }));
+
     let mut logfile = Logfile { invocation: args.clone(), files: Vec::new() };
 
     let count = files.len();
     let mut values = Vec::new();
-    for (i, ((file, orig), map)) in files.into_iter().zip(original).zip(mapping).enumerate() {
+    for (i, ((((file, orig), map), signs), tensor)) in files.into_iter().zip(original).zip(mapping).zip(signs).zip(tensor).enumerate() {
         with_message(i, count, |pb| {
             pb.set_message("  Loading circuit...");
-            let Ok(matrix) = ndarray_npy::read_npy::<_, nd::Array2<bool>>(&file) else {
-                pb.set_message(format!("  Error - failed to load matrix from file `{}`, skipping", file.display()));
-                return
+            let (matrix, ambiguous) = match read_matrix(&file, args.threshold) {
+                Some((matrix, ambiguous)) => (args.matrix_orientation.orient(matrix), ambiguous),
+                // Signature tensors are factored into a matrix with qubit rows
+                None => match ndarray_npy::read_npy::<_, nd::Array3<bool>>(&file) {
+                    Ok(tensor) => {
+                        let Some(matrix) = extract::waring_decompose(&tensor) else {
+                            pb.set_message(format!("  Error - `{}` is not a valid signature tensor, skipping", file.display()));
+                            return
+                        };
+                        (matrix, None)
+                    },
+                    Err(_) => {
+                        pb.set_message(format!("  Error - failed to load matrix from file `{}`, skipping", file.display()));
+                        return
+                    }
+                }
             };
+            if let Some(ambiguous) = ambiguous.filter(|&n| n > 0) {
+                pb.println(format!("{} {}",
+                    console::style("Warning:").bold().yellow(),
+                    format!("{} entries of `{}` are more than {} from 0 or 1 before thresholding", ambiguous, file.display(), AMBIGUOUS_MARGIN)
+                ));
+            }
+            let thresholded = ambiguous.is_some();
 
             let orig = if let Some(orig) = orig {
                 let Ok(orig) = ndarray_npy::read_npy::<_, nd::Array2<bool>>(&orig) else {
                     pb.set_message(format!("  Error - failed to load matrix from file `{}`, skipping", orig.display()));
                     return
                 };
+                let orig = args.matrix_orientation.orient(orig);
 
                 if orig.shape()[0] != matrix.shape()[0] {
                     pb.set_message(format!("  Error - original decomposition for `{}` has the wrong shape, skipping", file.display()));
@@ -217,12 +366,45 @@ pub fn main(args: Args) {
                 (0..matrix.shape()[0]).collect::<Vec<_>>()
             };
 
-            values.push((file, matrix, orig, map));
+            let signs = if let Some(signs) = signs {
+                let Ok(signs) = ndarray_npy::read_npy::<_, nd::Array1<bool>>(&signs) else {
+                    pb.set_message(format!("  Error - failed to load signs from file `{}`, skipping", signs.display()));
+                    return
+                };
+
+                if signs.len() != matrix.shape()[1] {
+                    pb.set_message(format!("  Error - signs for `{}` have the wrong size, skipping", file.display()));
+                    return
+                }
+
+                signs.to_vec()
+            } else {
+                vec![false; matrix.shape()[1]]
+            };
+
+            // Tensors may be written densely or packed by compile
+            let tensor = if let Some(tensor) = tensor {
+                let dense = ndarray_npy::read_npy::<_, nd::Array3<bool>>(&tensor).ok();
+                let Some(tensor) = dense.or_else(|| ndarray_npy::read_npy::<_, nd::Array1<bool>>(&tensor).ok()
+                    .and_then(|packed| extract::unpack_symmetric_tensor(&packed))) else {
+                    pb.set_message(format!("  Error - failed to load tensor from file `{}`, skipping", tensor.display()));
+                    return
+                };
+
+                if tensor.shape()[0] != matrix.shape()[0] {
+                    pb.set_message(format!("  Error - tensor for `{}` has the wrong shape, skipping", file.display()));
+                    return
+                }
+
+                Some(tensor)
+            } else { None };
+
+            values.push((file, matrix, thresholded, orig, map, signs, tensor));
         });
     }
 
     let count = values.len();
-    for (i, (path, matrix, orig, map)) in values.into_iter().enumerate() {
+    for (i, (path, matrix, thresholded, orig, map, signs, tensor)) in values.into_iter().enumerate() {
         let mut filestats = FileStats::default();
         filestats.path = path.canonicalize()
             .expect("Couldn't canonicalize path");
@@ -233,27 +415,152 @@ pub fn main(args: Args) {
             continue
         }
 
+        // A mismatch after thresholding is most likely a rounding problem rather than a bad solution
+        let hint = if thresholded { " (after thresholding floats, try another --threshold)" } else { "" };
+        if let Some(tensor) = &tensor {
+            if extract::find_signature_tensor(&matrix) != *tensor {
+                put_message(i, count, format!("  Error - signature tensor of decomposition doesn't match the given tensor{}, skipping", hint));
+                continue
+            }
+        }
+
         if let Some(orig) = &orig {
             if extract::find_signature_tensor(&matrix) != extract::find_signature_tensor(orig) {
-                put_message(i, count, "  Error - signature tensors of decomposition and original don't match, skipping".into());
+                put_message(i, count, format!("  Error - signature tensors of decomposition and original don't match{}, skipping", hint));
+                continue
+            }
+
+            if args.strict && !extract::matrices_equivalent(&extract::expand_signs(&matrix, &signs), orig, &map) {
+                put_message(i, count, "  Error - phase polynomials of decomposition and original don't match, skipping".into());
                 continue
             }
         }
 
+        let (matrix, signs) = match args.optimal_small {
+            Some(max) if matrix.shape()[0] <= max => with_message(i, count, |pb| {
+                pb.set_message("  Searching for an optimal decomposition...");
+                match extract::optimal_decomposition(&matrix) {
+                    Some(optimal) if optimal.shape()[1] < matrix.shape()[1] => {
+                        pb.set_message(format!("  Found a smaller decomposition: {} => {} columns", matrix.shape()[1], optimal.shape()[1]));
+                        // The search only produces T gadgets
+                        let signs = vec![false; optimal.shape()[1]];
+                        (optimal, signs)
+                    },
+                    Some(_) => {
+                        pb.set_message("  The decomposition is already optimal");
+                        (matrix, signs)
+                    },
+                    None => {
+                        pb.set_message(format!("  Search for an optimal decomposition is too large (more than {} qubits or {} sets of columns), keeping the original", extract::MAX_OPTIMAL_QUBITS, extract::MAX_OPTIMAL_SUBSETS));
+                        (matrix, signs)
+                    }
+                }
+            }),
+            _ => (matrix, signs)
+        };
+
+        // Dense matrices take a long time to synthesize into circuits with many CNOTs
+        let entries = matrix.len().max(1);
+        filestats.density = matrix.iter().filter(|&&v| v).count() as f64 / entries as f64;
+        if let Some(max) = args.max_matrix_density.filter(|&max| filestats.density > max) {
+            put_message(i, count, format!("  Skipping - decomposition matrix density {:.3} is above {}", filestats.density, max));
+            filestats.skipped = true;
+            logfile.files.push(filestats);
+            continue
+        }
+
+        if let Some(target) = args.target_qubit.filter(|&target| !extract::is_valid_target(&map, target)) {
+            put_message(i, count, format!("  Error - target qubit {} is not in the mapping, but is below some of its qubits so it can't be an ancilla, skipping", target));
+            continue
+        }
+
+        let costs = extract::GadgetCosts {
+            ccz: args.ccz_cost,
+            cs: args.cs_cost,
+            t: args.t_cost
+        };
+        let synthesize = |costs: Option<extract::GadgetCosts>| {
+            let order = match (costs, args.gadget_window) {
+                (Some(_), Some(window)) => extract::gadget_column_order(&matrix, window),
+                _ => (0..matrix.shape()[1]).collect()
+            };
+            let grouped = matrix.select(nd::Axis(1), &order);
+            let signs = order.iter().map(|&l| signs[l]).collect::<Vec<_>>();
+            extract::extract_signed_gadgets(&grouped, &signs, &map, costs, args.phase_gadget_order, args.target_qubit)
+        };
+
         let mut circuit = with_message(i, count, |pb| {
             pb.set_message("  Synthesizing circuit...");
-            let (circuit, nccz, ncs, nt) = extract::extract_gadgets(&matrix, &map, args.gadgets);
+            let (circuit, nccz, ncs, nt) = synthesize(args.gadgets.then_some(costs));
             pb.set_message(format!("  Circuit synthesis complete - CCZ = {}, CS = {}, T = {}", nccz, ncs, nt));
             filestats.nccz = nccz;
             filestats.ncs = ncs;
             filestats.nt = nt;
             circuit
         });
-        
+
+        if args.verify_gadgets {
+            // Differential check of the CCZ and CS recognition: both syntheses implement the same
+            // phase polynomial, so they must agree whichever one is emitted
+            let verified = with_message(i, count, |pb| {
+                pb.set_message("  Verifying gadget synthesis against flat synthesis...");
+                let (gadget, nccz, ncs, nt) = if args.gadgets {
+                    (circuit.clone(), filestats.nccz, filestats.ncs, filestats.nt)
+                } else {
+                    synthesize(Some(costs))
+                };
+                let (flat, _, _, flat_tcount) = if args.gadgets {
+                    synthesize(None)
+                } else {
+                    (circuit.clone(), 0, 0, filestats.nt)
+                };
+                // CCZ and CS gates take 7 and 3 T gates to implement directly
+                let gadget_tcount = 7 * nccz + 3 * ncs + nt;
+                filestats.gadget_tcount = Some(gadget_tcount);
+                filestats.flat_tcount = Some(flat_tcount);
+                // The weighted cost that the gadget selection minimizes, in units of T gates
+                let gadget_cost = (nt as f64 * args.t_cost + nccz as f64 * args.ccz_cost + ncs as f64 * args.cs_cost) / args.t_cost;
+
+                let verified = simulate::probably_equal(&gadget, &flat, args.samples);
+                match verified {
+                    Some(true) => pb.set_message(format!(
+                        "  Gadget synthesis verified - T-count {} with gadgets vs {} without, weighted cost {:.2} vs {}",
+                        gadget_tcount, flat_tcount, gadget_cost, flat_tcount
+                    )),
+                    Some(false) => pb.set_message("  Error - gadget and flat synthesis are not equivalent, skipping"),
+                    None => pb.set_message(format!("  Too many qubits to verify gadget synthesis (more than {})", simulate::MAX_QUBITS))
+                }
+                verified
+            });
+
+            filestats.gadgets_verified = verified;
+            if verified == Some(false) {
+                continue
+            }
+        }
+
+        let consumed = filestats.nt + 7 * filestats.nccz + 3 * filestats.ncs;
+        if consumed != matrix.shape()[1] {
+            put_message(i, count, format!("  Error - synthesis consumed {} columns but the decomposition has {}, skipping", consumed, matrix.shape()[1]));
+            continue
+        }
+
         if let Some(orig) = &orig {
+            // Each column of the original is implemented by a single T gate, so compare
+            // against the cost of the synthesized gadgets in units of T gates
+            let original = orig.shape()[1];
+            let achieved = filestats.nt as f64 * args.t_cost
+                + filestats.nccz as f64 * args.ccz_cost
+                + filestats.ncs as f64 * args.cs_cost;
+            filestats.original_tcount = Some(original);
+            filestats.cost_ratio = (original > 0).then(|| achieved / (original as f64 * args.t_cost));
+            if let Some(ratio) = filestats.cost_ratio {
+                put_message(i, count, format!("  Cost relative to original decomposition: {:.3}", ratio));
+            }
+
             let correction = with_message(i, count, |pb| {
                 pb.set_message("  Applying Clifford correction factor...");
-                let correction = extract::clifford_correction(&matrix, orig, &map);
+                let correction = extract::clifford_correction(&extract::expand_signs(&matrix, &signs), orig, &map);
                 pb.set_message(format!("  Clifford correction factor applied, {} gates", correction.gates.len()));
                 correction
             });
@@ -261,6 +568,11 @@ pub fn main(args: Args) {
             circuit.merge(correction);
         }
 
+        // Qubits outside of the layout, such as a target qubit ancilla, stay where they are
+        if let Some(layout) = &layout {
+            circuit.map_qubits(|Qubit(q)| Qubit(layout.get(q).copied().unwrap_or(q)));
+        }
+
         if args.emit.contains(&OutputType::CircuitQASM) {
             let output = args.write_output(&path, ".qasm", &circuit.to_openqasm(false));
             put_message(i, count, format!("    Wrote synthesized circuit to: {}", output.display()));
@@ -271,6 +583,12 @@ pub fn main(args: Args) {
             put_message(i, count, format!("    Wrote synthesized circuit to: {}", output.display()));
         }
 
+        if args.emit.contains(&OutputType::Phasepoly) {
+            let poly = extract::find_phase_polynomial(&extract::expand_signs(&matrix, &signs));
+            let output = args.write_output(&path, ".phasepoly.txt", &format!("{}\n", extract::format_phase_polynomial(&poly, &map)));
+            put_message(i, count, format!("    Wrote phase polynomial to: {}", output.display()));
+        }
+
         logfile.files.push(filestats);
     }
 
@@ -0,0 +1,238 @@
+use num_complex::Complex64;
+use ndarray as nd;
+use rand::Rng;
+use crate::circuit::{Circuit, Gate, Phase, Qubit};
+
+/// The largest number of qubits we are willing to simulate.
+pub const MAX_QUBITS: usize = 24;
+
+/// The largest number of qubits we are willing to build a dense unitary for.
+pub const MAX_UNITARY_QUBITS: usize = 12;
+
+/// A dense state vector, where qubit i is bit i of the basis state index.
+#[derive(Debug, Clone)]
+pub struct StateVector {
+    pub amplitudes: Vec<Complex64>
+}
+
+/// The phase e^(i pi p / 4).
+fn omega(Phase(p): Phase) -> Complex64 {
+    Complex64::from_polar(1.0, std::f64::consts::FRAC_PI_4 * p as f64)
+}
+
+impl StateVector {
+    /// The state |0...0> on n qubits.
+    pub fn zero(n: usize) -> StateVector {
+        let mut amplitudes = vec![Complex64::new(0.0, 0.0); 1 << n];
+        amplitudes[0] = Complex64::new(1.0, 0.0);
+        StateVector { amplitudes }
+    }
+
+    /// A random stabilizer state on n qubits, prepared by a random
+    /// H + S + CNOT circuit on |0...0>. This is not uniformly distributed
+    /// over stabilizer states, but spreads well enough to catch differences.
+    pub fn random_stabilizer(n: usize, rng: &mut impl Rng) -> StateVector {
+        let mut state = StateVector::zero(n);
+        let mut gates = Vec::new();
+        for _ in 0..4 * n * n.max(1) {
+            let q = Qubit(rng.gen_range(0..n));
+            match rng.gen_range(0..3) {
+                0 => gates.push(Gate::H(q)),
+                1 => gates.push(Gate::Phase(Phase::S, q)),
+                _ if n > 1 => {
+                    let t = Qubit((q.0 + rng.gen_range(1..n)) % n);
+                    gates.push(Gate::CNOT(q, t));
+                },
+                _ => ()
+            }
+        }
+        state.apply(&Circuit::new(gates));
+        state
+    }
+
+    /// Apply a circuit to this state in place, including its global phase.
+    pub fn apply(&mut self, circuit: &Circuit) {
+        for &gate in &circuit.gates {
+            self.apply_gate(gate);
+        }
+
+        if circuit.global_phase != Phase(0) {
+            let w = omega(circuit.global_phase);
+            self.amplitudes.iter_mut().for_each(|a| *a *= w);
+        }
+    }
+
+    fn apply_gate(&mut self, gate: Gate) {
+        let bit = |Qubit(q): Qubit| 1usize << q;
+        let amps = &mut self.amplitudes;
+        match gate {
+            Gate::I(_) => (),
+            Gate::X(q) => {
+                let m = bit(q);
+                for i in (0..amps.len()).filter(|i| i & m == 0) {
+                    amps.swap(i, i | m);
+                }
+            },
+            Gate::CNOT(c, t) => {
+                let (mc, mt) = (bit(c), bit(t));
+                for i in (0..amps.len()).filter(|i| i & mc != 0 && i & mt == 0) {
+                    amps.swap(i, i | mt);
+                }
+            },
+            Gate::SWAP(a, b) => {
+                let (ma, mb) = (bit(a), bit(b));
+                for i in (0..amps.len()).filter(|i| i & ma != 0 && i & mb == 0) {
+                    amps.swap(i, (i & !ma) | mb);
+                }
+            },
+            Gate::Phase(p, q) => {
+                let (m, w) = (bit(q), omega(p));
+                for i in (0..amps.len()).filter(|i| i & m != 0) {
+                    amps[i] *= w;
+                }
+            },
+            Gate::CZ(a, b) => {
+                let m = bit(a) | bit(b);
+                for i in (0..amps.len()).filter(|i| i & m == m) {
+                    amps[i] = -amps[i];
+                }
+            },
+            Gate::CS(a, b) => {
+                let m = bit(a) | bit(b);
+                for i in (0..amps.len()).filter(|i| i & m == m) {
+                    amps[i] *= Complex64::i();
+                }
+            },
+            Gate::CCZ(a, b, c) => {
+                let m = bit(a) | bit(b) | bit(c);
+                for i in (0..amps.len()).filter(|i| i & m == m) {
+                    amps[i] = -amps[i];
+                }
+            },
+            Gate::H(q) => {
+                let m = bit(q);
+                let s = std::f64::consts::FRAC_1_SQRT_2;
+                for i in (0..amps.len()).filter(|i| i & m == 0) {
+                    let (a, b) = (amps[i], amps[i | m]);
+                    amps[i] = (a + b) * s;
+                    amps[i | m] = (a - b) * s;
+                }
+            }
+        }
+    }
+
+    /// Extend this state with extra qubits in |0>.
+    pub fn extend(&self, n: usize) -> StateVector {
+        let mut amplitudes = self.amplitudes.clone();
+        amplitudes.resize(1 << n, Complex64::new(0.0, 0.0));
+        StateVector { amplitudes }
+    }
+
+    /// Postselect all qubits from n onwards in |0>, without renormalizing.
+    pub fn postselect(&self, n: usize) -> StateVector {
+        StateVector { amplitudes: self.amplitudes[..1 << n].to_vec() }
+    }
+
+    /// The norm sqrt(<self|self>).
+    pub fn norm(&self) -> f64 {
+        self.amplitudes.iter().map(|a| a.norm_sqr()).sum::<f64>().sqrt()
+    }
+
+    /// The inner product <self|other>.
+    pub fn inner(&self, other: &StateVector) -> Complex64 {
+        self.amplitudes.iter()
+            .zip(&other.amplitudes)
+            .map(|(a, b)| a.conj() * b)
+            .sum()
+    }
+}
+
+/// Probabilistically check if two circuits are equal up to global phase and normalization,
+/// by simulating both on the same random stabilizer inputs. Any qubits that only one circuit
+/// acts on are treated as ancillas prepared and postselected in |0>, which scales the state
+/// by a constant factor (1/sqrt(2) for each Hadamard gadget), so states are compared by their
+/// normalized overlap. Returns None if the circuits are too large to simulate, otherwise false
+/// only if the circuits definitely differ. CNOT + Phase circuits on the same qubits are
+/// instead compared exactly via their phase polynomials, without simulation.
+///
+/// The simulation is a dense state vector rather than a stabilizer simulation, so its cost
+/// is exponential in the qubit count and it is limited to `MAX_QUBITS` qubits.
+pub fn probably_equal(a: &Circuit, b: &Circuit, samples: usize) -> Option<bool> {
+    let (na, nb) = (a.qubits(), b.qubits());
+    if na == nb {
+        if let (Some(fa), Some(fb)) = (a.cnot_phase_form(), b.cnot_phase_form()) {
+            return Some(fa == fb)
+        }
+    }
+
+    let n = na.min(nb);
+    if na.max(nb) > MAX_QUBITS {
+        return None
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut phase: Option<(Complex64, f64)> = None;
+    for _ in 0..samples {
+        let input = StateVector::random_stabilizer(n, &mut rng);
+
+        let mut sa = input.extend(na);
+        sa.apply(a);
+        let mut sb = input.extend(nb);
+        sb.apply(b);
+        let (sa, sb) = (sa.postselect(n), sb.postselect(n));
+
+        // Equal up to global phase and scale iff the normalized overlap has unit modulus,
+        // and the same overlap and ratio of norms for every input
+        let (norm_a, norm_b) = (sa.norm(), sb.norm());
+        match (norm_a < 1e-9, norm_b < 1e-9) {
+            (true, true) => continue,
+            (false, false) => (),
+            _ => return Some(false)
+        }
+        let overlap = sa.inner(&sb) / (norm_a * norm_b);
+        if (overlap.norm() - 1.0).abs() > 1e-6 {
+            return Some(false)
+        }
+
+        let scale = norm_b / norm_a;
+        match phase {
+            None => phase = Some((overlap, scale)),
+            Some((p, r)) if (p - overlap).norm() > 1e-6 || (r - scale).abs() > 1e-6 * r => return Some(false),
+            Some(_) => ()
+        }
+    }
+
+    Some(true)
+}
+
+impl Circuit {
+    /// Compute the dense unitary of this circuit, including its global phase, where qubit i
+    /// is bit i of the row and column indices. Returns None above `MAX_UNITARY_QUBITS` qubits.
+    pub fn to_unitary(&self) -> Option<nd::Array2<Complex64>> {
+        let n = self.qubits();
+        if n > MAX_UNITARY_QUBITS {
+            return None
+        }
+
+        let mut unitary = nd::Array2::zeros((1 << n, 1 << n));
+        for j in 0..1 << n {
+            let mut state = StateVector { amplitudes: vec![Complex64::new(0.0, 0.0); 1 << n] };
+            state.amplitudes[j] = Complex64::new(1.0, 0.0);
+            state.apply(self);
+            unitary.column_mut(j).assign(&nd::Array1::from(state.amplitudes));
+        }
+        Some(unitary)
+    }
+}
+
+impl Gate {
+    /// The matrix of this gate on just the qubits it acts on, in the order
+    /// they appear in the gate, so that the first qubit is the lowest bit.
+    pub fn to_unitary(self) -> nd::Array2<Complex64> {
+        let mut qubits = self.qubits().to_vec();
+        qubits.dedup();
+        let mut gate = self;
+        gate.map_qubits(|q| Qubit(qubits.iter().position(|&p| p == q).unwrap()));
+        Circuit::new(vec![gate]).to_unitary().unwrap()
+    }
+}
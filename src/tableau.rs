@@ -0,0 +1,115 @@
+use ndarray as nd;
+use crate::circuit::{Circuit, Gate, Phase, Qubit};
+
+/// A Clifford unitary U as a stabilizer tableau, laid out as in Aaronson and Gottesman
+/// [arXiv:quant-ph/0406196]: row i is U X_i U^dag and row n + i is U Z_i U^dag, each given
+/// by its n X bits, then its n Z bits, then a sign bit that is set for a -1 phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tableau {
+    pub n: usize,
+    pub table: nd::Array2<bool>
+}
+
+impl Tableau {
+    /// The tableau of the identity on n qubits.
+    pub fn identity(n: usize) -> Tableau {
+        Tableau {
+            n,
+            table: nd::Array2::from_shape_fn((2 * n, 2 * n + 1), |(i, j)| i == j)
+        }
+    }
+
+    fn x(&self, row: usize, q: usize) -> bool {
+        self.table[[row, q]]
+    }
+
+    fn z(&self, row: usize, q: usize) -> bool {
+        self.table[[row, self.n + q]]
+    }
+
+    fn flip_sign(&mut self, row: usize, flip: bool) {
+        self.table[[row, 2 * self.n]] ^= flip;
+    }
+
+    fn h(&mut self, q: usize) {
+        for row in 0..2 * self.n {
+            let (x, z) = (self.x(row, q), self.z(row, q));
+            self.flip_sign(row, x & z);
+            self.table[[row, q]] = z;
+            self.table[[row, self.n + q]] = x;
+        }
+    }
+
+    fn s(&mut self, q: usize) {
+        for row in 0..2 * self.n {
+            let (x, z) = (self.x(row, q), self.z(row, q));
+            self.flip_sign(row, x & z);
+            self.table[[row, self.n + q]] = z ^ x;
+        }
+    }
+
+    fn cnot(&mut self, c: usize, t: usize) {
+        for row in 0..2 * self.n {
+            let (xc, zc, xt, zt) = (self.x(row, c), self.z(row, c), self.x(row, t), self.z(row, t));
+            self.flip_sign(row, xc & zt & !(xt ^ zc));
+            self.table[[row, t]] = xt ^ xc;
+            self.table[[row, self.n + c]] = zc ^ zt;
+        }
+    }
+
+    /// Conjugate by a Pauli X (if x) and Z (if z) on qubit q, which only changes signs.
+    fn pauli(&mut self, q: usize, x: bool, z: bool) {
+        for row in 0..2 * self.n {
+            let flip = (x & self.z(row, q)) ^ (z & self.x(row, q));
+            self.flip_sign(row, flip);
+        }
+    }
+
+    /// Apply a Clifford gate after the unitary this tableau represents.
+    pub fn apply(&mut self, gate: Gate) -> Result<(), String> {
+        if let Some(q) = gate.qubits().iter().find(|q| q.0 >= self.n) {
+            return Err(format!("qubit {} of {:?} is outside of the tableau on {} qubits", q.0, gate, self.n))
+        }
+
+        match gate {
+            Gate::X(Qubit(q)) => self.pauli(q, true, false),
+            Gate::Phase(Phase(p), Qubit(q)) => match p {
+                0 => (),
+                2 => self.s(q),
+                4 => self.pauli(q, false, true),
+                6 => {
+                    self.s(q);
+                    self.pauli(q, false, true);
+                },
+                _ => return Err(format!("non-Clifford phase {p}*pi/4 on qubit {q} in tableau"))
+            },
+            Gate::CNOT(Qubit(c), Qubit(t)) => self.cnot(c, t),
+            Gate::CZ(Qubit(a), Qubit(b)) => {
+                self.h(b);
+                self.cnot(a, b);
+                self.h(b);
+            },
+            Gate::SWAP(Qubit(a), Qubit(b)) => {
+                self.cnot(a, b);
+                self.cnot(b, a);
+                self.cnot(a, b);
+            },
+            Gate::H(Qubit(q)) => self.h(q),
+            Gate::I(_) => (),
+            Gate::CS(_, _) | Gate::CCZ(_, _, _) => return Err(format!("non-Clifford gate {:?} in tableau", gate))
+        }
+        Ok(())
+    }
+}
+
+impl Circuit {
+    /// Simulate this Clifford circuit into its tableau on n qubits, which must cover every
+    /// qubit the circuit acts on. Fails if the circuit has any non-Clifford gates.
+    pub fn to_tableau(&self, n: usize) -> Result<Tableau, String> {
+        let mut tableau = Tableau::identity(n);
+        for &gate in &self.gates {
+            tableau.apply(gate)?;
+        }
+        Ok(tableau)
+    }
+}
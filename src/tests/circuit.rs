@@ -0,0 +1,763 @@
+use crate::{circuit::{angle_to_phase, Circuit, Condition, Endian, Gate, Phase, Qubit, DEFAULT_ANGLE_TOLERANCE}, hadamard::AncillaBasis};
+use std::collections::HashSet;
+use std::io::Write;
+use super::verify_quizx;
+
+fn parse_qasm(source: &str) -> Circuit {
+    parse_qasm_with(source, false)
+}
+
+fn parse_qasm_with(source: &str, opaque: bool) -> Circuit {
+    let dir = tempfile::tempdir()
+        .expect("Couldn't create temporary directory!");
+    let path = dir.path().join("circ.qasm");
+    write!(
+        std::fs::File::create(&path)
+            .expect("Couldn't open temporary file!"),
+        "{}",
+        source
+    ).expect("Couldn't write to temporary file!");
+
+    let mut cache = openqasm::SourceCache::new();
+    match Circuit::from_openqasm(&mut cache, &path, opaque, DEFAULT_ANGLE_TOLERANCE) {
+        Ok(circuit) => circuit,
+        Err(errors) => {
+            errors.eprint(&mut cache).unwrap();
+            panic!("Failed to parse test circuit")
+        }
+    }
+}
+
+#[test]
+fn u_family_clifford() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\n";
+    let cases = [
+        ("u2(0, pi) q[0];", "h q[0];"),
+        ("u3(pi/2, 0, pi) q[0];", "h q[0];"),
+        ("u3(pi, 0, pi) q[0];", "x q[0];"),
+        ("u3(pi, pi/2, pi/2) q[0];", "z q[0];\nx q[0];"),
+        ("u3(pi/2, -pi/2, pi/2) q[0];", "h q[0];\ns q[0];\nh q[0];"),
+        ("U(3*pi/2, 0, 0) q[0];", "h q[0];\nz q[0];"),
+        ("u1(pi/4) q[0];", "t q[0];"),
+        ("u1(-pi/2) q[0];", "sdg q[0];"),
+        ("u0(1) q[0];\nu2(pi/4, pi) q[0];", "h q[0];\nt q[0];")
+    ];
+
+    for (u, reference) in cases {
+        let a = parse_qasm(&format!("{header}{u}\n"));
+        let b = parse_qasm(&format!("{header}{reference}\n"));
+        verify_quizx(&a, &b);
+    }
+}
+
+#[test]
+fn identity_emits_nothing() {
+    let mut circuit = super::random_circuit(5, 50, 0.2, 0.3);
+    let mut spaced = circuit.clone();
+    for i in (0..spaced.gates.len()).step_by(3).rev() {
+        spaced.gates.insert(i, Gate::I(Qubit(i % 5)));
+    }
+    circuit.gates.push(Gate::I(Qubit(4)));
+    spaced.gates.push(Gate::I(Qubit(4)));
+
+    assert_eq!(circuit.qubits(), spaced.qubits());
+    assert_eq!(circuit.to_openqasm(false), spaced.to_openqasm(false));
+    assert_eq!(circuit.to_qc(5), spaced.to_qc(5));
+    spaced.strip_identities();
+    circuit.strip_identities();
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", spaced.gates));
+}
+
+#[test]
+fn from_zx_phase_range() {
+    let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nrz(13*pi/4) q[0];\nrz(-9*pi/4) q[0];\nrz(7*pi/4) q[0];\nrz(-pi/4) q[0];\nrz(4*pi) q[0];\n";
+    let zx = quizx::circuit::Circuit::from_qasm(source).unwrap();
+    let circuit = Circuit::from_zx(zx);
+    let phases = circuit.gates.iter()
+        .map(|g| match g {
+            Gate::Phase(p, _) => p.0,
+            _ => panic!("Unexpected gate {:?}", g)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(phases, vec![5, 7, 7, 7, 0]);
+}
+
+#[test]
+fn global_phase_roundtrip() {
+    let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nopaque gphase(g) a;\nqreg q[2];\ngphase(3*pi/4) q[0];\nh q[0];\nt q[1];\ngphase(pi/2) q[1];\ncx q[0], q[1];\n";
+    let circuit = parse_qasm(source);
+    assert_eq!(circuit.global_phase, Phase(5));
+
+    let reparsed = parse_qasm(&circuit.to_openqasm(true));
+    assert_eq!(reparsed.global_phase, Phase(5));
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", reparsed.gates));
+
+    let merged = circuit.clone().partition().merge();
+    assert_eq!(merged.global_phase, Phase(5));
+    verify_quizx(&circuit, &merged);
+}
+
+#[test]
+fn qc_roundtrip() {
+    let source = ".v a b c\nBEGIN\ntof a\ntof a b\ntof a b c\nccz a b c\ncs b c\nX* a\nZ* b\nEND\n";
+    let circuit = Circuit::from_qc(source).unwrap();
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", vec![
+        Gate::X(Qubit(0)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::H(Qubit(2)), Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)), Gate::H(Qubit(2)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+        Gate::CS(Qubit(1), Qubit(2)),
+        Gate::X(Qubit(0)),
+        Gate::Phase(Phase::Z, Qubit(1))
+    ]));
+    assert!(Circuit::from_qc(".v a b c d\nBEGIN\ntof a b c d\nEND\n").is_err());
+
+    // Everything to_qc writes can be read back
+    for _ in 0..50 {
+        let circuit = super::random_circuit(5, 60, 0.1, 0.3);
+        let reparsed = Circuit::from_qc(&circuit.to_qc(circuit.qubits())).unwrap();
+        verify_quizx(&circuit, &reparsed);
+    }
+}
+
+#[test]
+fn qc_labeled_roundtrip() {
+    let source = ".v q7 q2 anc\n.i q7 q2\nBEGIN\ncnot q7 anc\nT q2\ntof q2 anc\nEND\n";
+    let (circuit, labels, inputs) = Circuit::from_qc_labeled(source).unwrap();
+    assert_eq!(labels, vec!["q7", "q2", "anc"]);
+    assert_eq!(inputs, vec![Qubit(0), Qubit(1)]);
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", vec![
+        Gate::CNOT(Qubit(0), Qubit(2)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(1), Qubit(2))
+    ]));
+
+    let qc = circuit.to_qc_labeled(&labels, &inputs);
+    assert!(qc.starts_with(".v q7 q2 anc\n.i q7 q2\n"));
+    assert!(qc.contains("cnot q7 anc\nT q2\ncnot q2 anc\n"));
+    let (reparsed, relabels, reinputs) = Circuit::from_qc_labeled(&qc).unwrap();
+    assert_eq!(relabels, labels);
+    assert_eq!(reinputs, inputs);
+    assert_eq!(format!("{:?}", reparsed.gates), format!("{:?}", circuit.gates));
+
+    // Inputs keep their order, and qubits without a label keep their index
+    let qc = circuit.to_qc_labeled(&labels[..2], &[Qubit(1), Qubit(0)]);
+    assert!(qc.starts_with(".v q7 q2 2\n.i q2 q7\n"));
+
+    assert!(Circuit::from_qc_labeled(".v a b\n.i a c\nBEGIN\nEND\n").is_err());
+}
+
+#[test]
+fn map_qubits_permutation() {
+    let layout = [2, 0, 3, 1];
+    let permute = |x: usize| (0..4).filter(|&q| x >> q & 1 == 1).map(|q| 1 << layout[q]).sum::<usize>();
+    for _ in 0..20 {
+        let mut circuit = super::random_circuit(4, 40, 0.1, 0.3);
+        circuit.gates.push(Gate::I(Qubit(3)));
+        let mut permuted = circuit.clone();
+        permuted.map_qubits(|Qubit(q)| Qubit(layout[q]));
+
+        // The permuted circuit is the original under the same relabeling of basis states
+        let u = circuit.to_unitary().unwrap();
+        let v = permuted.to_unitary().unwrap();
+        for ((x, y), a) in u.indexed_iter() {
+            assert!((v[(permute(x), permute(y))] - a).norm() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn inverse_random() {
+    for _ in 0..20 {
+        let mut circuit = super::random_circuit(4, 40, 0.1, 0.3);
+        circuit.gates.extend([Gate::CS(Qubit(0), Qubit(2)), Gate::CCZ(Qubit(1), Qubit(2), Qubit(3)), Gate::CS(Qubit(3), Qubit(1))]);
+        circuit.global_phase = Phase(3);
+        let mut merged = circuit.clone();
+        merged.merge(circuit.inverse());
+        assert_eq!(merged.global_phase, Phase(0));
+
+        let u = merged.to_unitary().unwrap();
+        for ((x, y), a) in u.indexed_iter() {
+            let expected = if x == y { 1.0 } else { 0.0 };
+            assert!((a - expected).norm() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn opaque_skips_defined_gates() {
+    let circuit = parse_qasm_with("
+        OPENQASM 2.0;
+        include \"qelib1.inc\";
+        gate cs a, b { t a; t b; cx a, b; tdg b; cx a, b; }
+        qreg q[3];
+        cs q[0], q[1];
+        ccz q[0], q[1], q[2];
+    ", true);
+    verify_quizx(&circuit, &Circuit::new(vec![
+        Gate::CS(Qubit(0), Qubit(1)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2))
+    ]));
+}
+
+#[test]
+fn depth() {
+    assert_eq!(Circuit::new(vec![]).depth(), 0);
+
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::I(Qubit(3)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::H(Qubit(2)),
+        Gate::CCZ(Qubit(1), Qubit(2), Qubit(3)),
+        Gate::X(Qubit(0))
+    ]);
+    assert_eq!(circuit.depth_per_qubit(), vec![3, 3, 3, 3]);
+    assert_eq!(circuit.depth(), 3);
+
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::H(Qubit(0)),
+        Gate::CNOT(Qubit(1), Qubit(2))
+    ]);
+    assert_eq!(circuit.depth_per_qubit(), vec![2, 1, 1]);
+    assert_eq!(circuit.depth(), 2);
+}
+
+#[test]
+fn native_swap() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::SWAP(Qubit(0), Qubit(2)),
+        Gate::CNOT(Qubit(1), Qubit(2))
+    ]);
+
+    let qc = circuit.to_qc_with(3, true);
+    assert!(qc.contains("swap 0 2"));
+    assert!(!circuit.to_qc(3).contains("swap"));
+    let reparsed = Circuit::from_qc(&qc).unwrap();
+    assert_eq!(format!("{:?}", reparsed.gates), format!("{:?}", circuit.gates));
+
+    let qasm = circuit.to_openqasm_with(false, true);
+    assert!(qasm.contains("swap q[0], q[2];"));
+    assert!(!circuit.to_openqasm(false).contains("swap"));
+    assert!(qasm.contains("opaque swap a, b;"));
+    // The declaration makes the output readable without injecting opaque definitions
+    let reparsed = parse_qasm(&qasm);
+    assert_eq!(format!("{:?}", reparsed.gates), format!("{:?}", circuit.gates));
+    let reparsed = parse_qasm_with(&circuit.to_openqasm_with(true, true), true);
+    assert_eq!(format!("{:?}", reparsed.gates), format!("{:?}", circuit.gates));
+    verify_quizx(&circuit, &parse_qasm(&circuit.to_openqasm(false)));
+}
+
+#[test]
+fn cs_without_opaque() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::H(Qubit(1)),
+        Gate::CS(Qubit(0), Qubit(1)),
+        Gate::CS(Qubit(1), Qubit(2)),
+        Gate::H(Qubit(2))
+    ]);
+    let qasm = circuit.to_openqasm(false);
+    assert!(!qasm.contains("cs "));
+    assert!(circuit.to_openqasm(true).contains("cs q[0], q[1];"));
+
+    // Stock qelib1.inc is enough to parse and type check the output
+    let reparsed = parse_qasm(&qasm);
+    assert_eq!(reparsed.count_gates(|g| matches!(g, Gate::CS(_, _))), 0);
+    verify_quizx(&circuit, &reparsed);
+}
+
+#[test]
+fn comments_roundtrip() {
+    let source = ".v a b c\n.i a b c\n# @layout 0 1 2\nBEGIN\nH a\n# @t_factory 0\nT b\ncnot a c\nEND\n# trailing\n";
+    let circuit = Circuit::from_qc(source).unwrap();
+    assert_eq!(circuit.comments, vec![
+        (0, " @layout 0 1 2".to_string()),
+        (1, " @t_factory 0".to_string()),
+        (3, " trailing".to_string())
+    ]);
+
+    let reparsed = Circuit::from_qc(&circuit.to_qc(3)).unwrap();
+    assert_eq!(reparsed.comments, circuit.comments);
+
+    let qasm = parse_qasm(&circuit.to_openqasm(false));
+    assert_eq!(
+        qasm.comments.iter().map(|(_, c)| c.as_str()).collect::<Vec<_>>(),
+        vec![" @layout 0 1 2", " @t_factory 0", " trailing"]
+    );
+
+    let mut spaced = circuit.clone();
+    spaced.gates.insert(1, Gate::I(Qubit(0)));
+    spaced.comments[1].0 += 1;
+    spaced.comments[2].0 += 1;
+    spaced.strip_identities();
+    assert_eq!(spaced.comments, circuit.comments);
+
+    // Rewrites that move gates around keep the comments in order at the start
+    let partitioned = circuit.clone().partition();
+    assert_eq!(partitioned.front.comments.iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec![0, 0, 0]);
+    let mut optimized = circuit.clone();
+    optimized.move_h_optimal();
+    assert_eq!(
+        optimized.comments.iter().map(|(i, c)| (*i, c.as_str())).collect::<Vec<_>>(),
+        vec![(0, " @layout 0 1 2"), (0, " @t_factory 0"), (0, " trailing")]
+    );
+}
+
+#[test]
+fn emitted_blocks_declare_qubits() {
+    let q = 6;
+    let g = 80;
+    for _ in 0..50 {
+        let circuit = super::random_circuit(q, g, 0.2, 0.3);
+        let mut partitioned = circuit.partition();
+        partitioned.pick_gadgets(usize::MAX, 10);
+        partitioned.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        partitioned.extract_gadgets();
+
+        let blocks = std::iter::once(&partitioned.front)
+            .chain(&partitioned.blocks)
+            .chain(std::iter::once(&partitioned.back));
+        for block in blocks {
+            // The qasm must reference only declared qubits
+            let reparsed = parse_qasm_with(&block.to_openqasm(false), true);
+            assert!(reparsed.qubits() <= block.qubits().max(1));
+
+            // Every .i qubit in the qc header must be declared in .v
+            let qc = block.to_qc(q);
+            let mut lines = qc.lines();
+            let declared = lines.next().unwrap().strip_prefix(".v ").unwrap().split(' ').collect::<Vec<_>>();
+            let inputs = lines.next().unwrap().strip_prefix(".i ").unwrap().split(' ').collect::<Vec<_>>();
+            assert!(inputs.iter().all(|i| declared.contains(i)));
+        }
+    }
+}
+
+#[test]
+fn controlled_phase_lattice() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nopaque cp(l) a, b;\nopaque ccp(l) a, b, c;\nqreg q[3];\n";
+    let cases = [
+        ("cp(pi) q[0], q[1];", "cz q[0], q[1];"),
+        ("cp(pi/2) q[0], q[1];", "cx q[0], q[1];\ntdg q[1];\ncx q[0], q[1];\nt q[0];\nt q[1];"),
+        ("cp(-pi/2) q[1], q[2];", "cx q[1], q[2];\nt q[2];\ncx q[1], q[2];\ntdg q[1];\ntdg q[2];"),
+        ("cp(2*pi) q[0], q[2];", "id q[0];"),
+        ("ccp(pi) q[0], q[1], q[2];", "h q[2];\nccx q[0], q[1], q[2];\nh q[2];"),
+        ("ccp(-pi) q[2], q[0], q[1];", "h q[1];\nccx q[0], q[2], q[1];\nh q[1];")
+    ];
+
+    for (cp, reference) in cases {
+        let a = parse_qasm(&format!("{header}{cp}\nh q[0];\nh q[1];\nh q[2];\n"));
+        let b = parse_qasm(&format!("{header}{reference}\nh q[0];\nh q[1];\nh q[2];\n"));
+        verify_quizx(&a, &b);
+    }
+
+    let mut cache = openqasm::SourceCache::new();
+    let dir = tempfile::tempdir().unwrap();
+    for unsupported in ["cp(pi/4) q[0], q[1];", "ccp(pi/2) q[0], q[1], q[2];"] {
+        let path = dir.path().join("unsupported.qasm");
+        std::fs::write(&path, format!("{header}{unsupported}\n")).unwrap();
+        assert!(Circuit::from_openqasm(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).is_err());
+    }
+}
+
+#[test]
+fn deferred_corrections() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\ncreg a[1];\ncreg b[2];\n";
+    let circuit = parse_qasm(&format!("{header}h q[0];\ncx q[0], q[1];\nt q[2];\nmeasure q[0] -> a[0];\nif (a == 1) z q[1];\nif (a == 1) swap q[1], q[2];\nmeasure q[1] -> b[1];\n"));
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", vec![
+        Gate::H(Qubit(0)), Gate::CNOT(Qubit(0), Qubit(1)), Gate::Phase(Phase::T, Qubit(2))
+    ]));
+    assert_eq!(circuit.measurements, vec![(Qubit(0), 0), (Qubit(1), 2)]);
+    let condition = Condition { start: 0, count: 1, value: 1 };
+    assert_eq!(circuit.corrections.len(), 2);
+    assert!(circuit.corrections.iter().all(|(c, _)| *c == condition));
+
+    // Re-emitting and parsing again keeps the deferred part
+    let reparsed = parse_qasm(&circuit.to_openqasm(false));
+    assert_eq!(format!("{:?}", reparsed.gates), format!("{:?}", circuit.gates));
+    assert_eq!(reparsed.measurements, circuit.measurements);
+    assert_eq!(format!("{:?}", reparsed.corrections), format!("{:?}", circuit.corrections));
+
+    let mut cache = openqasm::SourceCache::new();
+    let dir = tempfile::tempdir().unwrap();
+    for unsupported in [
+        "measure q[0] -> a[0];\nh q[0];",
+        "measure q[0] -> a[0];\nif (a == 1) t q[1];",
+        "measure q[0] -> a[0];\nif (a == 1) x q[1];\ncx q[2], q[1];",
+        "measure q[0] -> a[0];\nif (a == 1) x q[1];\nmeasure q[1] -> b[0];",
+        "if (a == 1) x q[1];\nmeasure q[0] -> a[0];",
+        "measure q[0] -> b[0];\nif (b == 1) x q[1];",
+        "measure q[0] -> a[0];\nif (a == 1) x q[1];\nmeasure q[2] -> a[0];"
+    ] {
+        let path = dir.path().join("unsupported.qasm");
+        std::fs::write(&path, format!("{header}{unsupported}\n")).unwrap();
+        assert!(Circuit::from_openqasm(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).is_err());
+    }
+}
+
+#[test]
+fn gate_counts() {
+    let circuit = Circuit::new(vec![
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+        Gate::CS(Qubit(1), Qubit(2)),
+        Gate::CZ(Qubit(0), Qubit(2)),
+        Gate::SWAP(Qubit(0), Qubit(1)),
+        Gate::CNOT(Qubit(2), Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::H(Qubit(1)),
+        Gate::I(Qubit(2))
+    ]);
+    assert_eq!(circuit.num_cnots(), 2);
+    assert_eq!(circuit.num_two_qubit_gates(), 5);
+    assert_eq!(circuit.num_ccz(), 1);
+    assert_eq!(circuit.num_cs(), 1);
+    assert_eq!(circuit.tcount(), 1);
+    assert_eq!(circuit.count_hadamards(), 1);
+    assert_eq!(circuit.count_gates(|g| matches!(g, Gate::I(_))), 1);
+}
+
+#[test]
+fn stim_clifford_only() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(Phase::S, Qubit(1)),
+        Gate::Phase(-Phase::S, Qubit(2)),
+        Gate::Phase(Phase::Z, Qubit(0)),
+        Gate::CZ(Qubit(1), Qubit(2)),
+        Gate::SWAP(Qubit(0), Qubit(2)),
+        Gate::X(Qubit(1))
+    ]);
+    assert_eq!(
+        circuit.to_stim().unwrap(),
+        "H 0\nCX 0 1\nS 1\nS_DAG 2\nZ 0\nCZ 1 2\nSWAP 0 2\nX 1\n"
+    );
+
+    for gate in [Gate::Phase(Phase::T, Qubit(0)), Gate::CS(Qubit(0), Qubit(1)), Gate::CCZ(Qubit(0), Qubit(1), Qubit(2))] {
+        let mut bad = circuit.clone();
+        bad.gates.push(gate);
+        assert!(bad.to_stim().is_err());
+    }
+}
+
+#[test]
+fn to_quantikz() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase(1), Qubit(2)),
+        Gate::CNOT(Qubit(2), Qubit(0)),
+        Gate::CCZ(Qubit(2), Qubit(0), Qubit(1)),
+        Gate::Phase(Phase(7), Qubit(3))
+    ]);
+    let tex = circuit.to_quantikz(3);
+    let rows = tex.lines().collect::<Vec<_>>();
+    assert_eq!(rows.first(), Some(&"\\begin{quantikz}"));
+    assert_eq!(rows.last(), Some(&"\\end{quantikz}"));
+    assert_eq!(rows.len(), 6);
+
+    // H and T share a column, as do the CCZ and the T-dagger below its span
+    assert_eq!(rows[1], "\\lstick{$q_{0}$} & \\gate{H} & \\targ{} & \\ctrl{1} & \\qw \\\\");
+    assert_eq!(rows[2], "\\lstick{$q_{1}$} & \\qw & \\qw & \\ctrl{1} & \\qw \\\\");
+    assert_eq!(rows[3], "\\lstick{$q_{2}$} & \\gate{T} & \\ctrl{-2} & \\control{} & \\qw \\\\");
+    assert_eq!(rows[4], "\\lstick{$\\ket{0}$} & \\qw & \\qw & \\gate{T^\\dagger} & \\qw");
+
+    // A gate within the span of a multi-qubit gate needs its own column
+    let circuit = Circuit::new(vec![Gate::CZ(Qubit(0), Qubit(2)), Gate::H(Qubit(1))]);
+    assert!(circuit.to_quantikz(3).contains("\\lstick{$q_{1}$} & \\qw & \\gate{H} & \\qw"));
+}
+
+#[test]
+fn to_quirk_url() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase(3), Qubit(1)),
+        Gate::X(Qubit(2)),
+        Gate::CNOT(Qubit(0), Qubit(2)),
+        Gate::CS(Qubit(1), Qubit(0)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+        Gate::SWAP(Qubit(1), Qubit(2)),
+        Gate::Phase(Phase(7), Qubit(0))
+    ]);
+    let url = circuit.to_quirk_url();
+    let encoded = url.strip_prefix("https://algassert.com/quirk#circuit=").unwrap();
+    assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.~%".contains(&b)));
+
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2]).unwrap(), 16).unwrap());
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    let json = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+    assert_eq!(json, serde_json::json!({ "cols": [
+        ["H", "Z^½", 1],
+        [1, "Z^¼", "X"],
+        ["•", 1, "X"],
+        ["Z^½", "•", 1],
+        ["•", "•", "Z"],
+        [1, "Swap", "Swap"],
+        ["Z^-¼", 1, 1]
+    ]}));
+}
+
+#[test]
+fn dependency_dot() {
+    let circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(2)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+        Gate::CZ(Qubit(1), Qubit(2))
+    ]);
+    let dot = circuit.to_dependency_dot();
+    assert!(dot.starts_with("digraph circuit {"));
+    assert!(dot.contains("g1 [label=\"CNOT 0 1\"];"));
+    assert!(dot.contains("g2 [label=\"Phase(1) 2\"];"));
+
+    let mut edges = dot.lines()
+        .filter(|l| l.contains("->"))
+        .map(|l| l.trim().to_string())
+        .collect::<Vec<_>>();
+    edges.sort();
+    assert_eq!(edges, vec!["g0 -> g1;", "g1 -> g3;", "g2 -> g3;", "g3 -> g4;"]);
+}
+
+#[test]
+fn to_openqasm3() {
+    let mut circuit = Circuit::new(vec![
+        Gate::H(Qubit(0)),
+        Gate::CS(Qubit(0), Qubit(1)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(3)),
+        Gate::Phase(Phase(6), Qubit(1)),
+        Gate::SWAP(Qubit(1), Qubit(2))
+    ]);
+    circuit.global_phase = Phase(2);
+    let qasm = circuit.to_openqasm3();
+    let lines = qasm.lines().collect::<Vec<_>>();
+    assert_eq!(lines, vec![
+        "OPENQASM 3.0;",
+        "include \"stdgates.inc\";",
+        "qubit[4] q;",
+        "gphase(2*pi/4);",
+        "h q[0];",
+        "ctrl @ s q[0], q[1];",
+        "ctrl @ ctrl @ z q[0], q[1], q[3];",
+        "sdg q[1];",
+        "swap q[1], q[2];"
+    ]);
+}
+
+#[test]
+fn openqasm_multi() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n";
+    let dir = tempfile::tempdir().unwrap();
+    let mut cache = openqasm::SourceCache::new();
+
+    let markers = format!("// CIRCUIT 0\n{header}qreg q[1];\nh q[0];\n// CIRCUIT 1\n{header}qreg q[2];\ncx q[0], q[1];\nt q[1];\n");
+    let headers = format!("{header}qreg q[1];\nh q[0];\n\n{header}qreg q[2];\ncx q[0], q[1];\nt q[1];\n");
+    for source in [markers, headers] {
+        let path = dir.path().join("bundle.qasm");
+        std::fs::write(&path, source).unwrap();
+        let circuits = Circuit::from_openqasm_multi(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).unwrap();
+        assert_eq!(circuits.len(), 2);
+        assert_eq!(format!("{:?}", circuits[0].gates), format!("{:?}", vec![Gate::H(Qubit(0))]));
+        assert_eq!(
+            format!("{:?}", circuits[1].gates),
+            format!("{:?}", vec![Gate::CNOT(Qubit(0), Qubit(1)), Gate::Phase(Phase::T, Qubit(1))])
+        );
+    }
+
+    // A plain file is a single circuit
+    let path = dir.path().join("single.qasm");
+    std::fs::write(&path, format!("{header}qreg q[1];\nh q[0];\n")).unwrap();
+    assert_eq!(Circuit::from_openqasm_multi(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).unwrap().len(), 1);
+}
+
+#[test]
+fn openqasm_gzip() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n";
+    let dir = tempfile::tempdir().unwrap();
+    let mut cache = openqasm::SourceCache::new();
+    let write_gzip = |name: &str, source: &str| {
+        let path = dir.path().join(name);
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(source.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        path
+    };
+
+    let path = write_gzip("single.qasm.gz", &format!("{header}// a comment\nqreg q[2];\nh q[0];\ncx q[0], q[1];\nt q[1];\n"));
+    let circuit = Circuit::from_openqasm(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).unwrap();
+    assert_eq!(
+        format!("{:?}", circuit.gates),
+        format!("{:?}", vec![Gate::H(Qubit(0)), Gate::CNOT(Qubit(0), Qubit(1)), Gate::Phase(Phase::T, Qubit(1))])
+    );
+    assert_eq!(circuit.comments, vec![(0, " a comment".to_string())]);
+
+    let path = write_gzip("bundle.qasm.gz", &format!("{header}qreg q[1];\nh q[0];\n\n{header}qreg q[1];\nt q[0];\n"));
+    assert_eq!(Circuit::from_openqasm_multi(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).unwrap().len(), 2);
+
+    assert_eq!(crate::circuit::strip_gzip(&path), dir.path().join("bundle.qasm"));
+}
+
+#[test]
+fn to_zx_single_phase() {
+    use quizx::{graph::GraphLike, tensor::ToTensor};
+
+    for p in 1..8 {
+        let circuit = Circuit::new(vec![Gate::Phase(Phase(p), Qubit(0))]);
+        assert_eq!(circuit.to_zx().num_gates(), 1);
+    }
+
+    // The tensor must match quizx's own translation of the named gates
+    for _ in 0..20 {
+        let circuit = super::random_circuit(3, 20, 0.2, 0.3);
+        let reference = quizx::circuit::Circuit::from_qasm(&circuit.to_openqasm(false)).unwrap();
+        let a = circuit.to_zx().to_graph::<quizx::hash_graph::Graph>().to_tensorf();
+        let b = reference.to_graph::<quizx::hash_graph::Graph>().to_tensorf();
+        assert_eq!(a.shape(), b.shape());
+        assert!(a.iter().zip(&b).all(|(x, y)| (x - y).norm() < 1e-6));
+    }
+}
+
+#[test]
+fn to_pyzx_json() {
+    let json = |circuit: Circuit| serde_json::from_str::<serde_json::Value>(&circuit.to_pyzx_json()).unwrap();
+
+    for p in 1..8 {
+        let graph = json(Circuit::new(vec![Gate::Phase(Phase(p), Qubit(0))]));
+        let phases = graph["vertices"].as_array().unwrap().iter()
+            .filter_map(|v| v["phase"].as_str())
+            .collect::<Vec<_>>();
+        let expected = match p {
+            2 => "1/2".to_string(),
+            4 => "1".to_string(),
+            6 => "3/2".to_string(),
+            p => format!("{p}/4")
+        };
+        assert_eq!(phases, vec![expected.as_str()]);
+    }
+
+    let graph = json(Circuit::new(vec![Gate::H(Qubit(0)), Gate::CNOT(Qubit(0), Qubit(1))]));
+    assert_eq!(graph["inputs"].as_array().unwrap().len(), 2);
+    assert_eq!(graph["outputs"].as_array().unwrap().len(), 2);
+    let edges = graph["edges"].as_array().unwrap();
+    assert!(edges.iter().any(|e| e[2] == 2));
+
+    // Every edge must refer to a vertex in the list
+    for _ in 0..20 {
+        let graph = json(super::random_circuit(3, 20, 0.2, 0.3));
+        let ids = graph["vertices"].as_array().unwrap().iter()
+            .map(|v| v["id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        for e in graph["edges"].as_array().unwrap() {
+            assert!(ids.contains(&e[0].as_u64().unwrap()) && ids.contains(&e[1].as_u64().unwrap()));
+        }
+    }
+}
+
+#[test]
+fn rotation_gates() {
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\n";
+    let cases = [
+        ("rz(pi/4) q[0];", "t q[0];"),
+        ("rz(-3*pi/4) q[0];", "z q[0];\nt q[0];"),
+        ("rx(pi/4) q[0];", "h q[0];\nt q[0];\nh q[0];"),
+        ("rx(pi/2) q[0];", "u3(pi/2, -pi/2, pi/2) q[0];"),
+        ("ry(pi/2) q[0];", "u3(pi/2, 0, 0) q[0];"),
+        ("ry(pi) q[0];", "u3(pi, 0, 0) q[0];"),
+        ("ry(pi/4) q[0];\nry(pi/4) q[0];", "u3(pi/2, 0, 0) q[0];")
+    ];
+
+    for (rotation, reference) in cases {
+        // Entangle with a second qubit so that only a global phase can differ
+        let a = parse_qasm(&format!("{header}h q[0];\ncx q[0], q[1];\n{rotation}\ncx q[1], q[0];\n"));
+        let b = parse_qasm(&format!("{header}h q[0];\ncx q[0], q[1];\n{reference}\ncx q[1], q[0];\n"));
+        verify_quizx(&a, &b);
+    }
+
+    let mut cache = openqasm::SourceCache::new();
+    let dir = tempfile::tempdir().unwrap();
+    for unsupported in ["rz(pi/3) q[0];", "rx(0.1) q[0];", "ry(pi/8) q[0];"] {
+        let path = dir.path().join("unsupported.qasm");
+        std::fs::write(&path, format!("{header}{unsupported}\n")).unwrap();
+        assert!(Circuit::from_openqasm(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).is_err());
+    }
+}
+
+#[test]
+fn convert_endian() {
+    let circuit = Circuit::new(vec![
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::CCZ(Qubit(0), Qubit(2), Qubit(4)),
+        Gate::Phase(Phase::T, Qubit(3))
+    ]);
+
+    let mut big = circuit.clone();
+    big.convert_endian(Endian::Big, 4);
+    // Qubit 4 is past the input qubits, like an ancilla, so it keeps its index
+    assert_eq!(format!("{:?}", big.gates), format!("{:?}", vec![
+        Gate::CNOT(Qubit(3), Qubit(2)),
+        Gate::CCZ(Qubit(3), Qubit(1), Qubit(4)),
+        Gate::Phase(Phase::T, Qubit(0))
+    ]));
+    big.convert_endian(Endian::Big, 4);
+    assert_eq!(format!("{:?}", big.gates), format!("{:?}", circuit.gates));
+
+    let mut little = circuit.clone();
+    little.convert_endian(Endian::Little, 4);
+    assert_eq!(format!("{:?}", little.gates), format!("{:?}", circuit.gates));
+
+    // A round trip through qasm in big-endian order is the identity
+    for _ in 0..20 {
+        let circuit = super::random_circuit(5, 40, 0.1, 0.3);
+        let n = circuit.qubits();
+        let mut external = circuit.clone();
+        external.convert_endian(Endian::Big, n);
+        let mut parsed = parse_qasm(&external.to_openqasm(false));
+        parsed.convert_endian(Endian::Big, n);
+        verify_quizx(&circuit, &parsed);
+    }
+}
+
+#[test]
+fn serialize_json() {
+    let mut circuit = Circuit::new(vec![
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::H(Qubit(0))
+    ]);
+    circuit.global_phase = Phase(2);
+    assert_eq!(
+        serde_json::to_string(&circuit).unwrap(),
+        r#"{"gates":[{"CNOT":[0,1]},{"Phase":[1,1]},{"H":0}],"global_phase":2,"comments":[]}"#
+    );
+}
+
+#[test]
+fn angle_tolerance() {
+    let quarter = std::f64::consts::FRAC_PI_4;
+    assert_eq!(angle_to_phase(3.0 * quarter, DEFAULT_ANGLE_TOLERANCE), Some(Phase(3)));
+    assert_eq!(angle_to_phase(-quarter, DEFAULT_ANGLE_TOLERANCE), Some(Phase(7)));
+    assert_eq!(angle_to_phase(0.785398, DEFAULT_ANGLE_TOLERANCE), None);
+    assert_eq!(angle_to_phase(0.785398, 1e-6), Some(Phase(1)));
+    assert_eq!(angle_to_phase(0.5, 1e-6), None);
+
+    let header = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\n";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("decimal.qasm");
+    std::fs::write(&path, format!("{header}u1(0.785398) q[0];\nu1(1.5707963267948966) q[0];\n")).unwrap();
+    let mut cache = openqasm::SourceCache::new();
+    assert!(Circuit::from_openqasm(&mut cache, &path, false, DEFAULT_ANGLE_TOLERANCE).is_err());
+    let circuit = Circuit::from_openqasm(&mut cache, &path, false, 1e-6).unwrap();
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", vec![Gate::Phase(Phase::T, Qubit(0)), Gate::Phase(Phase::S, Qubit(0))]));
+}
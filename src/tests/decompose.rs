@@ -1,6 +1,7 @@
 use super::{parallel_iters, random_circuit, verify_feynver, verify_quizx};
-use crate::{extract, circuit::{Circuit, Qubit, Phase, Gate}};
+use crate::{extract, hadamard::AncillaBasis, circuit::{Circuit, Qubit, Phase, Gate}};
 use serial_test::serial;
+use std::collections::HashSet;
 
 #[test]
 #[serial]
@@ -18,6 +19,139 @@ fn partition_split_merge_random() {
     });
 }
 
+#[test]
+#[serial]
+fn pick_gadgets_patience_random() {
+    use rand::SeedableRng;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    let iters = 100;
+    let (stopped, matched) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    parallel_iters(k, "pick_gadgets_patience_random", |seed| {
+        let circuit = random_circuit(q, g, 0.1, 0.3);
+        let original = circuit.clone();
+        let mut fixed = circuit.clone().partition();
+        let (fixed_cost, fixed_restarts) = fixed.pick_gadgets_with(10, iters, None, &mut rand::rngs::StdRng::seed_from_u64(seed as u64));
+        let mut new = circuit.partition();
+        let before = new.blocks.len();
+        let (cost, restarts) = new.pick_gadgets_with(10, iters, Some(5), &mut rand::rngs::StdRng::seed_from_u64(seed as u64));
+        assert_eq!(cost, new.blocks.len());
+        assert!(cost <= before);
+
+        // With the same seed the restarts are a prefix of the fixed ones
+        assert!(restarts <= fixed_restarts);
+        assert!(cost >= fixed_cost);
+        if restarts < fixed_restarts {
+            stopped.fetch_add(1, Ordering::Relaxed);
+        }
+        if cost == fixed_cost {
+            matched.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let new = new.merge();
+        verify_quizx(&original, &new);
+    });
+
+    // Patience should actually stop early, and usually not at the cost of the result
+    let (stopped, matched) = (stopped.into_inner(), matched.into_inner());
+    println!("Stopped early in {} of {}, matched fixed iterations in {} of {}", stopped, k, matched, k);
+    assert!(stopped > 0);
+    assert!(matched * 2 > k);
+}
+
+#[test]
+#[serial]
+fn partition_clifford_only_random() {
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    parallel_iters(k, "partition_clifford_only_random", |_| {
+        let mut circuit = random_circuit(q, g, 0.25, 0.25);
+        for gate in &mut circuit.gates {
+            if let Gate::Phase(p, _) = gate {
+                *p = Phase(2 * (p.0 / 2));
+            }
+        }
+        let original = circuit.clone();
+        let new = circuit.partition();
+        assert!(new.blocks.is_empty());
+        assert!(new.back.gates.is_empty());
+        assert_eq!(new.front.gates.len(), original.gates.len());
+        let new = new.merge();
+        verify_quizx(&original, &new);
+    });
+}
+
+#[test]
+#[serial]
+fn partition_no_hoist_random() {
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    parallel_iters(k, "partition_no_hoist_random", |_| {
+        let circuit = random_circuit(q, g, 0.1, 0.3);
+        let original = circuit.clone();
+        let mut new = circuit.partition_with(false);
+        assert!(new.front.gates.is_empty());
+        assert!(new.back.gates.is_empty());
+        for (j, block) in new.blocks.iter().enumerate() {
+            if j % 2 == 0 {
+                assert!(block.gates.iter().all(|g| !matches!(g, Gate::H(_))));
+            } else {
+                assert!(block.gates.iter().all(|&g| g.is_clifford()));
+            }
+        }
+        verify_quizx(&original, &new.merge());
+
+        new.pick_gadgets(10, 100);
+        verify_quizx(&original, &new.merge());
+    });
+}
+
+#[test]
+fn extract_cliffords_maximal() {
+    // The CNOT on qubit 2 is only unblocked once the H before it is pulled,
+    // and the S after it only once the CNOT is pulled
+    let mut circuit = Circuit::new(vec![
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::H(Qubit(1)),
+        Gate::CNOT(Qubit(1), Qubit(2)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(Phase::S, Qubit(2)),
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(2)),
+        Gate::H(Qubit(1))
+    ]);
+    let (front, back) = circuit.extract_cliffords();
+    assert_eq!(format!("{:?}", front.gates), format!("{:?}", vec![
+        Gate::H(Qubit(1)), Gate::CNOT(Qubit(1), Qubit(2)), Gate::Phase(Phase::S, Qubit(2))
+    ]));
+    assert_eq!(format!("{:?}", back.gates), format!("{:?}", vec![Gate::CNOT(Qubit(0), Qubit(1)), Gate::H(Qubit(0)), Gate::H(Qubit(1))]));
+    assert_eq!(format!("{:?}", circuit.gates), format!("{:?}", vec![Gate::Phase(Phase::T, Qubit(0)), Gate::Phase(Phase::T, Qubit(2))]));
+
+    for _ in 0..200 {
+        let mut circuit = random_circuit(5, 60, 0.2, 0.3);
+        let original = circuit.clone();
+        let (front, back) = circuit.extract_cliffords();
+        assert!(front.gates.iter().chain(&back.gates).all(|g| g.is_clifford()));
+
+        // Every Clifford left behind must be blocked on both sides by a gate that stays
+        for (i, &g) in circuit.gates.iter().enumerate() {
+            if g.is_clifford() {
+                assert!(circuit.gates[..i].iter().any(|&h| h.overlaps(g)));
+                assert!(circuit.gates[i + 1..].iter().any(|&h| h.overlaps(g)));
+            }
+        }
+
+        let mut merged = front;
+        merged.merge(circuit).merge(back);
+        verify_quizx(&original, &merged);
+    }
+}
+
 fn random_not_h(q: usize, g: usize) -> Circuit {
     let mut gates = Vec::new();
     for _ in 0..g {
@@ -40,7 +174,7 @@ fn random_not_h(q: usize, g: usize) -> Circuit {
             gates.push(Gate::CNOT(Qubit(i), Qubit(j)));
         }
     }
-    Circuit { gates }
+    Circuit::new(gates)
 }
 
 #[test]
@@ -69,13 +203,35 @@ fn synth_gadgets_random() {
         let original = circuit.clone();
         let cliff1 = circuit.to_cnot_phase();
         let (map, mat, cliff2) = circuit.extract_gadgets();
-        let (mut resynth, _, _, _) = extract::extract_gadgets(&mat, &map, false);
+        let (mut resynth, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
         assert!(verify_feynver(&circuit, &resynth, original.qubits()));
         resynth.merge(cliff2).merge(cliff1);
         assert!(verify_feynver(&original, &resynth, original.qubits()));
     });
 }
 
+#[test]
+fn phase_gadgets_random() {
+    for _ in 0..200 {
+        let mut circuit = random_not_h(8, 50);
+        circuit.to_cnot_phase();
+        let gadgets = circuit.phase_gadgets();
+        assert_eq!(gadgets.len(), circuit.count_gates(|g| matches!(g, Gate::Phase(_, _))));
+
+        // The non-Clifford gadgets are exactly the columns of the synthesis matrix
+        let (map, mat, _) = circuit.clone().extract_gadgets();
+        let columns = gadgets.iter()
+            .filter(|(p, _)| !p.is_clifford())
+            .map(|(_, qubits)| qubits.iter().map(|q| q.0).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let expected = mat.columns()
+            .into_iter()
+            .map(|col| col.iter().zip(&map).filter(|(&v, _)| v).map(|(_, &q)| q).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(columns, expected);
+    }
+}
+
 #[test]
 #[serial]
 fn partition_synth_gadgets_random() {
@@ -87,13 +243,239 @@ fn partition_synth_gadgets_random() {
         let original = circuit.clone();
         let mut new = circuit.partition();
         new.pick_gadgets(5, 100);
-        new.to_cnot_phase();
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
         new.extract_gadgets();
         let new = new.merge();
         verify_quizx(&original, &new);
     });
 }
 
+#[test]
+#[serial]
+fn remove_redundant_ancillas_random() {
+    let q = 7;
+    let g = 200;
+    let k = 1000;
+    parallel_iters(k, "remove_redundant_ancillas_random", |_| {
+        let circuit = random_circuit(q, g, 0.1, 0.3);
+        let original = circuit.clone();
+        let mut new = circuit.partition();
+        new.pick_gadgets(usize::MAX, 100);
+        let before = new.blocks.iter().map(Circuit::count_hadamards).sum::<usize>();
+        let removed = new.remove_redundant_ancillas();
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        assert_eq!(new.ancilla.len() + removed, before);
+        let new = new.merge();
+        verify_quizx(&original, &new);
+    });
+}
+
+#[test]
+fn remove_redundant_ancillas() {
+    // The H pair on qubit 1 and the H on qubit 0 land in the same Clifford block
+    // between the T gates, so merging everything would gadgetize all three
+    let circuit = Circuit::new(vec![
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::H(Qubit(1)),
+        Gate::H(Qubit(1)),
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(0))
+    ]);
+    let mut new = circuit.clone().partition();
+    assert_eq!(new.pick_gadgets(usize::MAX, 10), 1);
+    assert_eq!(new.remove_redundant_ancillas(), 2);
+
+    // The remaining ancilla still skips the reserved qubit
+    new.to_cnot_phase(AncillaBasis::Zero, &HashSet::from([2]));
+    assert_eq!(new.ancilla, vec![Qubit(3)]);
+    new.validate_ancillas().unwrap();
+    verify_quizx(&circuit, &new.merge());
+}
+
+#[test]
+#[serial]
+fn merge_gadgets_across_blocks_random() {
+    let q = 5;
+    let g = 200;
+    let k = 1000;
+    parallel_iters(k, "merge_gadgets_across_blocks_random", |_| {
+        let circuit = random_circuit(q, g, 0.05, 0.4);
+        let original = circuit.clone();
+        let mut new = circuit.partition();
+        new.pick_gadgets(1, 100);
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        let before = new.merge().tcount();
+        let reduction = new.merge_gadgets_across_blocks();
+        assert_eq!(new.merge().tcount() + reduction, before);
+        let new = new.merge();
+        verify_quizx(&original, &new);
+    });
+}
+
+#[test]
+fn merge_gadgets_across_blocks_negated() {
+    // X Z X = -Z, so the later T is e^(i pi/4) T^dag on the same parity before the X
+    let mut new = crate::decompose::PartitionedCircuit {
+        front: Circuit::new(vec![Gate::H(Qubit(0))]),
+        back: Circuit::new(vec![Gate::H(Qubit(0))]),
+        blocks: vec![
+            Circuit::new(vec![Gate::CNOT(Qubit(1), Qubit(0)), Gate::Phase(Phase::T, Qubit(0))]),
+            Circuit::new(vec![Gate::X(Qubit(0))]),
+            Circuit::new(vec![Gate::Phase(Phase::T, Qubit(0)), Gate::CNOT(Qubit(1), Qubit(0))])
+        ],
+        ancilla: Vec::new(),
+        x_corrections: Vec::new()
+    };
+    let original = new.merge().to_unitary().unwrap();
+    assert_eq!(new.merge_gadgets_across_blocks(), 2);
+    assert_eq!(new.merge().tcount(), 0);
+    let merged = new.merge().to_unitary().unwrap();
+    for (a, b) in original.iter().zip(merged.iter()) {
+        assert!((a - b).norm() < 1e-9);
+    }
+}
+
+#[test]
+#[serial]
+fn fold_phases_random() {
+    let q = 5;
+    let g = 200;
+    let k = 1000;
+    parallel_iters(k, "fold_phases_random", |_| {
+        let circuit = random_circuit(q, g, 0.05, 0.4);
+        let original = circuit.clone();
+        let mut new = circuit.partition();
+        new.pick_gadgets(1, 100);
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        let before = new.merge().tcount();
+        let reduction = new.fold_phases();
+        assert_eq!(new.merge().tcount() + reduction, before);
+        verify_quizx(&original, &new.merge());
+    });
+}
+
+#[test]
+fn fold_phases_exposes_ccz() {
+    // A CCZ, with a T and T-dagger on the same parity hidden behind a CNOT pair
+    let mut circuit = Circuit::new(vec![Gate::CCZ(Qubit(0), Qubit(1), Qubit(2))]);
+    circuit.to_cnot_phase();
+    // Split the CCZ's phases, so that its columns aren't contiguous until folding
+    let middle = circuit.gates.iter()
+        .enumerate()
+        .filter(|(_, g)| matches!(g, Gate::Phase(_, _)))
+        .nth(3)
+        .unwrap().0;
+    circuit.gates.splice(middle..middle, [
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(2), Qubit(0)),
+        Gate::CNOT(Qubit(2), Qubit(0)),
+        Gate::Phase(-Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(0), Qubit(1))
+    ]);
+    let original = circuit.clone();
+
+    assert_eq!(circuit.clone().extract_gadgets().1.shape()[1], 9);
+    assert_eq!(circuit.count_gadgets().0, 0);
+
+    assert_eq!(circuit.fold_phases(), 2);
+    verify_quizx(&original, &circuit);
+    assert_eq!(circuit.count_gadgets(), (1, 0));
+    let (map, mat, _) = circuit.extract_gadgets();
+    assert_eq!(mat.shape()[1], 7);
+    let (_, nccz, _, nt) = extract::extract_gadgets(&mat, &map, Some(extract::GadgetCosts::default()), extract::GadgetOrder::None, None);
+    assert_eq!((nccz, nt), (1, 0));
+}
+
+#[test]
+fn fold_phases_global_random() {
+    for _ in 0..200 {
+        let mut circuit = random_circuit(5, 100, 0.1, 0.4);
+        let original = circuit.clone();
+        let before = circuit.tcount();
+        let reduction = circuit.fold_phases_global();
+        assert_eq!(circuit.tcount() + reduction, before);
+        verify_quizx(&original, &circuit);
+    }
+}
+
+#[test]
+fn fold_phases_global_through_hadamards() {
+    // The T-dagger is on the same parity as the T, past a Hadamard on another qubit
+    // and an X, which the block-local folding can't see across
+    let mut circuit = Circuit::new(vec![
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::H(Qubit(1)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::X(Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::H(Qubit(1)),
+        Gate::Phase(Phase::T, Qubit(0))
+    ]);
+    let original = circuit.clone();
+    assert_eq!(circuit.fold_phases_global(), 2);
+    assert_eq!(circuit.tcount(), 1);
+    verify_quizx(&original, &circuit);
+
+    // A Hadamard on the qubit itself starts a new parity
+    let mut circuit = Circuit::new(vec![
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::H(Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(0))
+    ]);
+    assert_eq!(circuit.fold_phases_global(), 0);
+}
+
+#[test]
+fn x_corrections_random() {
+    for _ in 0..200 {
+        let mut circuit = random_circuit(5, 80, 0.1, 0.3);
+        for i in (0..circuit.gates.len()).step_by(4).rev() {
+            circuit.gates.insert(i, Gate::X(Qubit(rand::random::<usize>() % 5)));
+        }
+        let mut new = circuit.partition();
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        assert_eq!(new.x_corrections.len(), (new.blocks.len() + 1) / 2);
+
+        // Each correction is applied at the start of the following Clifford block
+        for (j, xs) in new.x_corrections.iter().enumerate() {
+            let next = new.blocks.get(2 * j + 1).unwrap_or(&new.back);
+            let expected = xs.iter().map(|&q| Gate::X(q)).collect::<Vec<_>>();
+            assert_eq!(format!("{:?}", &next.gates[..xs.len()]), format!("{:?}", expected));
+            assert!(new.blocks[2 * j].gates.iter().all(|g| !matches!(g, Gate::X(_))));
+        }
+    }
+}
+
+#[test]
+fn validate_ancillas_random() {
+    for _ in 0..200 {
+        let mut circuit = random_circuit(5, 80, 0.1, 0.3);
+        circuit.move_h_optimal();
+        let mut new = circuit.partition();
+        new.pick_gadgets(3, 20);
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        assert_eq!(new.validate_ancillas(), Ok(()));
+    }
+
+    // Qubit 1 is only touched by the front Cliffords, so it must not be reused as an ancilla
+    let mut new = crate::decompose::PartitionedCircuit {
+        front: Circuit::new(vec![Gate::CNOT(Qubit(0), Qubit(1))]),
+        back: Circuit::new(vec![]),
+        blocks: vec![Circuit::new(vec![Gate::H(Qubit(0)), Gate::Phase(Phase::T, Qubit(0)), Gate::H(Qubit(0))])],
+        ancilla: Vec::new(),
+        x_corrections: Vec::new()
+    };
+    new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+    assert!(new.ancilla.iter().all(|q| q.0 >= 2));
+    assert_eq!(new.validate_ancillas(), Ok(()));
+    new.ancilla.push(Qubit(1));
+    assert!(new.validate_ancillas().is_err());
+}
+
 #[test]
 #[serial]
 fn end_to_end_random() {
@@ -106,12 +488,192 @@ fn end_to_end_random() {
         circuit.move_h_optimal();
         let mut new = circuit.partition();
         new.pick_gadgets(5, 100);
-        new.to_cnot_phase();
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
         for (i, (map, mat)) in new.extract_gadgets().into_iter().enumerate() {
-            let (resynth, _, _, _) = extract::extract_gadgets(&mat, &map, true);
+            let (resynth, _, _, _) = extract::extract_gadgets(&mat, &map, Some(extract::GadgetCosts::default()), extract::GadgetOrder::None, None);
             new.blocks[2*i] = resynth;
         }
         let new = new.merge();
         verify_quizx(&original, &new);
     });
 }
+
+#[test]
+#[serial]
+fn extract_gadgets_cnot_synth_random() {
+    let q = 6;
+    let g = 150;
+    let k = 300;
+    parallel_iters(k, "extract_gadgets_cnot_synth_random", |i| {
+        let strategy = [extract::LinearSynthStrategy::Naive, extract::LinearSynthStrategy::Pmh, extract::LinearSynthStrategy::Gauss][i % 3];
+        let mut circuit = random_circuit(q, g, 0.1, 0.3);
+        let original = circuit.clone();
+        circuit.move_h_optimal();
+        let mut new = circuit.partition();
+        new.pick_gadgets(5, 100);
+        new.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        for (i, (map, mat)) in new.extract_gadgets_with(strategy).into_iter().enumerate() {
+            let (resynth, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+            new.blocks[2*i] = resynth;
+        }
+        verify_quizx(&original, &new.merge());
+    });
+}
+
+#[test]
+fn slice_by_t_depth_random() {
+    let q = 7;
+    let g = 200;
+    for max in 1..10 {
+        let circuit = random_circuit(q, g, 0.1, 0.3);
+        let slices = circuit.slice_by_t_depth(max);
+        assert!(slices.iter().all(|s| s.gates.iter().filter(|g| !g.is_clifford()).count() <= max));
+
+        let mut merged = Circuit::new(Vec::new());
+        for slice in slices {
+            merged.merge(slice);
+        }
+        assert_eq!(format!("{:?}", merged.gates), format!("{:?}", circuit.gates));
+    }
+}
+
+#[test]
+#[serial]
+fn extract_pauli_frame_random() {
+    let q = 7;
+    let g = 50;
+    let k = 1000;
+    parallel_iters(k, "extract_pauli_frame_random", |_| {
+        let mut circuit = random_circuit(q, g, 0.2, 0.3);
+        circuit.merge(random_not_h(q, g));
+        circuit.gates.push(Gate::CZ(Qubit(0), Qubit(1)));
+        let original = circuit.clone();
+        let frame = circuit.extract_pauli_frame();
+        assert!(circuit.gates.iter().all(|g| !matches!(g, Gate::X(_) | Gate::Phase(Phase::Z, _))));
+        circuit.merge(frame.to_circuit());
+        verify_quizx(&original, &circuit);
+    });
+}
+
+#[test]
+#[serial]
+fn resynth_mapping_random() {
+    let q = 8;
+    let g = 50;
+    let k = 1000;
+    parallel_iters(k, "resynth_mapping_random", |_| {
+        // Shift the circuit up so that the low qubits are unused and the mapping is not the identity
+        let mut circuit = random_not_h(q - 2, g);
+        circuit.gates.iter_mut().for_each(|g| g.map_qubits(|Qubit(i)| Qubit(i + 2)));
+        circuit.to_cnot_phase();
+        let (map, mat, _) = circuit.extract_gadgets();
+        assert!(map.iter().all(|&i| i >= 2));
+
+        let costs = Some(extract::GadgetCosts::default());
+        let (resynth, _, _, _) = extract::extract_gadgets(&mat, &map, costs, extract::GadgetOrder::None, None);
+        let identity = (0..map.len()).collect::<Vec<_>>();
+        let (unmapped, _, _, _) = extract::extract_gadgets(&mat, &identity, costs, extract::GadgetOrder::None, None);
+        assert_eq!(format!("{:?}", resynth.gates), format!("{:?}", unmapped.rebase(&map).gates));
+        assert!(verify_feynver(&circuit, &resynth, q));
+    });
+}
+
+#[test]
+fn signature_tensor_random() {
+    let q = 8;
+    let g = 60;
+    for _ in 0..200 {
+        let mut circuit = random_not_h(q, g);
+        circuit.to_cnot_phase();
+        let tensor = circuit.signature_tensor();
+        let (map, mat, _) = circuit.clone().extract_gadgets();
+        let expected = extract::find_signature_tensor(&mat);
+        let n = tensor.shape()[0];
+        for i in 0..n {
+            for j in 0..n {
+                for k in 0..n {
+                    let value = match (map.iter().position(|&m| m == i), map.iter().position(|&m| m == j), map.iter().position(|&m| m == k)) {
+                        (Some(a), Some(b), Some(c)) => expected[(a, b, c)],
+                        _ => false
+                    };
+                    assert_eq!(tensor[(i, j, k)], value);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn block_dependencies_random() {
+    let q = 8;
+    let g = 60;
+    for _ in 0..200 {
+        let mut partitioned = random_circuit(q, g, 0.1, 0.3).partition();
+        // Add an independent block on a fresh qubit which only the back depends on
+        partitioned.blocks.push(Circuit::new(vec![Gate::Phase(Phase::T, Qubit(q))]));
+        partitioned.blocks.push(Circuit::new(Vec::new()));
+        partitioned.back.gates.push(Gate::H(Qubit(q)));
+
+        let deps = partitioned.block_dependencies();
+        let n = deps.len();
+        assert_eq!(n, partitioned.blocks.len() + 2);
+        for (i, (qubits, depends)) in deps.iter().enumerate() {
+            for &j in depends {
+                assert!(j < i);
+                assert!(deps[j].0.iter().any(|q| qubits.contains(q)));
+            }
+        }
+        assert_eq!(deps[n - 3].0, vec![Qubit(q)]);
+        assert!(deps[n - 3].1.is_empty());
+        assert!(deps[n - 1].1.contains(&(n - 3)));
+    }
+}
+
+#[test]
+fn place_ancillas_random() {
+    let q = 6;
+    let g = 60;
+    for _ in 0..200 {
+        let mut partitioned = random_circuit(q, g, 0.2, 0.3).partition();
+        partitioned.pick_gadgets(usize::MAX, 10);
+        partitioned.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        let before = partitioned.merge();
+        let ancilla = partitioned.ancilla.clone();
+        let n = before.qubits();
+
+        // Place the ancillas at random distinct positions
+        use rand::seq::SliceRandom;
+        let mut positions = (0..n).collect::<Vec<_>>();
+        positions.shuffle(&mut rand::thread_rng());
+        positions.truncate(ancilla.len());
+
+        let layout = partitioned.place_ancillas(&positions);
+        assert_eq!(partitioned.ancilla, positions.iter().map(|&p| Qubit(p)).collect::<Vec<_>>());
+        assert_eq!(format!("{:?}", partitioned.merge().gates), format!("{:?}", before.rebase(&layout).gates));
+
+        // The layout is a permutation which keeps the data qubits in order
+        let mut sorted = layout.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..layout.len()).collect::<Vec<_>>());
+        let data = (0..n).filter(|i| !ancilla.contains(&Qubit(*i))).map(|i| layout[i]).collect::<Vec<_>>();
+        assert!(data.windows(2).all(|w| w[0] < w[1]));
+    }
+}
+
+#[test]
+fn merge_annotated() {
+    let circuit = random_circuit(5, 100, 0.1, 0.3);
+    let mut partitioned = circuit.partition();
+    partitioned.pick_gadgets(usize::MAX, 10);
+    let annotated = partitioned.merge_annotated();
+    assert_eq!(format!("{:?}", annotated.gates), format!("{:?}", partitioned.merge().gates));
+
+    let qasm = annotated.to_openqasm(false);
+    let qc = annotated.to_qc(5);
+    for j in 0..partitioned.blocks.len() + 2 {
+        assert!(qasm.contains(&format!("// block {} begin\n", j)));
+        assert!(qasm.contains(&format!("// block {} end\n", j)));
+        assert!(qc.contains(&format!("# block {} begin\n", j)));
+        assert!(qc.contains(&format!("# block {} end\n", j)));
+    }
+}
@@ -0,0 +1,129 @@
+use super::verify_quizx;
+use crate::{circuit::{Circuit, Gate, Phase, Qubit}, decomposition::{Decomposition, DecompositionTable, GateKind}};
+
+/// CS via the phase polynomial x0 + x1 - (x0 ^ x1), rather than the built-in ordering.
+fn custom_cs() -> Decomposition {
+    Decomposition::Custom(vec![
+        Gate::Phase(Phase::T, Qubit(0)),
+        Gate::Phase(Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(0), Qubit(1)),
+        Gate::Phase(-Phase::T, Qubit(1)),
+        Gate::CNOT(Qubit(0), Qubit(1))
+    ])
+}
+
+fn random_configurable(q: usize, g: usize) -> Circuit {
+    let qubit = || Qubit(rand::random::<usize>() % q);
+    let mut gates = Vec::new();
+    while gates.len() < g {
+        let (a, b, c) = (qubit(), qubit(), qubit());
+        if a == b || a == c || b == c { continue }
+        gates.push(match rand::random::<usize>() % 6 {
+            0 => Gate::CZ(a, b),
+            1 => Gate::CS(a, b),
+            2 => Gate::CCZ(a, b, c),
+            3 => Gate::SWAP(a, b),
+            4 => Gate::CNOT(a, b),
+            _ => Gate::Phase(Phase(rand::random::<usize>() % 8), a)
+        });
+    }
+    Circuit::new(gates)
+}
+
+#[test]
+fn custom_decompositions_lowering() {
+    let mut table = DecompositionTable::default();
+    table.set(GateKind::CS, custom_cs()).unwrap();
+    // Custom decompositions may use other configurable gates, which are lowered in turn
+    table.set(GateKind::CCZ, Decomposition::Custom(vec![
+        Gate::CS(Qubit(0), Qubit(1)),
+        Gate::CNOT(Qubit(1), Qubit(2)),
+        Gate::Phase(Phase::T, Qubit(2)),
+        Gate::CNOT(Qubit(0), Qubit(2)),
+        Gate::Phase(-Phase::T, Qubit(2)),
+        Gate::CNOT(Qubit(1), Qubit(2)),
+        Gate::Phase(Phase::T, Qubit(2)),
+        Gate::CNOT(Qubit(0), Qubit(2)),
+        Gate::Phase(-Phase::T, Qubit(2)),
+        Gate::CS(Qubit(0), Qubit(1)),
+        Gate::CS(Qubit(0), Qubit(1))
+    ])).unwrap();
+
+    for _ in 0..50 {
+        let original = random_configurable(4, 20);
+        let n = original.qubits();
+
+        let qc = Circuit::from_qc(&original.to_qc_with_table(n, &table)).unwrap();
+        assert_eq!(qc.num_cs(), 0);
+        verify_quizx(&original, &qc);
+
+        let qasm = original.to_openqasm_with_table(false, &table);
+        assert!(!qasm.contains("cs ") && !qasm.contains("ccz "));
+
+        let zx = Circuit::from_zx(original.to_zx_with(&table));
+        assert_eq!(zx.num_ccz(), 0);
+        verify_quizx(&original, &zx);
+
+        let mut lowered = original.clone();
+        let xswaps = lowered.to_cnot_phase_with(&table);
+        lowered.merge(xswaps);
+        verify_quizx(&original, &lowered);
+    }
+}
+
+#[test]
+fn native_decompositions() {
+    let circuit = Circuit::new(vec![
+        Gate::CZ(Qubit(0), Qubit(1)),
+        Gate::CS(Qubit(1), Qubit(2)),
+        Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)),
+        Gate::SWAP(Qubit(0), Qubit(2))
+    ]);
+
+    let mut table = DecompositionTable::default();
+    for kind in GateKind::ALL {
+        table.set(kind, Decomposition::Native).unwrap();
+    }
+    let qc = circuit.to_qc_with_table(3, &table);
+    for line in ["cz 0 1", "cs 1 2", "ccz 0 1 2", "swap 0 2"] {
+        assert!(qc.contains(line), "{} missing from {}", line, qc);
+    }
+    verify_quizx(&circuit, &Circuit::from_qc(&qc).unwrap());
+
+    let qasm = circuit.to_openqasm_with_table(false, &table);
+    for line in ["cz q[0], q[1];", "cs q[1], q[2];", "ccz q[0], q[1], q[2];", "swap q[0], q[2];", "opaque cs a, b;", "opaque swap a, b;"] {
+        assert!(qasm.contains(line), "{} missing from {}", line, qasm);
+    }
+
+    // CNOT + Phase has no native gates, so the built-in decompositions are used
+    let mut lowered = circuit.clone();
+    let xswaps = lowered.to_cnot_phase_with(&table);
+    assert!(lowered.gates.iter().all(|g| matches!(g, Gate::CNOT(_, _) | Gate::Phase(_, _))));
+    lowered.merge(xswaps);
+    verify_quizx(&circuit, &lowered);
+}
+
+#[test]
+fn invalid_decompositions() {
+    let mut table = DecompositionTable::default();
+    assert!(table.set(GateKind::CZ, Decomposition::Custom(vec![Gate::H(Qubit(2))])).is_err());
+    assert!(table.set(GateKind::CZ, Decomposition::Custom(vec![Gate::Phase(Phase(8), Qubit(0))])).is_err());
+    // Hadamards can't be lowered to CNOT + Phase, even in a correct decomposition
+    assert!(table.set(GateKind::CZ, Decomposition::Custom(vec![Gate::H(Qubit(1)), Gate::CNOT(Qubit(0), Qubit(1)), Gate::H(Qubit(1))])).is_err());
+    // Sequences that don't implement the gate are rejected
+    assert!(table.set(GateKind::CZ, Decomposition::Custom(vec![Gate::CS(Qubit(0), Qubit(1))])).is_err());
+    assert!(table.set(GateKind::SWAP, Decomposition::Custom(vec![Gate::CNOT(Qubit(0), Qubit(1)), Gate::CNOT(Qubit(1), Qubit(0))])).is_err());
+    assert!(matches!(table.get(GateKind::CZ), Decomposition::Builtin));
+
+    // Cycles are rejected, and leave the table as it was
+    table.set(GateKind::CZ, Decomposition::Custom(vec![Gate::CS(Qubit(0), Qubit(1)), Gate::CS(Qubit(0), Qubit(1))])).unwrap();
+    assert!(table.set(GateKind::CS, Decomposition::Custom(vec![Gate::CZ(Qubit(1), Qubit(0))])).is_err());
+    assert!(matches!(table.get(GateKind::CS), Decomposition::Builtin));
+    assert!(table.set(GateKind::SWAP, Decomposition::Custom(vec![Gate::SWAP(Qubit(1), Qubit(0))])).is_err());
+
+    let table = DecompositionTable::from_json(r#"{"CCZ": "native", "SWAP": {"custom": [{"CNOT": [1, 0]}, {"CNOT": [0, 1]}, {"CNOT": [1, 0]}]}}"#).unwrap();
+    assert!(table.is_native(GateKind::CCZ));
+    assert_eq!(table.expand(Gate::SWAP(Qubit(3), Qubit(5))).len(), 3);
+    assert!(DecompositionTable::from_json(r#"{"CZ": {"custom": [{"H": 1}, {"CNOT": [0, 1]}, {"H": 1}]}}"#).is_err());
+    assert!(DecompositionTable::from_json(r#"{"CZ": {"custom": [{"CNOT": [0, 2]}]}}"#).is_err());
+}
@@ -25,8 +25,8 @@ fn ccz_gadget_random() {
             (&a ^ &b ^ &c).view()
         ]).unwrap();
 
-        let (circ1, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), true);
-        let (circ2, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), false);
+        let (circ1, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), Some(extract::GadgetCosts::default()), extract::GadgetOrder::None, None);
+        let (circ2, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), None, extract::GadgetOrder::None, None);
 
         assert!(verify_feynver(&circ1, &circ2, n));
     });
@@ -50,10 +50,379 @@ fn cs_gadget_random() {
             a.view(), b.view(), (&a ^ &b).view()
         ]).unwrap();
 
-        let (circ1, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), true);
-        let (circ2, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), false);
+        let (circ1, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), Some(extract::GadgetCosts::default()), extract::GadgetOrder::None, None);
+        let (circ2, _, _, _) = extract::extract_gadgets(&mat, &(0..n).collect::<Vec<_>>(), None, extract::GadgetOrder::None, None);
 
         assert!(verify_feynver(&circ1, &circ2, circ1.qubits()));
     });
 }
 
+
+#[test]
+#[serial]
+fn gadget_order_random() {
+    let n = 6;
+    let r = 20;
+    let k = 1000;
+    parallel_iters(k, "gadget_order_random", |_| {
+        let mat = loop {
+            let mat = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+            if extract::has_zero_columns(&mat) { continue }
+            break mat;
+        };
+        let map = (0..n).collect::<Vec<_>>();
+        let cnots = |c: &crate::circuit::Circuit| c.gates.iter()
+            .filter(|g| matches!(g, crate::circuit::Gate::CNOT(_, _)))
+            .count();
+
+        let (base, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+        for order in [extract::GadgetOrder::SortByParity, extract::GadgetOrder::GreedySharedPrefix] {
+            let (circ, _, _, _) = extract::extract_gadgets(&mat, &map, None, order, None);
+            assert!(cnots(&circ) <= cnots(&base));
+            assert!(verify_feynver(&base, &circ, n));
+        }
+    });
+}
+
+#[test]
+fn matrices_equivalent_permuted() {
+    let n = 6;
+    let r = 12;
+    let map = (0..n).collect::<Vec<_>>();
+    for _ in 0..100 {
+        let a = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+        let mut b = a.clone();
+        b.invert_axis(nd::Axis(1));
+        assert!(extract::matrices_equivalent(&a, &b, &map));
+
+        // Repeating columns three times preserves the signature tensor but not the unitary
+        let x = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+        let y = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+        if x == y || x.iter().all(|&v| !v) || y.iter().all(|&v| !v) { continue }
+        let c = nd::stack(nd::Axis(1), &[x.view(), y.view(), (&x ^ &y).view()]).unwrap();
+        let d = nd::stack(nd::Axis(1), &[x.view(), y.view(), (&x ^ &y).view(), x.view(), y.view(), (&x ^ &y).view(), x.view(), y.view(), (&x ^ &y).view()]).unwrap();
+        assert_eq!(extract::find_signature_tensor(&c), extract::find_signature_tensor(&d));
+        assert!(!extract::matrices_equivalent(&c, &d, &map));
+    }
+}
+
+#[test]
+#[serial]
+fn gadget_costs_random() {
+    let n = 8;
+    let k = 1000;
+    parallel_iters(k, "gadget_costs_random", |_| {
+        let (a, b, c) = loop {
+            let a = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            let b = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            let c = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            if a.iter().all(|&x| !x) || b.iter().all(|&x| !x) || c.iter().all(|&x| !x) { continue }
+            if a == b || a == c || b == c || (&a ^ &b) == c { continue }
+            break (a, b, c);
+        };
+
+        let mat = nd::stack(nd::Axis(1), &[
+            a.view(), b.view(), c.view(),
+            (&a ^ &b).view(), (&a ^ &c).view(), (&b ^ &c).view(),
+            (&a ^ &b ^ &c).view(),
+            a.view(), b.view(), (&a ^ &b).view()
+        ]).unwrap();
+        let map = (0..n).collect::<Vec<_>>();
+
+        let (base, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+        let cheap = extract::GadgetCosts::default();
+        let no_ccz = extract::GadgetCosts { ccz: 10.0, ..cheap };
+        let no_cs = extract::GadgetCosts { cs: 10.0, ..cheap };
+        for (costs, ccz, cs) in [(cheap, 1, 1), (no_ccz, 0, 1), (no_cs, 1, 0)] {
+            let (circ, nccz, ncs, _) = extract::extract_gadgets(&mat, &map, Some(costs), extract::GadgetOrder::None, None);
+            assert_eq!((nccz, ncs), (ccz, cs));
+            assert!(verify_feynver(&base, &circ, n));
+        }
+    });
+}
+
+#[test]
+#[serial]
+fn fixed_target_random() {
+    let n = 6;
+    let r = 10;
+    let k = 1000;
+    parallel_iters(k, "fixed_target_random", |_| {
+        let mat = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+        let map = (0..n).collect::<Vec<_>>();
+        let (base, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+        let target = rand::random::<usize>() % (n + 1);
+        let (circ, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, Some(target));
+        assert!(circ.gates.iter().all(|g| !matches!(g, crate::circuit::Gate::Phase(_, q) if q.0 != target)));
+        assert!(verify_feynver(&base, &circ, n));
+    });
+}
+
+#[test]
+#[should_panic]
+fn fixed_target_unmapped_data_qubit() {
+    // Qubit 1 belongs to the circuit even though this block doesn't touch it
+    let mat = nd::Array2::from_elem((3, 4), true);
+    extract::extract_gadgets(&mat, &[0, 2, 3], None, extract::GadgetOrder::None, Some(1));
+}
+
+#[test]
+fn consumed_columns_random() {
+    let n = 5;
+    for _ in 0..1000 {
+        let r = rand::random::<usize>() % 30;
+        let mut mat = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+        // Plant some CS windows so that gadgets are actually found
+        for l in (0..r.saturating_sub(2)).step_by(5) {
+            let x = mat.column(l).to_owned() ^ mat.column(l + 1);
+            mat.column_mut(l + 2).assign(&x);
+        }
+        let map = (0..n).collect::<Vec<_>>();
+        let (_, nccz, ncs, nt) = extract::extract_gadgets(&mat, &map, Some(extract::GadgetCosts::default()), extract::GadgetOrder::None, None);
+        assert_eq!(nt + 7 * nccz + 3 * ncs, r);
+    }
+}
+
+#[test]
+#[serial]
+fn grouped_gadget_columns_random() {
+    let n = 8;
+    let k = 1000;
+    parallel_iters(k, "grouped_gadget_columns_random", |_| {
+        let (a, b, c) = loop {
+            let a = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            if a.iter().all(|&x| x == false) { continue }
+            let b = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            if a == b || b.iter().all(|&x| x == false) { continue; }
+            let c = nd::Array1::<bool>::from_shape_simple_fn(n, rand::random);
+            if a == c || b == c || c.iter().all(|&x| x == false) || a.iter().zip(&b).zip(&c).all(|((&x, &y), &z)| x ^ y == z) { continue; }
+            break (a, b, c);
+        };
+
+        // A CCZ and a CS gadget with their columns shuffled together
+        let ccz = [a.clone(), b.clone(), c.clone(), &a ^ &b, &a ^ &c, &b ^ &c, &a ^ &b ^ &c];
+        let cs = [b.clone(), c.clone(), &b ^ &c];
+        let mut cols = ccz.iter().chain(&cs).map(|c| c.view()).collect::<Vec<_>>();
+        use rand::seq::SliceRandom;
+        cols.shuffle(&mut rand::thread_rng());
+        let mat = nd::stack(nd::Axis(1), &cols).unwrap();
+
+        let map = (0..n).collect::<Vec<_>>();
+        let costs = Some(extract::GadgetCosts::default());
+        let grouped = extract::group_gadget_columns(&mat, mat.shape()[1]);
+        assert_eq!(extract::find_signature_tensor(&grouped), extract::find_signature_tensor(&mat));
+        assert!(extract::matrices_equivalent(&grouped, &mat, &map));
+
+        let (circ1, nccz, ncs, nt) = extract::extract_gadgets(&grouped, &map, costs, extract::GadgetOrder::None, None);
+        assert_eq!((nccz, ncs, nt), (1, 1, 0));
+        let (circ2, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+        assert!(verify_feynver(&circ1, &circ2, n));
+    });
+}
+
+#[test]
+fn small_circuits() {
+    use crate::circuit::{Circuit, Gate, Phase, Qubit};
+    let costs = Some(extract::GadgetCosts::default());
+
+    // No qubits at all
+    let mut empty = Circuit::new(Vec::new());
+    let (map, mat, cliffords) = empty.extract_gadgets();
+    assert!(map.is_empty() && mat.is_empty() && cliffords.gates.is_empty());
+    let (circ, nccz, ncs, nt) = extract::extract_gadgets(&mat, &map, costs, extract::GadgetOrder::None, None);
+    assert!(circ.gates.is_empty());
+    assert_eq!((nccz, ncs, nt), (0, 0, 0));
+
+    // Only Clifford phases, so there are no T gadgets
+    let mut clifford = Circuit::new(vec![Gate::Phase(Phase::S, Qubit(0))]);
+    let (map, mat, _) = clifford.extract_gadgets();
+    assert_eq!(mat.shape()[1], 0);
+    assert!(!extract::has_zero_columns(&mat));
+    let (_, _, _, nt) = extract::extract_gadgets(&mat, &map, costs, extract::GadgetOrder::None, None);
+    assert_eq!(nt, 0);
+
+    // One and two qubits, where no CCZ or CS gadget windows can match
+    let circuits = [
+        vec![Gate::Phase(Phase::T, Qubit(0))],
+        vec![Gate::Phase(Phase(3), Qubit(0)), Gate::Phase(Phase::T, Qubit(0))],
+        vec![Gate::Phase(Phase::T, Qubit(1))],
+        vec![Gate::Phase(Phase::T, Qubit(0)), Gate::CNOT(Qubit(0), Qubit(1)), Gate::Phase(Phase::T, Qubit(1)), Gate::CNOT(Qubit(0), Qubit(1)), Gate::Phase(Phase::T, Qubit(1))]
+    ];
+    for gates in circuits {
+        let mut circuit = Circuit::new(gates);
+        let (map, mat, _) = circuit.extract_gadgets();
+        assert_eq!(mat.shape()[0], map.len());
+        for order in [extract::GadgetOrder::None, extract::GadgetOrder::SortByParity, extract::GadgetOrder::GreedySharedPrefix] {
+            let (resynth, _, _, nt) = extract::extract_gadgets(&mat, &map, costs, order, None);
+            assert_eq!(nt, mat.shape()[1]);
+            assert!(verify_feynver(&circuit, &resynth, circuit.qubits()));
+        }
+    }
+
+    // Zero columns must not be mistaken for part of a gadget
+    let mat = nd::arr2(&[[false, true, true], [false, false, true]]);
+    extract::extract_gadgets(&mat, &[0, 1], costs, extract::GadgetOrder::None, None);
+    let mat = nd::arr2(&[[false, true, true]]);
+    extract::extract_gadgets(&mat, &[0], costs, extract::GadgetOrder::None, None);
+}
+
+#[test]
+#[serial]
+fn signed_gadgets_random() {
+    let n = 6;
+    let r = 15;
+    let k = 1000;
+    parallel_iters(k, "signed_gadgets_random", |_| {
+        let mat = loop {
+            let mat = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+            if extract::has_zero_columns(&mat) { continue }
+            break mat;
+        };
+        let signs = (0..r).map(|_| rand::random::<bool>()).collect::<Vec<_>>();
+        let map = (0..n).collect::<Vec<_>>();
+
+        let (signed, _, _, nt) = extract::extract_signed_gadgets(&mat, &signs, &map, None, extract::GadgetOrder::None, None);
+        assert_eq!(nt, r);
+
+        // The same as synthesizing with T gadgets only and then correcting by a Clifford
+        let (mut unsigned, _, _, _) = extract::extract_gadgets(&mat, &map, None, extract::GadgetOrder::None, None);
+        unsigned.merge(extract::clifford_correction(&mat, &extract::expand_signs(&mat, &signs), &map));
+        assert!(signed.gates.len() <= unsigned.gates.len());
+        assert!(verify_feynver(&signed, &unsigned, n));
+    });
+}
+
+#[test]
+fn packed_tensor_random() {
+    for n in 0..8 {
+        for _ in 0..20 {
+            let mat = nd::Array2::<bool>::from_shape_simple_fn((n, 12), rand::random);
+            let tensor = extract::find_signature_tensor(&mat);
+            let packed = extract::pack_symmetric_tensor(&tensor);
+            assert_eq!(packed.len(), n * (n + 1) * (n + 2) / 6);
+            assert_eq!(extract::unpack_symmetric_tensor(&packed), Some(tensor));
+        }
+    }
+
+    assert!(extract::unpack_symmetric_tensor(&nd::Array1::from_elem(5, false)).is_none());
+}
+
+#[test]
+fn gf2_rank_random() {
+    let identity = nd::Array2::from_shape_fn((5, 5), |(i, j)| i == j);
+    assert_eq!(extract::gf2_rank(&identity), 5);
+    assert_eq!(extract::gf2_rank(&nd::Array2::from_elem((4, 6), false)), 0);
+
+    for _ in 0..200 {
+        // Appending sums of existing columns never changes the rank
+        let a = nd::Array2::<bool>::from_shape_simple_fn((8, 5), rand::random);
+        let rank = extract::gf2_rank(&a);
+        assert!(rank <= 5);
+        let sums = nd::Array2::from_shape_fn((8, 3), |(i, l)| a[(i, l)] ^ a[(i, l + 1)] ^ a[(i, l + 2)]);
+        let extended = nd::concatenate(nd::Axis(1), &[a.view(), sums.view()]).unwrap();
+        assert_eq!(extract::gf2_rank(&extended), rank);
+        assert_eq!(extract::gf2_rank(&a.t().to_owned()), rank);
+    }
+}
+
+#[test]
+fn optimal_decomposition_random() {
+    // Repeated columns cancel in the signature tensor
+    let repeated = nd::arr2(&[[true, true, false], [false, false, true], [true, true, false]]);
+    let optimal = extract::optimal_decomposition(&repeated).unwrap();
+    assert_eq!(optimal.shape(), &[3, 1]);
+    assert_eq!(extract::find_signature_tensor(&optimal), extract::find_signature_tensor(&repeated));
+
+    for _ in 0..100 {
+        let n = 1 + rand::random::<usize>() % 4;
+        let r = rand::random::<usize>() % 7;
+        let a = nd::Array2::<bool>::from_shape_simple_fn((n, r), rand::random);
+        let optimal = extract::optimal_decomposition(&a).unwrap();
+        assert!(optimal.shape()[1] <= r);
+        assert_eq!(extract::find_signature_tensor(&optimal), extract::find_signature_tensor(&a));
+    }
+
+    let large = nd::Array2::from_elem((extract::MAX_OPTIMAL_QUBITS + 1, 2), true);
+    assert!(extract::optimal_decomposition(&large).is_none());
+    // Only the empty set fits in the budget, so the search gives up
+    assert!(extract::optimal_decomposition_with(&repeated, 1).is_none());
+    assert!(extract::optimal_decomposition_with(&repeated, 1 << 20).is_some());
+}
+
+#[test]
+fn linear_synth_random() {
+    use crate::circuit::{Gate, Qubit};
+    assert!(extract::LinearSynthStrategy::Naive.synthesize(&nd::Array2::from_elem((3, 3), false)).is_none());
+
+    for _ in 0..200 {
+        // Build a random invertible matrix from a random CNOT circuit
+        let n = 2 + rand::random::<usize>() % 8;
+        let mut matrix = nd::Array2::from_shape_fn((n, n), |(i, j)| i == j);
+        for _ in 0..rand::random::<usize>() % 40 {
+            let (c, t) = (rand::random::<usize>() % n, rand::random::<usize>() % n);
+            if c != t {
+                let control = matrix.row(c).to_owned();
+                matrix.row_mut(t).zip_mut_with(&control, |a, &b| *a ^= b);
+            }
+        }
+
+        for strategy in [extract::LinearSynthStrategy::Pmh, extract::LinearSynthStrategy::Gauss] {
+            let gates = strategy.synthesize(&matrix).unwrap();
+            let mut result = nd::Array2::from_shape_fn((n, n), |(i, j)| i == j);
+            for gate in gates {
+                let Gate::CNOT(Qubit(c), Qubit(t)) = gate else { panic!("Non-CNOT gate {:?} in synthesized circuit", gate) };
+                let control = result.row(c).to_owned();
+                result.row_mut(t).zip_mut_with(&control, |a, &b| *a ^= b);
+            }
+            assert_eq!(result, matrix, "{:?} synthesized the wrong linear map", strategy);
+        }
+    }
+}
+
+#[test]
+fn format_phase_polynomial() {
+    let mut s = nd::Array3::from_elem((4, 4, 4), 0);
+    assert_eq!(extract::format_phase_polynomial(&s, &[0, 1, 2, 3]), "0");
+
+    s[(2, 1, 0)] = 3;
+    s[(1, 0, 0)] = 2;
+    s[(3, 3, 3)] = 1;
+    assert_eq!(extract::format_phase_polynomial(&s, &[0, 1, 2, 3]), "3·x0·x1·x2 + 2·x0·x1 + x3");
+    assert_eq!(extract::format_phase_polynomial(&s, &[4, 6, 7, 9]), "3·x4·x6·x7 + 2·x4·x6 + x9");
+}
+
+#[test]
+fn waring_rank_bound_random() {
+    // Equal columns cancel, and zero columns don't contribute
+    let a = nd::array![[true, true, false, true], [false, false, false, true], [true, true, false, false]];
+    assert_eq!(extract::waring_rank_bound(&a), (1, 1));
+
+    for _ in 0..100 {
+        let a = nd::Array2::<bool>::from_shape_simple_fn((6, 12), rand::random);
+        let (lower, upper) = extract::waring_rank_bound(&a);
+        assert!(lower <= upper);
+        assert!(upper <= 12);
+    }
+}
+
+#[test]
+fn waring_decompose_random() {
+    for _ in 0..100 {
+        let a = nd::Array2::<bool>::from_shape_simple_fn((6, 12), rand::random);
+        let tensor = extract::find_signature_tensor(&a);
+        let b = extract::waring_decompose(&tensor).expect("A signature tensor should decompose");
+        assert_eq!(extract::find_signature_tensor(&b), tensor);
+    }
+
+    // Not symmetric
+    let mut tensor = nd::Array3::from_elem((3, 3, 3), false);
+    tensor[(2, 1, 0)] = true;
+    assert!(extract::waring_decompose(&tensor).is_none());
+
+    // Symmetric, but t[1, 1, 0] != t[1, 0, 0]
+    let mut tensor = nd::Array3::from_elem((3, 3, 3), false);
+    tensor[(1, 1, 0)] = true;
+    tensor[(1, 0, 1)] = true;
+    tensor[(0, 1, 1)] = true;
+    assert!(extract::waring_decompose(&tensor).is_none());
+}
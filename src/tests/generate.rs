@@ -0,0 +1,18 @@
+use crate::{circuit::Gate, generate::random_circuit};
+use rand::SeedableRng;
+
+#[test]
+fn random_circuit_seeded() {
+    let generate = |seed| random_circuit(6, 200, 0.2, 0.3, &mut rand::rngs::StdRng::seed_from_u64(seed));
+    let (a, b, c) = (generate(7), generate(7), generate(8));
+    assert_eq!(a.to_qc(6), b.to_qc(6));
+    assert_ne!(a.to_qc(6), c.to_qc(6));
+
+    assert_eq!(a.gates.len(), 200);
+    assert!(a.qubits() <= 6);
+    assert!(a.gates.iter().all(|g| match g {
+        Gate::CNOT(c, t) => c != t,
+        Gate::H(_) | Gate::Phase(_, _) => true,
+        _ => false
+    }));
+}
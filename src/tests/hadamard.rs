@@ -1,6 +1,7 @@
-use crate::circuit::Circuit;
+use crate::{circuit::Circuit, hadamard::AncillaBasis};
 use serial_test::serial;
-use super::{random_circuit, verify_quizx, parallel_iters};
+use std::collections::HashSet;
+use super::{random_circuit, verify_quizx, verify_quizx_with, parallel_iters};
 
 #[test]
 #[serial]
@@ -12,9 +13,9 @@ fn hadamard_gadgetize_random() {
         let mut circuit = random_circuit(q, g, 0.25, 0.25);
         let original = circuit.clone();
         let mut next_id = q;
-        let mut front = Circuit { gates: Vec::new() };
-        let mut back = Circuit { gates: Vec::new() };
-        circuit.decomp_hads(&mut next_id, &mut front, &mut back);
+        let mut front = Circuit::new(Vec::new());
+        let mut back = Circuit::new(Vec::new());
+        circuit.decomp_hads(&mut next_id, &mut front, &mut back, AncillaBasis::Zero, &HashSet::new());
         front.merge(circuit).merge(back);
         let new = front;
 
@@ -22,6 +23,25 @@ fn hadamard_gadgetize_random() {
     });
 }
 
+#[test]
+#[serial]
+fn hadamard_gadgetize_plus_random() {
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    parallel_iters(k, "hadamard_gadgetize_plus_random", |_| {
+        let mut circuit = random_circuit(q, g, 0.25, 0.25);
+        let original = circuit.clone();
+        let mut next_id = q;
+        let mut front = Circuit::new(Vec::new());
+        let mut back = Circuit::new(Vec::new());
+        circuit.decomp_hads(&mut next_id, &mut front, &mut back, AncillaBasis::Plus, &HashSet::new());
+        assert!(front.gates.is_empty() && back.gates.is_empty());
+
+        verify_quizx_with(&original, &circuit, quizx::graph::BasisElem::X0);
+    });
+}
+
 #[test]
 #[serial]
 fn move_h_optimal_random() {
@@ -35,3 +55,40 @@ fn move_h_optimal_random() {
         verify_quizx(&original, &circuit);
     });
 }
+
+#[test]
+#[serial]
+fn hcount_matches_ancilla_random() {
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    parallel_iters(k, "hcount_matches_ancilla_random", |_| {
+        let circuit = random_circuit(q, g, 0.25, 0.25);
+        let hcount = circuit.hcount_accurate();
+        let mut partitioned = circuit.partition();
+        partitioned.pick_gadgets(usize::MAX, 1);
+        partitioned.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        assert_eq!(partitioned.ancilla.len(), hcount);
+    });
+}
+
+#[test]
+#[serial]
+fn hadamard_gadgetize_reserved_random() {
+    let q = 7;
+    let g = 100;
+    let k = 1000;
+    parallel_iters(k, "hadamard_gadgetize_reserved_random", |_| {
+        let mut circuit = random_circuit(q, g, 0.25, 0.25);
+        let original = circuit.clone();
+        let reserved = (q..q + 20).step_by(2).collect::<HashSet<_>>();
+        let mut next_id = q;
+        let mut front = Circuit::new(Vec::new());
+        let mut back = Circuit::new(Vec::new());
+        circuit.decomp_hads(&mut next_id, &mut front, &mut back, AncillaBasis::Zero, &reserved);
+        assert!(circuit.gates.iter().all(|g| g.qubits().iter().all(|q| !reserved.contains(&q.0))));
+        front.merge(circuit).merge(back);
+
+        verify_quizx(&original, &front);
+    });
+}
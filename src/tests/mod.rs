@@ -1,10 +1,15 @@
-use crate::circuit::{Gate, Phase, Qubit, Circuit};
+use crate::circuit::Circuit;
 use rayon::iter::{ParallelIterator, IntoParallelIterator};
 use std::io::Write;
 
+mod circuit;
 mod extract;
+mod generate;
 mod hadamard;
 mod decompose;
+mod decomposition;
+mod simulate;
+mod tableau;
 
 fn parallel_iters(k: usize, desc: &'static str, f: impl Fn(usize) + Send + Sync) {
     std::thread::sleep(std::time::Duration::new(0, 250000000));
@@ -17,19 +22,7 @@ fn parallel_iters(k: usize, desc: &'static str, f: impl Fn(usize) + Send + Sync)
 } 
 
 fn random_circuit(q: usize, g: usize, p_h: f32, p_phase: f32) -> Circuit {
-    let mut gates = Vec::new();
-    for _ in 0..g {
-        let r = rand::random::<f32>();
-        if r < p_h {
-            gates.push(Gate::H(Qubit(rand::random::<usize>() % q)));
-        } else if r < p_h + p_phase {
-            gates.push(Gate::Phase(Phase(rand::random::<usize>() % 8), Qubit(rand::random::<usize>() % q)));
-        } else {
-            let (i, j) = loop { let (i, j) = rand::random::<(usize, usize)>(); if i % q == j % q { continue } else { break (i % q, j % q) } };
-            gates.push(Gate::CNOT(Qubit(i), Qubit(j)));
-        }
-    }
-    Circuit { gates }
+    crate::generate::random_circuit(q, g, p_h, p_phase, &mut rand::thread_rng())
 }
 
 fn verify_feynver(original: &Circuit, new: &Circuit, qubits: usize) -> bool {
@@ -74,6 +67,11 @@ fn verify_feynver(original: &Circuit, new: &Circuit, qubits: usize) -> bool {
 }
 
 fn verify_quizx(a: &Circuit, b: &Circuit) {
+    verify_quizx_with(a, b, quizx::graph::BasisElem::Z0)
+}
+
+/// Verify equivalence, with any extra ancillas prepared and postselected in the given basis.
+fn verify_quizx_with(a: &Circuit, b: &Circuit, basis: quizx::graph::BasisElem) {
     use ndarray::Dimension;
     use quizx::{graph::GraphLike, tensor::ToTensor};
 
@@ -86,13 +84,13 @@ fn verify_quizx(a: &Circuit, b: &Circuit) {
     let mut gb = b.to_graph::<quizx::hash_graph::Graph>();
     if b.num_qubits() > a.num_qubits() {
         for i in (a.num_qubits()..b.num_qubits()).rev() {
-            gb.plug_input(i, quizx::graph::BasisElem::Z0);
-            gb.plug_output(i, quizx::graph::BasisElem::Z0);
+            gb.plug_input(i, basis);
+            gb.plug_output(i, basis);
         }
     } else {
         for i in (b.num_qubits()..a.num_qubits()).rev() {
-            ga.plug_input(i, quizx::graph::BasisElem::Z0);
-            ga.plug_output(i, quizx::graph::BasisElem::Z0);
+            ga.plug_input(i, basis);
+            ga.plug_output(i, basis);
         }
     }
     gb.adjoint();
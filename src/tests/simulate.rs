@@ -0,0 +1,113 @@
+use crate::{circuit::{Circuit, Gate, Phase, Qubit}, hadamard::AncillaBasis, simulate};
+use num_complex::Complex64;
+use ndarray as nd;
+use super::{parallel_iters, random_circuit};
+use serial_test::serial;
+use std::collections::HashSet;
+
+#[test]
+#[serial]
+fn probably_equal_random() {
+    let q = 6;
+    let g = 80;
+    let k = 200;
+    parallel_iters(k, "probably_equal_random", |_| {
+        let circuit = random_circuit(q, g, 0.2, 0.3);
+        let mut optimized = circuit.clone();
+        optimized.move_h_optimal();
+        assert_eq!(simulate::probably_equal(&circuit, &optimized, 8), Some(true));
+
+        let mut partitioned = circuit.clone().partition();
+        partitioned.pick_gadgets(usize::MAX, 10);
+        partitioned.to_cnot_phase(AncillaBasis::Zero, &HashSet::new());
+        assert_eq!(simulate::probably_equal(&circuit, &partitioned.merge(), 8), Some(true));
+
+        let mut phased = circuit.clone();
+        phased.global_phase = Phase(3);
+        assert_eq!(simulate::probably_equal(&circuit, &phased, 8), Some(true));
+
+        let mut wrong = circuit.clone();
+        let i = rand::random::<usize>() % (wrong.gates.len() + 1);
+        wrong.gates.insert(i, Gate::Phase(Phase::T, Qubit(rand::random::<usize>() % q)));
+        assert_eq!(simulate::probably_equal(&circuit, &wrong, 8), Some(false));
+    });
+}
+
+#[test]
+fn cnot_phase_form_random() {
+    let q = 6;
+    let g = 60;
+    for _ in 0..500 {
+        let circuit = random_circuit(q, g, 0.0, 0.3);
+        if circuit.qubits() != q {
+            continue
+        }
+        assert!(circuit.cnot_phase_form().is_some());
+
+        let mut wrong = circuit.clone();
+        let i = rand::random::<usize>() % (wrong.gates.len() + 1);
+        wrong.gates.insert(i, Gate::Phase(Phase::S, Qubit(rand::random::<usize>() % q)));
+        assert_eq!(simulate::probably_equal(&circuit, &wrong, 8), Some(false));
+
+        let mut reordered = circuit.clone();
+        reordered.gates.insert(0, Gate::Phase(Phase::T, Qubit(0)));
+        reordered.gates.insert(1, Gate::Phase(-Phase::T, Qubit(0)));
+        assert_eq!(simulate::probably_equal(&circuit, &reordered, 8), Some(true));
+    }
+}
+
+#[test]
+fn gate_unitaries() {
+    let c = |re: f64, im: f64| Complex64::new(re, im);
+    let close = |a: &nd::Array2<Complex64>, b: &nd::Array2<Complex64>| {
+        a.shape() == b.shape() && a.iter().zip(b).all(|(x, y)| (x - y).norm() < 1e-9)
+    };
+    let s = std::f64::consts::FRAC_1_SQRT_2;
+
+    let h = nd::arr2(&[[c(s, 0.0), c(s, 0.0)], [c(s, 0.0), c(-s, 0.0)]]);
+    assert!(close(&Gate::H(Qubit(3)).to_unitary(), &h));
+
+    let t = nd::arr2(&[[c(1.0, 0.0), c(0.0, 0.0)], [c(0.0, 0.0), c(s, s)]]);
+    assert!(close(&Gate::Phase(Phase::T, Qubit(0)).to_unitary(), &t));
+
+    // Control is the low bit, so |01> (index 1) maps to |11> (index 3)
+    let mut cnot = nd::Array2::zeros((4, 4));
+    for (i, j) in [(0, 0), (3, 1), (2, 2), (1, 3)] {
+        cnot[(i, j)] = c(1.0, 0.0);
+    }
+    assert!(close(&Gate::CNOT(Qubit(5), Qubit(2)).to_unitary(), &cnot));
+
+    let ccz = nd::Array2::from_diag(&nd::Array1::from_shape_fn(8, |i| c(if i == 7 { -1.0 } else { 1.0 }, 0.0)));
+    assert!(close(&Gate::CCZ(Qubit(0), Qubit(1), Qubit(2)).to_unitary(), &ccz));
+
+    let cs = nd::Array2::from_diag(&nd::Array1::from_shape_fn(4, |i| if i == 3 { c(0.0, 1.0) } else { c(1.0, 0.0) }));
+    assert!(close(&Gate::CS(Qubit(0), Qubit(1)).to_unitary(), &cs));
+}
+
+#[test]
+fn circuit_unitary_random() {
+    let q = 4;
+    let g = 40;
+    for _ in 0..100 {
+        let circuit = random_circuit(q, g, 0.2, 0.3);
+        let u = circuit.to_unitary().unwrap();
+        let n = 1 << circuit.qubits();
+
+        // Unitary, and agrees with applying the gates one at a time
+        let identity = u.t().mapv(|x| x.conj()).dot(&u);
+        assert!(identity.indexed_iter().all(|((i, j), x)| (x - if i == j { 1.0 } else { 0.0 }).norm() < 1e-9));
+
+        let mut product = nd::Array2::from_diag(&nd::Array1::from_elem(n, Complex64::new(1.0, 0.0)));
+        for &gate in &circuit.gates {
+            let mut single = Circuit::new(vec![gate, Gate::I(Qubit(circuit.qubits() - 1))]);
+            single.gates.push(Gate::I(Qubit(0)));
+            product = single.to_unitary().unwrap().dot(&product);
+        }
+        assert!(u.iter().zip(&product).all(|(x, y)| (x - y).norm() < 1e-9));
+    }
+
+    let mut large = Circuit::new(vec![Gate::H(Qubit(simulate::MAX_UNITARY_QUBITS))]);
+    assert!(large.to_unitary().is_none());
+    large.gates[0] = Gate::H(Qubit(simulate::MAX_UNITARY_QUBITS - 1));
+    assert!(large.to_unitary().is_some());
+}
@@ -0,0 +1,100 @@
+use crate::{circuit::{Circuit, Gate, Phase, Qubit}, tableau::Tableau};
+use num_complex::Complex64;
+use ndarray as nd;
+
+fn random_clifford(n: usize, g: usize) -> Circuit {
+    let mut gates = vec![Gate::I(Qubit(n - 1))];
+    for _ in 0..g {
+        let q = Qubit(rand::random::<usize>() % n);
+        let p = Qubit((q.0 + 1 + rand::random::<usize>() % (n - 1)) % n);
+        gates.push(match rand::random::<usize>() % 7 {
+            0 => Gate::H(q),
+            1 => Gate::Phase(Phase(2 * (rand::random::<usize>() % 4)), q),
+            2 => Gate::X(q),
+            3 => Gate::CNOT(q, p),
+            4 => Gate::CZ(q, p),
+            5 => Gate::SWAP(q, p),
+            _ => Gate::H(p)
+        });
+    }
+    Circuit::new(gates)
+}
+
+/// The dense matrix of the Pauli in the given tableau row, where qubit i is bit i.
+fn pauli_unitary(tableau: &Tableau, row: usize) -> nd::Array2<Complex64> {
+    let n = tableau.n;
+    let (mut xmask, mut zmask, mut ys) = (0, 0, 0);
+    for q in 0..n {
+        let (x, z) = (tableau.table[[row, q]], tableau.table[[row, n + q]]);
+        xmask |= (x as usize) << q;
+        zmask |= (z as usize) << q;
+        ys += (x & z) as usize;
+    }
+    let mut phase = Complex64::i().powu(ys as u32);
+    if tableau.table[[row, 2 * n]] {
+        phase = -phase;
+    }
+
+    let mut pauli = nd::Array2::zeros((1 << n, 1 << n));
+    for b in 0usize..1 << n {
+        let sign = if (zmask & b).count_ones() % 2 == 1 { -1.0 } else { 1.0 };
+        pauli[[b ^ xmask, b]] = phase * sign;
+    }
+    pauli
+}
+
+#[test]
+fn tableau_single_qubit() {
+    let h = Circuit::new(vec![Gate::H(Qubit(0))]).to_tableau(1).unwrap();
+    assert_eq!(h.table, nd::arr2(&[[false, true, false], [true, false, false]]));
+
+    // S maps X to Y and fixes Z
+    let s = Circuit::new(vec![Gate::Phase(Phase::S, Qubit(0))]).to_tableau(1).unwrap();
+    assert_eq!(s.table, nd::arr2(&[[true, true, false], [false, true, false]]));
+
+    // X flips the sign of Z
+    let x = Circuit::new(vec![Gate::X(Qubit(0))]).to_tableau(1).unwrap();
+    assert_eq!(x.table, nd::arr2(&[[true, false, false], [false, true, true]]));
+
+    assert!(Circuit::new(vec![Gate::Phase(Phase::T, Qubit(0))]).to_tableau(1).is_err());
+    assert!(Circuit::new(vec![Gate::H(Qubit(1))]).to_tableau(1).is_err());
+}
+
+#[test]
+fn tableau_equivalent_circuits() {
+    let (a, b) = (Qubit(0), Qubit(1));
+    let pairs = [
+        (vec![Gate::SWAP(a, b)], vec![Gate::CNOT(b, a), Gate::CNOT(a, b), Gate::CNOT(b, a)]),
+        (vec![Gate::CZ(a, b)], vec![Gate::CZ(b, a)]),
+        (vec![Gate::X(a)], vec![Gate::H(a), Gate::Phase(Phase::Z, a), Gate::H(a)]),
+        (vec![Gate::Phase(-Phase::S, a)], vec![Gate::Phase(Phase::S, a), Gate::Phase(Phase::S, a), Gate::Phase(Phase::S, a)]),
+        (vec![], vec![Gate::H(a), Gate::H(a), Gate::Phase(Phase::Z, b), Gate::Phase(Phase::Z, b)])
+    ];
+    for (x, y) in pairs {
+        assert_eq!(Circuit::new(x).to_tableau(2), Circuit::new(y).to_tableau(2));
+    }
+    assert_ne!(
+        Circuit::new(vec![Gate::CNOT(a, b)]).to_tableau(2),
+        Circuit::new(vec![Gate::CNOT(b, a)]).to_tableau(2)
+    );
+}
+
+#[test]
+fn tableau_random() {
+    // Each row must be the conjugate of X_i or Z_i by the circuit, i.e. U P_in = P_out U
+    for _ in 0..50 {
+        let n = 2 + rand::random::<usize>() % 3;
+        let circuit = random_clifford(n, 30);
+        let tableau = circuit.to_tableau(n).unwrap();
+        let unitary = circuit.to_unitary().unwrap();
+        for row in 0..2 * n {
+            let q = row % n;
+            let input = if row < n { Gate::X(Qubit(q)) } else { Gate::Phase(Phase::Z, Qubit(q)) };
+            let mut input = Circuit::new(vec![input]);
+            input.gates.push(Gate::I(Qubit(n - 1)));
+            let lhs = unitary.dot(&input.to_unitary().unwrap());
+            let rhs = pauli_unitary(&tableau, row).dot(&unitary);
+            assert!(lhs.iter().zip(&rhs).all(|(x, y)| (x - y).norm() < 1e-9), "row {} of {:?}", row, circuit.gates);
+        }
+    }
+}
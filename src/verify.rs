@@ -1,23 +1,107 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
 use std::io::Write;
 
-use crate::circuit::Circuit;
+use crate::{circuit::{Circuit, Gate, Qubit}, simulate};
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq)]
+enum Verifier {
+    #[value(help = "Exact verification by path sums using feynver")]
+    Feynver,
+    #[value(help = "Fast probabilistic verification by simulating random stabilizer inputs")]
+    Fast
+}
 
 #[derive(Debug, Clone, Parser)]
-#[clap(version, about = "Verify that two qasm circuits are the same using `feynver`")]
+#[clap(version, about = "Verify that two qasm circuits are the same using `feynver` or simulation")]
 pub struct Args {
     #[clap(long, short, help = "Whether to insert opaque definitions of common gates")]
     opaque: bool,
-    #[clap(required = true, help = "Original .qasm circuit file")]
+    #[clap(long, help = "Print the result as JSON and exit with a non-zero code if the circuits differ")]
+    json: bool,
+    #[clap(long, default_value_t = crate::circuit::DEFAULT_ANGLE_TOLERANCE, help = "Tolerance in radians when recognizing angles as multiples of pi/4")]
+    angle_tolerance: f64,
+    #[clap(long, value_enum, default_value = "feynver", help = "Method to use to check equivalence")]
+    verifier: Verifier,
+    #[clap(long, default_value_t = 16, help = "Number of random inputs to try with the fast verifier")]
+    samples: usize,
+    #[clap(long, value_parser = parse_basis_state, help = "Only compare the output states on this input basis state, e.g. 0010 with qubit 0 first")]
+    verify_state: Option<BasisState>,
+    #[clap(required = true, help = "Original .qasm circuit file, optionally gzip-compressed as .qasm.gz")]
     original: String,
-    #[clap(required = true, help = "New .qasm file to compare against")]
+    #[clap(required = true, help = "New .qasm file to compare against, optionally gzip-compressed as .qasm.gz")]
     new: String
 }
 
+/// A computational basis state, with qubit 0 first.
+#[derive(Debug, Clone)]
+struct BasisState(Vec<bool>);
+
+fn parse_basis_state(s: &str) -> Result<BasisState, String> {
+    s.chars()
+        .map(|c| match c {
+            '0' => Ok(false),
+            '1' => Ok(true),
+            _ => Err(format!("Invalid character `{}` in basis state, expected 0 or 1", c))
+        })
+        .collect::<Result<_, _>>()
+        .map(BasisState)
+}
+
+/// Contract a circuit with the given basis state plugged into its first qubits, and
+/// any remaining qubits treated as ancillas prepared and postselected in |0>.
+fn output_state(circuit: &Circuit, input: &[bool]) -> Vec<num_complex::Complex64> {
+    use quizx::{graph::{BasisElem, GraphLike}, tensor::ToTensor};
+
+    let mut padded = circuit.clone();
+    if let Some(n) = input.len().checked_sub(1) {
+        padded.gates.push(Gate::I(Qubit(n)));
+    }
+    let zx = padded.to_zx();
+    let mut graph = zx.to_graph::<quizx::hash_graph::Graph>();
+    // Plugging removes the input, so go from the back to keep the indices valid
+    for i in (input.len()..zx.num_qubits()).rev() {
+        graph.plug_input(i, BasisElem::Z0);
+        graph.plug_output(i, BasisElem::Z0);
+    }
+    for (i, &bit) in input.iter().enumerate().rev() {
+        graph.plug_input(i, if bit { BasisElem::Z1 } else { BasisElem::Z0 });
+    }
+    graph.to_tensorf().iter().copied().collect()
+}
+
+/// Check if two vectors are equal up to a nonzero scalar, which covers both
+/// the global phase and any normalization lost to ancilla postselection.
+fn states_equal(a: &[num_complex::Complex64], b: &[num_complex::Complex64]) -> bool {
+    let norm = |v: &[num_complex::Complex64]| v.iter().map(|x| x.norm_sqr()).sum::<f64>().sqrt();
+    let overlap = a.iter().zip(b).map(|(x, y)| x.conj() * y).sum::<num_complex::Complex64>();
+    let (na, nb) = (norm(a), norm(b));
+    a.len() == b.len() && na > 1e-9 && nb > 1e-9 && overlap.norm() >= (1.0 - 1e-6) * na * nb
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyResult {
+    equal: bool,
+    backend: &'static str,
+    detail: String
+}
+
+impl VerifyResult {
+    /// Interpret the output of `feynver`.
+    fn from_feynver(stdout: &[u8]) -> VerifyResult {
+        let detail = String::from_utf8_lossy(stdout).trim().to_string();
+        VerifyResult {
+            equal: detail.starts_with("Equal"),
+            backend: "feynver",
+            detail
+        }
+    }
+}
+
 pub fn main(args: Args) {
     let mut cache = openqasm::SourceCache::new();
 
-    let original = match Circuit::from_openqasm(&mut cache, args.original, args.opaque) {
+    let original = match Circuit::from_openqasm(&mut cache, args.original, args.opaque, args.angle_tolerance) {
         Ok(original) => original,
         Err(errors) => {
             errors.eprint(&mut cache).unwrap();
@@ -25,7 +109,7 @@ pub fn main(args: Args) {
         }
     };
 
-    let new = match Circuit::from_openqasm(&mut cache, args.new, args.opaque) {
+    let new = match Circuit::from_openqasm(&mut cache, args.new, args.opaque, args.angle_tolerance) {
         Ok(original) => original,
         Err(errors) => {
             errors.eprint(&mut cache).unwrap();
@@ -33,6 +117,35 @@ pub fn main(args: Args) {
         }
     };
 
+    let result = if let Some(BasisState(input)) = &args.verify_state {
+        let equal = states_equal(&output_state(&original, input), &output_state(&new, input));
+        let detail = if equal { "Output states are equal" } else { "Output states differ" };
+        Some(VerifyResult { equal, backend: "state", detail: detail.into() })
+    } else if args.verifier == Verifier::Fast {
+        Some(match simulate::probably_equal(&original, &new, args.samples) {
+            Some(true) => VerifyResult { equal: true, backend: "fast", detail: "Probably equal".into() },
+            Some(false) => VerifyResult { equal: false, backend: "fast", detail: "Not equal".into() },
+            None => {
+                eprintln!("Circuits are too large to simulate (more than {} qubits)", simulate::MAX_QUBITS);
+                std::process::exit(2);
+            }
+        })
+    } else {
+        None
+    };
+
+    if let Some(result) = result {
+        if args.json {
+            println!("{}", serde_json::to_string(&result).expect("Couldn't serialize result"));
+            if !result.equal {
+                std::process::exit(1);
+            }
+        } else {
+            println!("{}", result.detail);
+        }
+        return
+    }
+
     let dir = tempfile::tempdir()
         .expect("Couldn't create temporary directory!");
 
@@ -61,5 +174,13 @@ pub fn main(args: Args) {
         .output()
         .expect("Failed when trying to run `feynver`!");
     
-    print!("{}", String::from_utf8_lossy(&output.stdout));
+    if args.json {
+        let result = VerifyResult::from_feynver(&output.stdout);
+        println!("{}", serde_json::to_string(&result).expect("Couldn't serialize result"));
+        if !result.equal {
+            std::process::exit(1);
+        }
+    } else {
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+    }
 }
\ No newline at end of file